@@ -2,14 +2,222 @@ use crossterm::{
     event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
     terminal::size,
 };
-use encoding_rs::UTF_16LE;
-use ropey::Rope;
-use std::{cmp::min, fs, io::Write, path::PathBuf};
+use encoding_rs::{UTF_16BE, UTF_16LE};
+use ropey::{Rope, RopeSlice};
+use std::{
+    cmp::{max, min},
+    collections::HashMap,
+    fs,
+    io::{Read, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
 use unicode_segmentation::UnicodeSegmentation;
 
-use unicode_width::UnicodeWidthStr;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use crate::editor::config::{BufferSettings, FiletypeOverrides};
+use crate::editor::editorconfig::EditorConfig;
+#[cfg(feature = "spell")]
+use crate::editor::spell::Dictionary;
+
+/// The ways in which `Buffer::sort_lines` can order lines.
+pub enum SortOrder {
+    Ascending,
+    Descending,
+    /// Sorts by the number at the start of each line. Lines without a leading number come first.
+    Numeric,
+}
+
+/// A change of case applied by `Buffer::transform_case`.
+#[derive(Clone, Copy)]
+pub enum CaseOp {
+    Upper,
+    Lower,
+    /// Swap the case of each character.
+    Toggle,
+}
+
+/// A search for a character on the cursor's line, made by `Buffer::find_char_on_line`.
+#[derive(Clone, Copy)]
+pub struct CharSearch {
+    pub target: char,
+    /// Search after the cursor rather than before it.
+    pub forward: bool,
+    /// Stop next to the character rather than on it.
+    pub till: bool,
+}
+
+/// How comments are written in a file's language. Either kind may be missing.
+#[derive(Clone, Copy)]
+pub struct CommentSyntax {
+    /// Starts a comment that runs to the end of the line, like `//`.
+    pub line: Option<&'static str>,
+    /// Opens and closes a comment that can span lines, like `/*` and `*/`.
+    pub block: Option<(&'static str, &'static str)>,
+}
+
+/// The steps carried out by `Buffer::clean`.
+#[derive(Clone, Copy)]
+pub struct CleanOptions {
+    /// Replace each run of blank lines with a single blank line.
+    pub collapse_blank_lines: bool,
+    /// Remove whitespace from the end of every line.
+    pub trim_trailing_whitespace: bool,
+    /// Remove blank lines from the end of the buffer and make sure it ends with one line feed.
+    pub single_final_newline: bool,
+}
+
+impl Default for CleanOptions {
+    fn default() -> Self {
+        CleanOptions {
+            collapse_blank_lines: true,
+            trim_trailing_whitespace: true,
+            single_final_newline: true,
+        }
+    }
+}
+
+/// Wrapped in the error returned by `Buffer::save_file` when the file was written but couldn't be
+/// synced to disk.
+#[derive(Debug)]
+pub struct SyncError(pub std::io::Error);
+
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to sync file to disk: {}", self.0)
+    }
+}
+
+impl std::error::Error for SyncError {}
+
+/// Wrapped in the error returned by `Buffer::from_path` when the file looks binary and
+/// `open_binary_files` isn't set.
+#[derive(Debug)]
+pub struct BinaryFileError;
+
+impl std::fmt::Display for BinaryFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "file appears to be binary")
+    }
+}
+
+impl std::error::Error for BinaryFileError {}
+
+impl BinaryFileError {
+    /// The `BinaryFileError` wrapped in `err`, if there is one.
+    pub fn from_io_error(err: &std::io::Error) -> Option<&BinaryFileError> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+/// Wrapped in the error returned by `Buffer::from_path` when the file is larger than
+/// `large_file_threshold`. Holds the size of the file in bytes.
+#[derive(Debug)]
+pub struct LargeFileError(pub u64);
+
+impl std::fmt::Display for LargeFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "file is large ({})", self.readable_size())
+    }
+}
+
+impl std::error::Error for LargeFileError {}
+
+impl LargeFileError {
+    /// The `LargeFileError` wrapped in `err`, if there is one.
+    pub fn from_io_error(err: &std::io::Error) -> Option<&LargeFileError> {
+        err.get_ref()?.downcast_ref()
+    }
+
+    /// The size of the file in the largest unit it has at least one of, rounded down, like `120MB`.
+    pub fn readable_size(&self) -> String {
+        const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+        let mut size = self.0;
+        let mut unit = 0;
+        while size >= 1024 && unit < UNITS.len() - 1 {
+            size /= 1024;
+            unit += 1;
+        }
+        format!("{}{}", size, UNITS[unit])
+    }
+}
+
+/// The encoding a file's text was decoded from, so it can be written back the same way.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DetectedEncoding {
+    Utf8,
+    /// UTF-8 starting with a byte order mark.
+    Utf8WithBom,
+    Utf16Le,
+    Utf16Be,
+    /// Not valid UTF-8, with the invalid sequences replaced. Written back as UTF-8.
+    Lossy,
+}
+
+impl DetectedEncoding {
+    /// The byte order mark written at the start of a file in this encoding, which may be empty.
+    fn byte_order_mark(self) -> &'static [u8] {
+        match self {
+            DetectedEncoding::Utf8WithBom => UTF8_BOM,
+            DetectedEncoding::Utf16Le => &[0xFF, 0xFE],
+            DetectedEncoding::Utf16Be => &[0xFE, 0xFF],
+            DetectedEncoding::Utf8 | DetectedEncoding::Lossy => &[],
+        }
+    }
+
+    /// Encodes `text` in this encoding, without a byte order mark.
+    fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            DetectedEncoding::Utf16Le => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            DetectedEncoding::Utf16Be => text.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+            _ => text.as_bytes().to_vec(),
+        }
+    }
+}
+
+/// The line endings a file can use.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+impl LineEnding {
+    /// Returns the line ending used by most lines of `text`, and whether some lines use the other
+    /// one. Text without any line feeds, or with as many of each, counts as LF.
+    pub fn detect(text: &str) -> (LineEnding, bool) {
+        let line_feeds = text.matches('\n').count();
+        let crlfs = text.matches("\r\n").count();
+        let lfs = line_feeds - crlfs;
+        let line_ending = if crlfs > lfs {
+            LineEnding::Crlf
+        } else {
+            LineEnding::Lf
+        };
+        (line_ending, crlfs > 0 && lfs > 0)
+    }
+}
+
+/// How a line differs from the file as it was loaded or last saved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LineChange {
+    Added,
+    Modified,
+    /// One or more lines were removed just above this one.
+    Deleted,
+}
 
-use crate::editor::Editor;
+/// A copy of the text and cursor to go back to on undo. Ropes share unchanged nodes, so keeping
+/// many of these is cheap.
+struct UndoState {
+    text: Rope,
+    cursor_idx: usize,
+    line_ending: LineEnding,
+}
 
 /// One buffer represents one open file.
 pub struct Buffer {
@@ -37,17 +245,123 @@ pub struct Buffer {
 
     /// Represents whether a change has been made since the file was last saved.
     pub dirty_buffer: bool,
+
+    /// The char index at which the current selection was started, if there is one. The selection
+    /// spans from here to `cursor_idx`.
+    pub selection_anchor: Option<usize>,
+
+    /// Indentation and display settings for this buffer.
+    pub settings: BufferSettings,
+
+    /// Whether the file looked like a binary file when it was loaded.
+    pub is_binary: bool,
+
+    /// The line ending most of the file's lines used when it was loaded. `text` always uses LF;
+    /// line feeds are converted back to this when saving.
+    pub line_ending: LineEnding,
+
+    /// Whether the text last loaded or set used both LF and CRLF line endings. Saving writes
+    /// `line_ending` for every line.
+    pub mixed_line_endings: bool,
+
+    /// Other occurrences of the selected text collected by `add_next_occurrence`. Edits typed
+    /// next are made to all of them along with the selection, and any other edit forgets them.
+    pub occurrences: Vec<Range<usize>>,
+
+    /// The encoding the file was decoded from when it was loaded, which it's written back in. Any
+    /// byte order mark isn't part of `text`; it's written back when saving.
+    pub encoding: DetectedEncoding,
+
+    /// Whether typed characters replace the character under the cursor rather than being inserted
+    /// before it. Toggled with the Insert key.
+    pub overwrite: bool,
+
+    /// The display column vertical movement aims for, with the cursor index it was left at. Moving
+    /// up or down through a shorter line comes back out at this column. Any other movement leaves the
+    /// cursor somewhere else, which makes the goal stale.
+    goal_col: Option<(usize, usize)>,
+
+    /// The directory this buffer lists, if it's a directory listing rather than a file. Each line
+    /// is an entry of the directory, and directories end in a slash.
+    pub listing: Option<PathBuf>,
+
+    /// The modification time of the file when it was loaded or last saved, if it existed.
+    disk_mtime: Option<SystemTime>,
+
+    /// Whether the file has been modified on disk since it was loaded or last saved.
+    pub file_changed_externally: bool,
+
+    /// The number of edits made since the buffer was loaded or last saved.
+    pub edits_since_save: usize,
+
+    /// States to return to on undo, most recent last, and states undone that redo can restore.
+    undo_stack: Vec<UndoState>,
+    redo_stack: Vec<UndoState>,
+
+    /// Whether edits are currently being added to the most recent undo state rather than starting
+    /// a new one, and when the last of them was made.
+    undo_group_open: bool,
+    last_edit: Option<Instant>,
+
+    /// The text as it was loaded or last saved, which `line_changes` compares against.
+    baseline: Rope,
+    /// The line ending the file had when it was loaded or last saved.
+    baseline_line_ending: LineEnding,
+
+    /// Goes up with every change to `text` or `baseline`, so `line_changes` can tell its result
+    /// is out of date without comparing the text.
+    revision: u64,
+
+    /// The revision `line_changes` last ran at, and what it found.
+    diff_cache: Option<(u64, Vec<Option<LineChange>>)>,
 }
 
 impl Buffer {
+    /// How long typing can pause before the next edit starts a new undo step.
+    const UNDO_PAUSE: Duration = Duration::from_secs(1);
+
     /// Creates a buffer from a given file path. Loads contents if the file exists and is readable.
     /// Creates an empty buffer if the file does not exist. Returns Err if the file exists but it
-    /// can't be read.
-    pub fn from_path(path: PathBuf) -> std::io::Result<Self> {
+    /// can't be read. A file that looks binary isn't loaded unless `open_binary_files` is set, and
+    /// the error wraps a `BinaryFileError`.
+    pub fn from_path(
+        path: PathBuf,
+        mut settings: BufferSettings,
+        filetype_overrides: &HashMap<String, FiletypeOverrides>,
+    ) -> std::io::Result<Self> {
         // First, we read the text from the file. If the file can't be read, we simply return an
         // error. Next, we iterate through the text and replace CRLF with just LF.
 
-        let (cols, rows) = size().unwrap();
+        // Without a terminal, as in tests, the editor sizes the buffer itself.
+        let (cols, rows) = size().unwrap_or_default();
+
+        if let Some(overrides) = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| filetype_overrides.get(ext))
+        {
+            overrides.apply(&mut settings);
+        }
+
+        // Settings from `.editorconfig` files apply to the project, so they win over the rest.
+        let path = absolute_path(path);
+        let editor_config = if path.as_os_str().is_empty() {
+            EditorConfig::default()
+        } else {
+            EditorConfig::for_path(&path)
+        };
+        editor_config.overrides.apply(&mut settings);
+
+        // Check the size first, so a large file isn't loaded before the user has agreed to it.
+        if let Some(threshold) = settings.large_file_threshold
+            && let Ok(metadata) = fs::metadata(&path)
+            && metadata.len() > threshold
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::FileTooLarge,
+                LargeFileError(metadata.len()),
+            ));
+        }
 
         // Read raw bytes from the file
         let bytes = match fs::read(&path) {
@@ -56,286 +370,3341 @@ impl Buffer {
             Err(err) => return Err(err),
         };
 
-        // Attempt UTF-8 first, then UTF-16 LE, then fallback lossily
-        let contents = if let Ok(s) = String::from_utf8(bytes.clone()) {
-            s
-        } else if bytes.len() >= 2 && bytes[0] == 0xFF && bytes[1] == 0xFE {
-            // UTF-16 LE BOM detected
-            let (cow, _, _) = UTF_16LE.decode(&bytes[2..]); // skip BOM
-            cow.into_owned()
-        } else {
-            // Fallback: replace invalid sequences
-            String::from_utf8_lossy(&bytes).into_owned()
-        };
-
-        // Create the rope data structure from the string we've just read.
-        let mut rope: Rope = Rope::from_str(&contents);
-
-        // Go through this new rope and carriage returns from each line, if they're there.
-        let mut line_idx = 0;
-        while line_idx < rope.len_lines() {
-            let line = rope.line(line_idx);
-            let len = line.len_chars();
-            if len >= 2 {
-                let last_char = line.char(len - 2);
-                let newline_char = line.char(len - 1);
-                if last_char == '\r' && newline_char == '\n' {
-                    rope.remove(
-                        rope.line_to_char(line_idx) + len - 2
-                            ..rope.line_to_char(line_idx) + len - 1,
-                    );
-                }
-            }
-            line_idx += 1;
+        if looks_binary(&bytes) && !settings.open_binary_files {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                BinaryFileError,
+            ));
+        }
+
+        let (contents, encoding) = decode_file_bytes(&bytes);
+
+        let mut buffer = Buffer::from_str(&contents, settings);
+        buffer.is_binary = looks_binary(&bytes);
+        buffer.encoding = encoding;
+        if let Some(line_ending) = editor_config.end_of_line {
+            buffer.line_ending = line_ending;
+            buffer.baseline_line_ending = line_ending;
         }
+        buffer.file_path = path;
+        buffer.disk_mtime = buffer.read_disk_mtime();
+        buffer.visual_width = cols as usize;
+        buffer.visual_height = rows as usize;
+
+        Ok(buffer)
+    }
+
+    /// Creates a buffer listing the entries of the directory `dir`, as given by `directory_entries`.
+    pub fn directory_listing(dir: &Path, settings: BufferSettings) -> std::io::Result<Self> {
+        let mut text = directory_entries(dir)?.join("\n");
+        text.push('\n');
+        let mut buffer = Buffer::from_str(&text, settings);
+        buffer.file_path = dir.to_path_buf();
+        buffer.listing = Some(dir.to_path_buf());
+        Ok(buffer)
+    }
+
+    /// Creates an unnamed buffer holding `text`, with CRLF line endings replaced by LF. The viewport
+    /// is empty until the editor sizes it, so this never touches the terminal.
+    pub fn from_str(text: &str, settings: BufferSettings) -> Self {
+        // Record which line ending most of the text used, then normalise CRLF to LF in a single
+        // pass before building the rope.
+        let (line_ending, mixed_line_endings) = LineEnding::detect(text);
+        let rope: Rope = Rope::from_str(&text.replace("\r\n", "\n"));
 
-        Ok(Buffer {
-            file_path: path,
+        let mut buffer = Buffer {
+            file_path: PathBuf::new(),
+            occurrences: Vec::new(),
+            encoding: DetectedEncoding::Utf8,
+            overwrite: false,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            undo_group_open: false,
+            last_edit: None,
+            goal_col: None,
+            listing: None,
+            edits_since_save: 0,
+            disk_mtime: None,
+            file_changed_externally: false,
+            baseline: rope.clone(),
+            baseline_line_ending: line_ending,
+            revision: 0,
+            diff_cache: None,
             text: rope,
-            visual_width: cols as usize,
-            visual_height: rows as usize,
+            visual_width: 0,
+            visual_height: 0,
             visual_origin_row: 0,
             visual_origin_col: 0,
             cursor_idx: 0,
             dirty_buffer: false,
-        })
-    }
+            selection_anchor: None,
+            settings,
+            is_binary: false,
+            line_ending,
+            mixed_line_endings,
+        };
 
-    /// Finds the next instance of `target` in the buffer and puts the cursor at the start. If
-    /// found, returns true, otherwise returns false.
-    pub fn go_to_next_instance(&mut self, target: &String) -> bool {
-        for (line_idx, line) in self.text.lines().enumerate() {
-            if self.text.line_to_char(line_idx) < self.cursor_idx {
-                continue;
-            }
+        if buffer.settings.retab_on_load {
+            let all_tabs = buffer.settings.retab_all_tabs;
+            buffer.retab(0..buffer.content_lines(), all_tabs);
+            // The retabbed text is what was loaded, so it isn't an unsaved change or undoable.
+            buffer.dirty_buffer = false;
+            buffer.baseline = buffer.text.clone();
+            buffer.revision += 1;
+            buffer.undo_stack.clear();
+        }
 
-            let line_str = line.to_string();
-            let res = line_str.find(target);
-            match res {
-                Some(target_byte_idx) => {
-                    let cursor_byte_idx = self.text.char_to_byte(self.cursor_idx);
-                    let line_byte_idx = self.text.line_to_byte(line_idx);
-                    
-                    // Don't match anything before the cursor.
-                    if target_byte_idx + line_byte_idx <= cursor_byte_idx {
-                        continue;
-                    } else {
-                        self.cursor_idx = target_byte_idx + line_byte_idx;
-                        return true;
-                    }
-                },
-                None => {
-                    continue;
-                }
+        buffer
+    }
+
+    /// Finds the first instance of `target` starting at or after char index `from` and puts the
+    /// cursor at its start. If found, returns true, otherwise returns false and leaves the cursor
+    /// where it is.
+    pub fn go_to_next_instance(&mut self, target: &str, from: usize) -> bool {
+        let from = min(from, self.text.len_chars());
+        let haystack = self.text.slice(from..).to_string();
+        match haystack.find(target) {
+            Some(byte_idx) => {
+                self.cursor_idx = from + haystack[..byte_idx].chars().count();
+                true
             }
+            None => false,
         }
-        
-        false
     }
 
-    /// Save the current contents of the file.
-    pub fn save_file(&mut self) -> std::io::Result<()> {
-        let mut output_file = fs::File::create(&self.file_path).unwrap();
-        let save_result = output_file.write_all(self.text.to_string().as_bytes());
-        match save_result {
-            Ok(_) => self.dirty_buffer = false,
-            Err(_) => {}
+    /// Sorts the lines with indices in `lines` according to `order`. The line feed at the end of the
+    /// range is left where it is, so sorting never merges lines.
+    pub fn sort_lines(&mut self, lines: Range<usize>, order: SortOrder) {
+        if lines.is_empty() {
+            return;
         }
-        return save_result;
-    }
 
-    /// Return a string for the editor to use as a status bar for this buffer.
-    pub fn get_status_bar_text(&self) -> String {
-        let mut text = String::from("Viewing file ");
-        let filename = self
-            .file_path
-            .file_name()
-            .map(|name| name.to_string_lossy().to_string())
-            .unwrap_or_else(|| "<unnamed>".to_string());
-        text.push_str(&filename);
-        return text;
+        let start = self.text.line_to_char(lines.start);
+        let end = self.text.line_to_char(lines.end);
+        let region = self.text.slice(start..end).to_string();
+
+        let mut sorted: Vec<&str> = region.lines().collect();
+        match order {
+            SortOrder::Ascending => sorted.sort(),
+            SortOrder::Descending => sorted.sort_by(|a, b| b.cmp(a)),
+            SortOrder::Numeric => sorted.sort_by(|a, b| {
+                leading_number(a)
+                    .partial_cmp(&leading_number(b))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            }),
+        }
+
+        let mut new_region = sorted.join("\n");
+        if region.ends_with('\n') {
+            new_region.push('\n');
+        }
+
+        self.begin_edit();
+        self.text.remove(start..end);
+        self.text.insert(start, &new_region);
+        self.cursor_idx = self
+            .cursor_idx
+            .clamp(start, start + new_region.chars().count());
+        self.dirty_buffer = true;
     }
 
-    /// Moves the cursor right by one character.
-    pub fn move_right(&mut self) {
-        if self.cursor_idx < self.len_chars() {
-            self.cursor_idx += 1;
+    /// Tidies up the whole buffer as set out by `options`. Lines holding only whitespace count as
+    /// blank. The cursor stays on the same line, or moves to the next line kept if its own was
+    /// removed. Returns false if there was nothing to change.
+    pub fn clean(&mut self, options: CleanOptions) -> bool {
+        let text = self.text.to_string();
+        let mut lines: Vec<&str> = text.split('\n').collect();
+        if text.ends_with('\n') {
+            lines.pop();
+        }
+        let is_blank = |line: &str| line.trim().is_empty();
+
+        let (cursor_line, cursor_col) = self.get_logical_cursor_pos();
+        let mut new_cursor_line = None;
+        let mut cleaned: Vec<&str> = Vec::with_capacity(lines.len());
+        for (idx, line) in lines.into_iter().enumerate() {
+            if idx == cursor_line {
+                new_cursor_line = Some(cleaned.len());
+            }
+            let line = if options.trim_trailing_whitespace {
+                line.trim_end()
+            } else {
+                line
+            };
+            if options.collapse_blank_lines
+                && is_blank(line)
+                && cleaned.last().is_some_and(|prev| is_blank(prev))
+            {
+                continue;
+            }
+            cleaned.push(line);
         }
+
+        let mut new_text = if options.single_final_newline {
+            while cleaned.last().is_some_and(|line| is_blank(line)) {
+                cleaned.pop();
+            }
+            let mut new_text = cleaned.join("\n");
+            if !new_text.is_empty() {
+                new_text.push('\n');
+            }
+            new_text
+        } else {
+            cleaned.join("\n")
+        };
+        if !options.single_final_newline && text.ends_with('\n') {
+            new_text.push('\n');
+        }
+        if new_text == text {
+            return false;
+        }
+
+        self.begin_edit();
+        self.text = Rope::from_str(&new_text);
+        let line = min(
+            new_cursor_line.unwrap_or(usize::MAX),
+            self.text.len_lines() - 1,
+        );
+        self.cursor_idx = self.text.line_to_char(line) + min(cursor_col, self.line_len(line));
+        self.dirty_buffer = true;
+        true
     }
 
-    /// Moves the cursor left by one character.
-    pub fn move_left(&mut self) {
-        if self.cursor_idx > 0 {
-            self.cursor_idx -= 1;
+    /// Sets the line ending the buffer is saved with, as an undo step of its own. The text itself
+    /// always uses LF, so only the saved file changes. Returns false if every line already used
+    /// `line_ending`.
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) -> bool {
+        if self.line_ending == line_ending && !self.mixed_line_endings {
+            return false;
         }
+        self.break_undo_group();
+        let anchor = self.selection_anchor;
+        self.begin_edit();
+        self.selection_anchor = anchor;
+        self.line_ending = line_ending;
+        self.mixed_line_endings = false;
+        self.dirty_buffer = true;
+        self.break_undo_group();
+        true
     }
 
-    /// Moves the cursor up a line.
-    pub fn move_up(&mut self) {
-        let cursor_line = self.get_logical_cursor_line();
-        // If we're on the first line, go to the beginning of the line.
-        if cursor_line == 0 {
-            self.cursor_idx = 0;
-        } else {
-            let next_line_idx = cursor_line - 1;
-            let next_line_char_idx = self.text.line_to_char(next_line_idx);
-            let next_line_len = self.text.line(next_line_idx).to_string().width_cjk();
-            if next_line_len <= 1 || self.get_logical_cursor_col() == 0 {
-                self.cursor_idx = next_line_char_idx;
-            } else {
-                self.cursor_idx =
-                    next_line_char_idx + min(next_line_len - 1, self.get_logical_cursor_col());
+    /// Removes trailing whitespace from the lines with indices in `lines`.
+    pub fn trim_lines(&mut self, lines: Range<usize>) {
+        // Work from the bottom up so that earlier line indices stay valid.
+        for line_idx in lines.rev() {
+            let line = self.get_line(line_idx);
+            let content = line.trim_end_matches('\n');
+            let trimmed = content.trim_end();
+
+            let line_start = self.text.line_to_char(line_idx);
+            let trim_start = line_start + trimmed.chars().count();
+            let trim_end = line_start + content.chars().count();
+            if trim_start < trim_end {
+                self.delete_range(trim_start..trim_end);
             }
         }
     }
 
-    /// Moves the cursor down a line.
-    pub fn move_down(&mut self) {
-        let cursor_line = self.get_logical_cursor_line();
-        // If we're on the last line, go the end of the line.
-        if cursor_line == self.text.len_lines() - 1 {
-            self.cursor_idx = self.text.len_chars();
-        } else {
-            let next_line_idx = cursor_line + 1;
-            let next_line_char_idx = self.text.line_to_char(next_line_idx);
-            let next_line_len = self.text.line(next_line_idx).len_chars();
-            if next_line_len <= 1 || self.get_logical_cursor_col() == 0 {
-                self.cursor_idx = next_line_char_idx;
-            } else {
-                self.cursor_idx =
-                    next_line_char_idx + min(next_line_len, self.get_logical_cursor_col());
+    /// Joins the lines with indices in `lines` into one. Each line feed and the indentation after it
+    /// is replaced by a single space, and the cursor is left at the first join point. Lines past the
+    /// last line with content are ignored, so joining the last line is a no-op.
+    pub fn join_lines(&mut self, lines: Range<usize>) {
+        let end = min(lines.end, self.content_lines());
+        if end <= lines.start + 1 {
+            return;
+        }
+
+        // Work from the bottom up so that earlier line indices stay valid.
+        for line_idx in (lines.start + 1..end).rev() {
+            let join_idx = self.text.line_to_char(line_idx) - 1;
+            let next_line = self.get_line(line_idx);
+            let indent = next_line
+                .chars()
+                .take_while(|&c| c == ' ' || c == '\t')
+                .count();
+            let prev_is_empty = self.text.line(line_idx - 1).len_chars() == 1;
+
+            self.begin_edit();
+            self.text.remove(join_idx..join_idx + 1 + indent);
+            if !prev_is_empty && !next_line.trim().is_empty() {
+                self.text.insert_char(join_idx, ' ');
             }
+            self.cursor_idx = join_idx;
         }
+
+        self.dirty_buffer = true;
     }
 
-    pub fn handle_key_event(&mut self, key_event: KeyEvent) {
-        let (current_line_idx, _) = self.get_logical_cursor_pos();
-        if key_event.kind == KeyEventKind::Press {
-            match key_event.code {
-                KeyCode::Right => self.move_right(),
-                KeyCode::Left => {
-                    self.move_left();
-                }
-                KeyCode::Up => {
-                    self.move_up();
-                }
-                KeyCode::Down => {
-                    self.move_down();
-                }
-                KeyCode::Home => {
-                    if key_event.modifiers.contains(KeyModifiers::CONTROL) {
-                        self.cursor_idx = 0;
+    /// Re-wraps the lines with indices in `lines` as a single paragraph so that no line is wider
+    /// than `width` columns, unless it holds a single word that is. The first line keeps its
+    /// leading indentation, and the lines after it take the second line's, so hanging indents such
+    /// as list items stay as they were. The cursor stays next to the same text if it was inside
+    /// the paragraph.
+    pub fn reflow(&mut self, lines: Range<usize>, width: usize) {
+        let end = min(lines.end, self.content_lines());
+        if lines.start >= end {
+            return;
+        }
+
+        let indent_of = |line_idx: usize| -> (String, usize) {
+            let indent: String = self
+                .get_line(line_idx)
+                .chars()
+                .take_while(|&c| c == ' ' || c == '\t')
+                .collect();
+            let width = indent
+                .chars()
+                .map(|c| {
+                    if c == '\t' {
+                        self.settings.tab_display_width
                     } else {
-                        self.cursor_idx = self.line_to_char(current_line_idx);
+                        1
                     }
-                }
-                KeyCode::End => {
-                    if key_event.modifiers.contains(KeyModifiers::CONTROL) {
-                        self.cursor_idx = self.len_chars();
-                    } else {
-                        let current_line = self.get_line(current_line_idx);
-                        let mut current_line_len = current_line.graphemes(true).count();
+                })
+                .sum();
+            (indent, width)
+        };
+        let (first_indent, first_indent_width) = indent_of(lines.start);
+        let (indent, indent_width) = if end - lines.start > 1 {
+            indent_of(lines.start + 1)
+        } else {
+            (first_indent.clone(), first_indent_width)
+        };
 
-                        match current_line.chars().last() {
-                            Some('\n') => current_line_len -= 1,
-                            _ => (),
-                        }
+        let start_idx = self.text.line_to_char(lines.start);
+        let end_idx = self.text.line_to_char(end - 1) + self.line_len(end - 1);
+        let old_text = self.text.slice(start_idx..end_idx).to_string();
 
-                        let current_line_char_idx = self.line_to_char(current_line_idx);
-                        self.cursor_idx = current_line_char_idx + current_line_len;
-                    }
-                }
-                KeyCode::Char(x) => {
-                    let mut buf = [0u8; 4];
-                    self.text.insert(self.cursor_idx, x.encode_utf8(&mut buf));
-                    self.cursor_idx += 1;
-                    self.dirty_buffer = true;
-                }
-                KeyCode::Enter => {
-                    self.text.insert(self.cursor_idx, "\n");
-                    self.cursor_idx += 1;
-                    self.dirty_buffer = true;
-                }
-                KeyCode::Backspace => {
-                    if self.cursor_idx != 0 {
-                        self.text.remove(self.cursor_idx - 1..self.cursor_idx);
-                        self.cursor_idx -= 1;
-                        self.dirty_buffer = true;
-                    }
-                }
-                KeyCode::Tab => {
-                    self.text.insert(self.cursor_idx, "\t");
-                    self.cursor_idx += 1;
-                    self.dirty_buffer = true;
+        let mut new_lines: Vec<String> = Vec::new();
+        let mut current = first_indent;
+        let mut current_width = first_indent_width;
+        let mut line_indent_width = first_indent_width;
+        for word in old_text.split_whitespace() {
+            let word_width = word.width();
+            if current_width > line_indent_width && current_width + 1 + word_width > width {
+                new_lines.push(current);
+                current = indent.clone();
+                current_width = indent_width;
+                line_indent_width = indent_width;
+            }
+            if current_width > line_indent_width {
+                current.push(' ');
+                current_width += 1;
+            }
+            current.push_str(word);
+            current_width += word_width;
+        }
+        new_lines.push(current);
+        let new_text = new_lines.join("\n");
+
+        // Count the non-whitespace characters before the cursor so it can be put back after the
+        // same number in the new text.
+        let cursor_target = (start_idx..=end_idx).contains(&self.cursor_idx).then(|| {
+            old_text
+                .chars()
+                .take(self.cursor_idx - start_idx)
+                .filter(|c| !c.is_whitespace())
+                .count()
+        });
+
+        self.begin_edit();
+        self.text.remove(start_idx..end_idx);
+        self.text.insert(start_idx, &new_text);
+
+        if let Some(target) = cursor_target {
+            let mut seen = 0;
+            let mut offset = 0;
+            for c in new_text.chars() {
+                if seen == target && !c.is_whitespace() {
+                    break;
                 }
-                KeyCode::Delete => {
-                    if self.cursor_idx != self.text.len_chars() {
-                        self.text.remove(self.cursor_idx..self.cursor_idx + 1);
-                        self.dirty_buffer = true;
-                    }
+                if !c.is_whitespace() {
+                    seen += 1;
                 }
-                _ => {}
+                offset += 1;
             }
+            self.cursor_idx = start_idx + offset;
+        } else if self.cursor_idx > end_idx {
+            self.cursor_idx = self.cursor_idx - end_idx + start_idx + new_text.chars().count();
         }
-    }
 
-    /// Returns the logical line and column that the cursor is on. (line, column).
-    pub fn get_logical_cursor_pos(&self) -> (usize, usize) {
-        (
-            self.get_logical_cursor_line(),
-            self.get_logical_cursor_col(),
-        )
+        self.dirty_buffer = true;
     }
 
-    /// Gets the logical line that the cursor is on.
-    pub fn get_logical_cursor_line(&self) -> usize {
-        self.text.char_to_line(self.cursor_idx)
-    }
+    /// Returns the indices of the lines in the paragraph containing the cursor: the run of
+    /// non-blank lines around it. The range is empty if the cursor's line is blank.
+    pub fn paragraph_at_cursor(&self) -> Range<usize> {
+        let cursor_line = self.get_logical_cursor_line();
+        let is_blank = |idx: usize| self.get_line(idx).trim().is_empty();
+        if cursor_line >= self.content_lines() || is_blank(cursor_line) {
+            return cursor_line..cursor_line;
+        }
 
-    /// Gets the logical column that the cursor is on.
-    pub fn get_logical_cursor_col(&self) -> usize {
-        self.cursor_idx - self.text.line_to_char(self.get_logical_cursor_line())
+        let mut start = cursor_line;
+        while start > 0 && !is_blank(start - 1) {
+            start -= 1;
+        }
+        let mut end = cursor_line + 1;
+        while end < self.content_lines() && !is_blank(end) {
+            end += 1;
+        }
+        start..end
     }
 
-    /// Gets the column that the cursor should be shown at visually.
-    pub fn get_visual_cursor_col(&self) -> usize {
-        // Remember - tabs count as one logical character but TAB_WIDTH visual characters.
-        let cursor_line = self.get_line(self.get_logical_cursor_line());
-        let up_to_cursor: String = cursor_line
-            .chars()
-            .take(self.get_logical_cursor_col())
-            .collect();
-        let tab_count = up_to_cursor.chars().filter(|&c| c == '\t').count();
-        let upto_count = up_to_cursor.width_cjk();
-        upto_count + (Editor::TAB_WIDTH * tab_count) - self.visual_origin_col - tab_count
-    }
+    /// Adds `delta` to the number under the cursor, or the first number after it on the line, and
+    /// leaves the cursor on the last digit of the result. A `-` straight before a decimal number
+    /// makes it negative, and numbers starting with `0x` are read as hexadecimal. Leading zeros
+    /// keep the number at least as wide as it was. Returns a message for the footer if there's no
+    /// number to change, or if it's too large to read.
+    pub fn add_to_number(&mut self, delta: i64) -> Result<(), String> {
+        let line_idx = self.get_logical_cursor_line();
+        let line_start = self.text.line_to_char(line_idx);
+        let chars: Vec<char> = self.get_line(line_idx).chars().collect();
+        let cursor_col = self.get_logical_cursor_col();
 
-    /// Gets the row that the cursor should be shown at visually.
-    pub fn get_visual_cursor_line(&self) -> usize {
-        self.get_logical_cursor_line() - self.visual_origin_row
-    }
+        let mut idx = 0;
+        let found = loop {
+            let Some(&c) = chars.get(idx) else {
+                break None;
+            };
+            if !c.is_ascii_digit() {
+                idx += 1;
+                continue;
+            }
+            let is_hex = c == '0'
+                && matches!(chars.get(idx + 1), Some('x' | 'X'))
+                && chars.get(idx + 2).is_some_and(|c| c.is_ascii_hexdigit());
+            let digits_start = if is_hex { idx + 2 } else { idx };
+            let end = (digits_start..chars.len())
+                .find(|&i| !(is_hex && chars[i].is_ascii_hexdigit() || chars[i].is_ascii_digit()))
+                .unwrap_or(chars.len());
+            let negative = !is_hex && idx > 0 && chars[idx - 1] == '-';
+            let start = if negative { idx - 1 } else { idx };
+            if end > cursor_col {
+                break Some((start, digits_start, end, is_hex, negative));
+            }
+            idx = end;
+        };
+        let Some((start, digits_start, end, is_hex, negative)) = found else {
+            return Err(String::from("No number on this line."));
+        };
 
-    /// Get the number of lines in the buffer.
-    pub fn len_lines(&self) -> usize {
-        self.text.len_lines()
+        let digits: String = chars[digits_start..end].iter().collect();
+        // Leading zeros mean the number was written at a fixed width.
+        let width = if digits.len() > 1 && digits.starts_with('0') {
+            digits.len()
+        } else {
+            0
+        };
+        let replacement = if is_hex {
+            let Ok(value) = u64::from_str_radix(&digits, 16) else {
+                return Err(String::from("Number out of range."));
+            };
+            let value = value.wrapping_add_signed(delta);
+            let prefix: String = chars[start..digits_start].iter().collect();
+            if digits.chars().any(|c| c.is_ascii_uppercase()) {
+                format!("{}{:0width$X}", prefix, value)
+            } else {
+                format!("{}{:0width$x}", prefix, value)
+            }
+        } else {
+            let Ok(value) = digits.parse::<i64>() else {
+                return Err(String::from("Number out of range."));
+            };
+            let value = if negative { -value } else { value }.saturating_add(delta);
+            let sign = if value < 0 { "-" } else { "" };
+            format!("{}{:0width$}", sign, value.unsigned_abs())
+        };
+
+        self.break_undo_group();
+        self.delete_range(line_start + start..line_start + end);
+        self.insert_text(line_start + start, &replacement);
+        self.break_undo_group();
+        self.cursor_idx = line_start + start + replacement.chars().count() - 1;
+        Ok(())
     }
 
-    /// Get the character index of a given line.
+    /// Moves the cursor to the start of the first blank line after the next paragraph, skipping
+    /// any blank lines the cursor starts on, or to the end of the buffer if there isn't one.
+    pub fn next_paragraph(&mut self) {
+        let is_blank = |idx: usize| self.get_line(idx).trim().is_empty();
+        let last_line = self.content_lines();
+        let mut line_idx = self.get_logical_cursor_line();
+        while line_idx < last_line && is_blank(line_idx) {
+            line_idx += 1;
+        }
+        while line_idx < last_line && !is_blank(line_idx) {
+            line_idx += 1;
+        }
+        self.cursor_idx = if line_idx < last_line {
+            self.text.line_to_char(line_idx)
+        } else {
+            self.text.len_chars()
+        };
+    }
+
+    /// Moves the cursor to the start of the last blank line before the previous paragraph, skipping
+    /// any blank lines the cursor starts on, or to the start of the buffer if there isn't one.
+    pub fn prev_paragraph(&mut self) {
+        let is_blank = |idx: usize| self.get_line(idx).trim().is_empty();
+        let mut line_idx = self.get_logical_cursor_line();
+        while line_idx > 0 && is_blank(line_idx) {
+            line_idx -= 1;
+        }
+        while line_idx > 0 && !is_blank(line_idx) {
+            line_idx -= 1;
+        }
+        self.cursor_idx = self.text.line_to_char(line_idx);
+    }
+
+    /// Moves the cursor to the start of the text on the nearest non-blank line below (or above, if
+    /// `forward` is false) the cursor's line that is indented no further than it, which is roughly
+    /// the end (or start) of the current block. Returns false and leaves the cursor alone if there
+    /// is no such line.
+    pub fn go_to_matching_indent(&mut self, forward: bool) -> bool {
+        let cursor_line = self.get_logical_cursor_line();
+        let Some(current) = self.indent_width(cursor_line) else {
+            return false;
+        };
+
+        let target = if forward {
+            (cursor_line + 1..self.content_lines())
+                .find(|&idx| self.indent_width(idx).is_some_and(|width| width <= current))
+        } else {
+            (0..cursor_line)
+                .rev()
+                .find(|&idx| self.indent_width(idx).is_some_and(|width| width <= current))
+        };
+
+        match target {
+            Some(line_idx) => {
+                let indent = self
+                    .get_line(line_idx)
+                    .chars()
+                    .take_while(|&c| c == ' ' || c == '\t')
+                    .count();
+                self.cursor_idx = self.text.line_to_char(line_idx) + indent;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the display width of a line's leading whitespace, or None if the line is blank.
+    fn indent_width(&self, line_idx: usize) -> Option<usize> {
+        let line = self.get_line(line_idx);
+        if line.trim().is_empty() {
+            return None;
+        }
+        Some(
+            line.chars()
+                .take_while(|&c| c == ' ' || c == '\t')
+                .map(|c| {
+                    if c == '\t' {
+                        self.settings.tab_display_width
+                    } else {
+                        1
+                    }
+                })
+                .sum(),
+        )
+    }
+
+    /// Records the state before an edit so it can be undone. Consecutive edits are grouped into a
+    /// single undo step until the group is broken, either by `break_undo_group` or by a pause of
+    /// more than `UNDO_PAUSE` between edits. Call this before changing `text`. The selection is
+    /// cleared, since the edit could leave the anchor on other text or past the end; edits that
+    /// know where the anchor goes put it back afterwards.
+    fn begin_edit(&mut self) {
+        self.goal_col = None;
+        self.selection_anchor = None;
+        self.revision += 1;
+        // The collected occurrences' ranges would be out of date after an edit made elsewhere.
+        self.occurrences.clear();
+        self.edits_since_save += 1;
+        let now = Instant::now();
+        if self
+            .last_edit
+            .is_some_and(|last| now.duration_since(last) > Buffer::UNDO_PAUSE)
+        {
+            self.undo_group_open = false;
+        }
+        self.last_edit = Some(now);
+
+        if !self.undo_group_open {
+            self.undo_stack.push(UndoState {
+                text: self.text.clone(),
+                cursor_idx: self.cursor_idx,
+                line_ending: self.line_ending,
+            });
+            self.redo_stack.clear();
+            self.undo_group_open = true;
+        }
+    }
+
+    /// Ends the current undo group, so the next edit starts a new undo step. Typing, deleting and
+    /// other edits made in a row are undone together; moving the cursor, saving, running a command
+    /// or pausing for a moment separates them.
+    pub fn break_undo_group(&mut self) {
+        self.undo_group_open = false;
+    }
+
+    /// Undoes the most recent group of edits. Returns false if there is nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        self.break_undo_group();
+        let Some(state) = self.undo_stack.pop() else {
+            return false;
+        };
+        let current = self.restore(state);
+        self.redo_stack.push(current);
+        true
+    }
+
+    /// Redoes the most recently undone group of edits. Returns false if there is nothing to redo.
+    pub fn redo(&mut self) -> bool {
+        self.break_undo_group();
+        let Some(state) = self.redo_stack.pop() else {
+            return false;
+        };
+        let current = self.restore(state);
+        self.undo_stack.push(current);
+        true
+    }
+
+    /// Puts the text and cursor back to `state`, returning the state they were in before.
+    fn restore(&mut self, state: UndoState) -> UndoState {
+        let current = UndoState {
+            text: std::mem::replace(&mut self.text, state.text),
+            cursor_idx: self.cursor_idx,
+            line_ending: std::mem::replace(&mut self.line_ending, state.line_ending),
+        };
+        self.revision += 1;
+        self.cursor_idx = state.cursor_idx;
+        self.selection_anchor = None;
+        self.dirty_buffer =
+            self.text != self.baseline || self.line_ending != self.baseline_line_ending;
+        current
+    }
+
+    /// Changes the case of the text in `range`. Characters can change length, as `ß` becomes `SS`,
+    /// so the range is replaced as a whole. A selection is kept over the new text, and otherwise
+    /// the cursor is kept inside it.
+    pub fn transform_case(&mut self, range: Range<usize>, op: CaseOp) {
+        let old_text = self.get_range(range.clone());
+        let new_text: String = match op {
+            CaseOp::Upper => old_text.to_uppercase(),
+            CaseOp::Lower => old_text.to_lowercase(),
+            CaseOp::Toggle => old_text.chars().fold(String::new(), |mut text, c| {
+                if c.is_uppercase() {
+                    text.extend(c.to_lowercase());
+                } else {
+                    text.extend(c.to_uppercase());
+                }
+                text
+            }),
+        };
+        if new_text == old_text {
+            return;
+        }
+
+        let anchor = self.selection_anchor;
+        self.begin_edit();
+        self.text.remove(range.clone());
+        self.text.insert(range.start, &new_text);
+        self.dirty_buffer = true;
+
+        let new_end = range.start + new_text.chars().count();
+        match anchor {
+            Some(anchor) if anchor <= self.cursor_idx => {
+                self.selection_anchor = Some(range.start);
+                self.cursor_idx = new_end;
+            }
+            Some(_) => {
+                self.selection_anchor = Some(new_end);
+                self.cursor_idx = range.start;
+            }
+            None => self.cursor_idx = self.cursor_idx.clamp(range.start, new_end),
+        }
+    }
+
+    /// Swaps the character before the cursor with the one at it and moves the cursor forward one.
+    /// At the end of a line, the last two characters are swapped instead. Does nothing at the start
+    /// of a line, so characters are never swapped across a line break.
+    pub fn transpose_chars(&mut self) {
+        let line_idx = self.get_logical_cursor_line();
+        let col = self.get_logical_cursor_col();
+        let line_len = self.line_len(line_idx);
+        if col == 0 || line_len < 2 {
+            return;
+        }
+
+        let line_start = self.text.line_to_char(line_idx);
+        let first = line_start + min(col, line_len - 1) - 1;
+        let pair: String = [self.text.char(first + 1), self.text.char(first)]
+            .iter()
+            .collect();
+
+        self.begin_edit();
+        self.text.remove(first..first + 2);
+        self.text.insert(first, &pair);
+        self.cursor_idx = first + 2;
+        self.dirty_buffer = true;
+    }
+
+    /// Finds the next occurrence of the selected text after the selection, wrapping around to the
+    /// top if needed, and selects it, keeping the old selection as one of `occurrences`. Returns
+    /// false if nothing is selected or there's no other occurrence.
+    pub fn add_next_occurrence(&mut self) -> bool {
+        let Some(selection) = self.selection_range() else {
+            return false;
+        };
+        let target = self.get_range(selection.clone());
+        let len = selection.len();
+
+        let text = self.text.to_string();
+        let from = self.text.char_to_byte(selection.end);
+        let found = text[from..]
+            .find(&target)
+            .map(|idx| idx + from)
+            .or_else(|| text.find(&target));
+        let Some(byte_idx) = found else {
+            return false;
+        };
+        let start = self.text.byte_to_char(byte_idx);
+        let range = start..start + len;
+        let overlaps = |other: &Range<usize>| range.start < other.end && other.start < range.end;
+        if overlaps(&selection) || self.occurrences.iter().any(overlaps) {
+            return false;
+        }
+
+        self.occurrences.push(selection);
+        self.selection_anchor = Some(range.start);
+        self.cursor_idx = range.end;
+        true
+    }
+
+    /// Applies a key typed while occurrences are collected to the selection, or the cursor if
+    /// nothing is selected, and to every occurrence. A character replaces each of them, and
+    /// Backspace or Delete removes them, or the character before or after each if they're empty.
+    /// Afterwards, each occurrence becomes an empty range after its edit, so typing can carry on
+    /// in every place. Returns false, changing nothing, for any other key.
+    pub fn edit_occurrences(&mut self, code: KeyCode) -> bool {
+        let len = self.text.len_chars();
+        let replacement = match code {
+            KeyCode::Char(c) => c.to_string(),
+            KeyCode::Backspace | KeyCode::Delete => String::new(),
+            _ => return false,
+        };
+        let widen = |range: Range<usize>| match code {
+            KeyCode::Backspace if range.is_empty() => range.start.saturating_sub(1)..range.end,
+            KeyCode::Delete if range.is_empty() => range.start..min(range.end + 1, len),
+            _ => range,
+        };
+
+        // Pair each range with whether it's the selection, and merge any that overlap once
+        // widened.
+        let selection = self
+            .selection_range()
+            .unwrap_or(self.cursor_idx..self.cursor_idx);
+        let mut ranges: Vec<(Range<usize>, bool)> = std::mem::take(&mut self.occurrences)
+            .into_iter()
+            .map(|range| (widen(range), false))
+            .chain(std::iter::once((widen(selection), true)))
+            .collect();
+        ranges.sort_by_key(|(range, _)| range.start);
+        ranges.dedup_by(
+            |(later, later_is_selection), (earlier, earlier_is_selection)| {
+                let overlaps = later.start < earlier.end || later == earlier;
+                if overlaps {
+                    earlier.end = earlier.end.max(later.end);
+                    *earlier_is_selection |= *later_is_selection;
+                }
+                overlaps
+            },
+        );
+
+        // Work from the start, keeping track of how much earlier edits have moved the text.
+        self.begin_edit();
+        let new_len = replacement.chars().count();
+        let mut shift = 0isize;
+        let mut carets = Vec::new();
+        for (range, is_selection) in ranges {
+            let start = range.start.saturating_add_signed(shift);
+            self.text.remove(start..start + range.len());
+            self.text.insert(start, &replacement);
+            shift += new_len as isize - range.len() as isize;
+            let end = start + new_len;
+            if is_selection {
+                self.cursor_idx = end;
+            } else {
+                carets.push(end..end);
+            }
+        }
+        self.occurrences = carets;
+        self.dirty_buffer = true;
+        true
+    }
+
+    /// Returns the range of the word the cursor is in or just after, or None if it isn't next to
+    /// one. Words are runs of letters, digits and underscores.
+    pub fn word_range_at_cursor(&self) -> Option<Range<usize>> {
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        let mut start = self.cursor_idx;
+        while start > 0 && is_word(self.text.char(start - 1)) {
+            start -= 1;
+        }
+        let mut end = self.cursor_idx;
+        while end < self.text.len_chars() && is_word(self.text.char(end)) {
+            end += 1;
+        }
+        (start < end).then_some(start..end)
+    }
+
+    /// Returns the range of the run of similar chars under the cursor: letters, digits and
+    /// underscores, or whitespace, or other punctuation. At the end of a line the char before the
+    /// cursor is used instead. Line feeds are never included.
+    pub fn word_bounds(&self) -> Range<usize> {
+        let class = |c: char| {
+            if c.is_alphanumeric() || c == '_' {
+                0
+            } else if c.is_whitespace() {
+                1
+            } else {
+                2
+            }
+        };
+        let len = self.text.len_chars();
+        let mut idx = self.cursor_idx;
+        if (idx == len || self.text.char(idx) == '\n') && idx > 0 && self.text.char(idx - 1) != '\n'
+        {
+            idx -= 1;
+        }
+        if idx == len || self.text.char(idx) == '\n' {
+            return self.cursor_idx..self.cursor_idx;
+        }
+
+        let target = class(self.text.char(idx));
+        let same = |c: char| c != '\n' && class(c) == target;
+        let mut start = idx;
+        while start > 0 && same(self.text.char(start - 1)) {
+            start -= 1;
+        }
+        let mut end = idx + 1;
+        while end < len && same(self.text.char(end)) {
+            end += 1;
+        }
+        start..end
+    }
+
+    /// Returns the range of the cursor's line, including its line feed.
+    pub fn line_bounds(&self) -> Range<usize> {
+        let line_idx = self.get_logical_cursor_line();
+        let start = self.text.line_to_char(line_idx);
+        start..start + self.text.line(line_idx).len_chars()
+    }
+
+    /// Returns the range of the paragraph containing the cursor, including the line feed after its
+    /// last line. On a blank line, this is just that line.
+    pub fn paragraph_bounds(&self) -> Range<usize> {
+        let lines = self.paragraph_at_cursor();
+        if lines.is_empty() {
+            return self.line_bounds();
+        }
+        self.text.line_to_char(lines.start)..self.text.line_to_char(lines.end)
+    }
+
+    /// Selects the chars in `range`, leaving the cursor at its end. An empty range clears the
+    /// selection instead.
+    pub fn select(&mut self, range: Range<usize>) {
+        self.selection_anchor = (!range.is_empty()).then_some(range.start);
+        self.cursor_idx = range.end;
+    }
+
+    /// Inserts the contents of the file at `path` at the cursor, leaving the cursor after them.
+    pub fn insert_file(&mut self, path: &Path) -> std::io::Result<()> {
+        let bytes = fs::read(path)?;
+        let (contents, _) = decode_file_bytes(&bytes);
+        self.insert_text(self.cursor_idx, &contents.replace("\r\n", "\n"));
+        Ok(())
+    }
+
+    /// Adds one level of indentation to the start of each non-empty line with an index in `lines`.
+    pub fn indent_lines(&mut self, lines: Range<usize>) {
+        let indent = self.indent_unit();
+        for line_idx in lines {
+            if self.text.line(line_idx).len_chars() > 1 {
+                self.insert_text(self.text.line_to_char(line_idx), &indent);
+            }
+        }
+    }
+
+    /// Removes up to one level of indentation from the start of each line with an index in `lines`.
+    /// A level is either a single tab or up to `indent_size` spaces.
+    pub fn dedent_lines(&mut self, lines: Range<usize>) {
+        for line_idx in lines {
+            let line = self.get_line(line_idx);
+            let width = if line.starts_with('\t') {
+                1
+            } else {
+                line.chars()
+                    .take(self.settings.indent_size)
+                    .take_while(|&c| c == ' ')
+                    .count()
+            };
+            let line_start = self.text.line_to_char(line_idx);
+            if width > 0 {
+                self.delete_range(line_start..line_start + width);
+            }
+        }
+    }
+
+    /// Replaces tabs with spaces on the lines with indices in `lines`. Each leading tab becomes
+    /// enough spaces to reach the next multiple of `indent_size`. If `all_tabs` is set, every other
+    /// tab becomes enough spaces to reach the next multiple of `tab_display_width`, so text after
+    /// it stays aligned. The cursor and selection anchor stay on the same characters.
+    pub fn retab(&mut self, lines: Range<usize>, all_tabs: bool) {
+        let indent_size = self.settings.indent_size.max(1);
+        let tab_width = self.settings.tab_display_width.max(1);
+
+        // Work from the bottom up so that earlier line indices stay valid.
+        for line_idx in lines.rev() {
+            let line_start = self.text.line_to_char(line_idx);
+            let line_len = self.line_len(line_idx);
+            let line: String = self.text.slice(line_start..line_start + line_len).into();
+
+            // `new_cols[i]` is the column in the new line of the char at column `i` of the old one.
+            let mut new_line = String::new();
+            let mut new_len = 0;
+            let mut new_cols = Vec::with_capacity(line_len + 1);
+            let mut leading = true;
+            for c in line.chars() {
+                new_cols.push(new_len);
+                leading &= c == ' ' || c == '\t';
+                if c == '\t' && (leading || all_tabs) {
+                    let stop = if leading { indent_size } else { tab_width };
+                    let width = stop - new_len % stop;
+                    new_line.push_str(&" ".repeat(width));
+                    new_len += width;
+                } else {
+                    new_line.push(c);
+                    new_len += 1;
+                }
+            }
+            new_cols.push(new_len);
+
+            if new_line == line {
+                continue;
+            }
+
+            self.cursor_idx = remap_column(self.cursor_idx, line_start, &new_cols);
+            let anchor = self
+                .selection_anchor
+                .map(|idx| remap_column(idx, line_start, &new_cols));
+
+            self.begin_edit();
+            self.text.remove(line_start..line_start + line_len);
+            self.text.insert(line_start, &new_line);
+            self.selection_anchor = anchor;
+            self.dirty_buffer = true;
+        }
+    }
+
+    /// Inserts a line feed at the cursor. With `auto_indent` on, the new line gets the current
+    /// line's indentation, and pressing Enter between an empty pair of brackets such as `{}` puts
+    /// the closing bracket on its own line with the cursor on an indented line in between.
+    pub fn insert_newline(&mut self) {
+        if !self.settings.auto_indent {
+            self.insert_text(self.cursor_idx, "\n");
+            return;
+        }
+
+        // Only the indentation before the cursor carries over to the new line.
+        let col = self.get_logical_cursor_col();
+        let indent: String = self
+            .get_line(self.get_logical_cursor_line())
+            .chars()
+            .take(col)
+            .take_while(|&c| c == ' ' || c == '\t')
+            .collect();
+
+        let before = (self.cursor_idx > 0).then(|| self.text.char(self.cursor_idx - 1));
+        let after =
+            (self.cursor_idx < self.text.len_chars()).then(|| self.text.char(self.cursor_idx));
+        let in_empty_pair = matches!(
+            (before, after),
+            (Some('{'), Some('}')) | (Some('['), Some(']')) | (Some('('), Some(')'))
+        );
+
+        let cursor_idx = self.cursor_idx;
+        if in_empty_pair {
+            let inner = format!("\n{}{}", indent, self.indent_unit());
+            self.insert_text(cursor_idx, &format!("{}\n{}", inner, indent));
+            self.cursor_idx = cursor_idx + inner.chars().count();
+        } else {
+            self.insert_text(cursor_idx, &format!("\n{}", indent));
+        }
+    }
+
+    /// Deletes the line with index `line_idx`, including its line feed, and puts the cursor at the
+    /// start of the line that takes its place.
+    pub fn delete_line(&mut self, line_idx: usize) {
+        if line_idx >= self.content_lines() {
+            return;
+        }
+
+        let start = self.text.line_to_char(line_idx);
+        let end = self.text.line_to_char(line_idx + 1);
+        // The last line has no line feed of its own, so take the one before it instead.
+        let range = if end == self.text.len_chars() && !self.get_line(line_idx).ends_with('\n') {
+            start.saturating_sub(1)..end
+        } else {
+            start..end
+        };
+        self.delete_range(range);
+        let line_idx = min(line_idx, self.content_lines().saturating_sub(1));
+        self.cursor_idx = self.text.line_to_char(line_idx);
+    }
+
+    /// Opens a new line below (or above, if `below` is false) the cursor's line and moves the
+    /// cursor onto it, wherever the cursor was in its line. With auto-indent on, the new line gets
+    /// the same indentation as the cursor's line.
+    pub fn open_line(&mut self, below: bool) {
+        let line_idx = self.get_logical_cursor_line();
+        let indent: String = if self.settings.auto_indent {
+            self.get_line(line_idx)
+                .chars()
+                .take_while(|&c| c == ' ' || c == '\t')
+                .collect()
+        } else {
+            String::new()
+        };
+        let indent_len = indent.chars().count();
+
+        let line_start = self.text.line_to_char(line_idx);
+        if below {
+            let line_end = line_start + self.line_len(line_idx);
+            self.insert_text(line_end, &format!("\n{}", indent));
+            self.cursor_idx = line_end + 1 + indent_len;
+        } else {
+            self.insert_text(line_start, &format!("{}\n", indent));
+            self.cursor_idx = line_start + indent_len;
+        }
+    }
+
+    /// Returns the text inserted for one level of indentation.
+    fn indent_unit(&self) -> String {
+        if self.settings.indent_with_spaces {
+            " ".repeat(self.settings.indent_size)
+        } else {
+            String::from("\t")
+        }
+    }
+
+    /// Inserts `text` at char index `idx`, moving the cursor and selection anchor along with any
+    /// text after the insertion point.
+    pub fn insert_text(&mut self, idx: usize, text: &str) {
+        let len = text.chars().count();
+        if self.cursor_idx >= idx {
+            self.cursor_idx += len;
+        }
+        let anchor = self
+            .selection_anchor
+            .map(|anchor| if anchor >= idx { anchor + len } else { anchor });
+        self.begin_edit();
+        self.text.insert(idx, text);
+        self.selection_anchor = anchor;
+        self.dirty_buffer = true;
+    }
+
+    /// Inserts spaces at the cursor up to the next multiple of `tab_display_width` columns, counting
+    /// from where the cursor is displayed. This pads with spaces even when indenting with tabs.
+    pub fn insert_spaces_to_tab_stop(&mut self) {
+        let col = self.get_visual_cursor_col() + self.visual_origin_col;
+        let tab_width = self.settings.tab_display_width.max(1);
+        let count = tab_width - col % tab_width;
+        self.insert_text(self.cursor_idx, &" ".repeat(count));
+    }
+
+    /// Puts `open` before and `close` after the chars in `range`, keeping the cursor and selection
+    /// anchor around the text inside the pair.
+    pub fn wrap_range(&mut self, range: Range<usize>, open: char, close: char) {
+        let anchor = self.selection_anchor;
+        self.begin_edit();
+        self.selection_anchor = anchor;
+        self.text.insert_char(range.end, close);
+        self.insert_text(range.start, &open.to_string());
+    }
+
+    /// Removes the chars in `range` from the buffer, keeping the cursor and selection anchor
+    /// pointing at the same text.
+    pub fn delete_range(&mut self, range: Range<usize>) {
+        self.cursor_idx = shift_for_delete(self.cursor_idx, &range);
+        let anchor = self
+            .selection_anchor
+            .map(|idx| shift_for_delete(idx, &range));
+        self.begin_edit();
+        self.text.remove(range);
+        self.selection_anchor = anchor;
+        self.dirty_buffer = true;
+    }
+
+    /// Save the current contents of the file. Returns Ok(false) without touching the file if it
+    /// already holds exactly what would be written, so its modification time doesn't change. If
+    /// `fsync_on_save` is set and the written file can't be synced to disk, the error wraps a
+    /// `SyncError`.
+    pub fn save_file(&mut self) -> std::io::Result<bool> {
+        if self.settings.trim_trailing_whitespace {
+            self.clean(CleanOptions {
+                collapse_blank_lines: false,
+                trim_trailing_whitespace: true,
+                single_final_newline: false,
+            });
+        }
+        let mut text = self.text();
+        if self.settings.insert_final_newline && !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        let bytes = self.encode_for_file(text);
+
+        let unchanged = fs::read(&self.file_path).is_ok_and(|old_bytes| old_bytes == bytes);
+        if !unchanged {
+            let mut output_file = fs::File::create(&self.file_path)?;
+            output_file.write_all(&bytes)?;
+            if self.settings.fsync_on_save {
+                output_file
+                    .sync_all()
+                    .map_err(|err| std::io::Error::other(SyncError(err)))?;
+            }
+        }
+
+        self.dirty_buffer = false;
+        self.mixed_line_endings = false;
+        self.edits_since_save = 0;
+        self.baseline = self.text.clone();
+        self.baseline_line_ending = self.line_ending;
+        self.revision += 1;
+        self.disk_mtime = self.read_disk_mtime();
+        self.file_changed_externally = false;
+        self.break_undo_group();
+        Ok(!unchanged)
+    }
+
+    /// Replaces the text with the file's contents on disk, as an edit that can be undone.
+    pub fn reload(&mut self) -> std::io::Result<()> {
+        let bytes = fs::read(&self.file_path)?;
+        let (contents, encoding) = decode_file_bytes(&bytes);
+
+        self.set_text(&contents);
+        self.encoding = encoding;
+        self.dirty_buffer = false;
+        self.edits_since_save = 0;
+        self.baseline = self.text.clone();
+        self.baseline_line_ending = self.line_ending;
+        self.revision += 1;
+        self.disk_mtime = self.read_disk_mtime();
+        self.file_changed_externally = false;
+        Ok(())
+    }
+
+    /// How long `run_formatter` waits for the formatter before stopping it.
+    const FORMATTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Replaces the text with the output of the shell command `command`, which is given the text
+    /// on stdin. The cursor stays on the same line and column where it can, and the line endings
+    /// are kept. Returns Ok(false) if the output is the same as the text, or the first line of the
+    /// command's error output if it can't be run or doesn't exit successfully. A command still
+    /// running after `FORMATTER_TIMEOUT` is killed and the text is left alone.
+    pub fn run_formatter(&mut self, command: &str) -> Result<bool, String> {
+        self.run_formatter_with_timeout(command, Self::FORMATTER_TIMEOUT)
+    }
+
+    fn run_formatter_with_timeout(
+        &mut self,
+        command: &str,
+        timeout: Duration,
+    ) -> Result<bool, String> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| format!("could not run {}: {}", command, err))?;
+
+        // Write and read from other threads, so a command that starts writing before it has read
+        // all of its input can't leave both sides waiting on a full pipe, and so the deadline can
+        // be checked while it runs.
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let text = self.text();
+        let writer = thread::spawn(move || stdin.write_all(text.as_bytes()));
+        let read_all = |mut pipe: Box<dyn Read + Send>| {
+            thread::spawn(move || {
+                let mut bytes = Vec::new();
+                let _ = pipe.read_to_end(&mut bytes);
+                bytes
+            })
+        };
+        let stdout = read_all(Box::new(child.stdout.take().expect("stdout is piped")));
+        let stderr = read_all(Box::new(child.stderr.take().expect("stderr is piped")));
+
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                Ok(None) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(format!(
+                        "{} took longer than {} seconds and was stopped",
+                        command,
+                        timeout.as_secs_f32()
+                    ));
+                }
+                Err(err) => return Err(format!("could not run {}: {}", command, err)),
+            }
+        };
+        let _ = writer.join();
+        let output = std::process::Output {
+            status,
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        };
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(match stderr.lines().find(|line| !line.trim().is_empty()) {
+                Some(line) => line.trim().to_string(),
+                None => format!("{} exited with {}", command, output.status),
+            });
+        }
+        let formatted = String::from_utf8(output.stdout)
+            .map_err(|_| format!("{} didn't write valid UTF-8", command))?;
+        if formatted == self.text() {
+            return Ok(false);
+        }
+
+        let (line, col) = self.get_logical_cursor_pos();
+        let line_ending = self.line_ending;
+        self.set_text(&formatted);
+        self.line_ending = line_ending;
+        self.go_to_position(line, col);
+        Ok(true)
+    }
+
+    /// Sets `file_changed_externally` if the file's modification time differs from when it was
+    /// loaded or last saved, including if it has since been deleted. Returns true if the change
+    /// has only just been noticed.
+    pub fn check_disk_changes(&mut self) -> bool {
+        if self.file_changed_externally || self.disk_mtime.is_none() || self.listing.is_some() {
+            return false;
+        }
+        self.file_changed_externally = self.read_disk_mtime() != self.disk_mtime;
+        self.file_changed_externally
+    }
+
+    /// Returns the modification time of the file on disk, or None if it can't be read.
+    fn read_disk_mtime(&self) -> Option<SystemTime> {
+        fs::metadata(&self.file_path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    /// Writes the selection, or the whole buffer if nothing is selected, to the file at `path`. The
+    /// buffer's own file and modified state are left alone.
+    pub fn write_selection_to(&self, path: &Path) -> std::io::Result<()> {
+        let text = match self.selection_range() {
+            Some(range) => self.get_range(range),
+            None => self.text(),
+        };
+        fs::write(path, self.encode_for_file(text))
+    }
+
+    /// Returns the bytes to write to a file for `text`, converting line feeds back to the buffer's
+    /// line ending and encoding it in the buffer's encoding, byte order mark and all.
+    fn encode_for_file(&self, mut text: String) -> Vec<u8> {
+        if self.line_ending == LineEnding::Crlf {
+            text = text.replace('\n', "\r\n");
+        }
+
+        let mut bytes = self.file_byte_order_mark().to_vec();
+        bytes.extend(self.encoding.encode(&text));
+        bytes
+    }
+
+    /// Returns the byte order mark saving writes at the start of the file, which may be empty.
+    /// UTF-8 files get one if they had one or `write_utf8_bom` is set.
+    fn file_byte_order_mark(&self) -> &'static [u8] {
+        match self.encoding {
+            DetectedEncoding::Utf8 | DetectedEncoding::Lossy if self.settings.write_utf8_bom => {
+                UTF8_BOM
+            }
+            encoding => encoding.byte_order_mark(),
+        }
+    }
+
+    /// Returns how each line differs from the text as it was loaded or last saved, indexed by line.
+    /// The result is cached until the text is edited or saved.
+    pub fn line_changes(&mut self) -> &[Option<LineChange>] {
+        let stale = self
+            .diff_cache
+            .as_ref()
+            .is_none_or(|(revision, _)| *revision != self.revision);
+        if stale {
+            let changes = diff_lines(&self.baseline, &self.text);
+            self.diff_cache = Some((self.revision, changes));
+        }
+        match &self.diff_cache {
+            Some((_, changes)) => changes,
+            None => &[],
+        }
+    }
+
+    /// Returns the index of the first line of each run of changed lines, in order.
+    pub fn hunk_starts(&mut self) -> Vec<usize> {
+        let changes = self.line_changes();
+        (0..changes.len())
+            .filter(|&idx| changes[idx].is_some() && (idx == 0 || changes[idx - 1].is_none()))
+            .collect()
+    }
+
+    /// Return the text for the editor to use as a status bar for this buffer, as a pair of strings
+    /// to show on the left and right of the bar. The left holds the file name and flags, and the
+    /// right holds the size of the selection, if there is one, then the language and cursor
+    /// position.
+    pub fn get_status_bar_text(&self) -> (String, String) {
+        let mut left = match &self.listing {
+            Some(dir) => format!("Browsing {}", dir.display()),
+            None => format!("Viewing file {}", self.file_name()),
+        };
+        if self.dirty_buffer {
+            left.push_str(" [+]");
+        }
+        if self.is_binary {
+            left.push_str(" [binary]");
+        }
+        if self.overwrite {
+            left.push_str(" [overwrite]");
+        }
+        if self.file_changed_externally {
+            left.push_str(" [changed on disk]");
+        }
+
+        let (line, col) = self.get_logical_cursor_pos();
+        let percentage = (line + 1) * 100 / self.len_lines();
+        let mut right = String::new();
+        if let Some(range) = self.selection_range() {
+            right.push_str(&format!(
+                "SEL {} chars, {} lines | ",
+                range.len(),
+                self.selected_lines().len()
+            ));
+        }
+        right.push_str(&format!(
+            "{} | Ln {}, Col {} | {}% ",
+            self.language(),
+            line + 1,
+            col + 1,
+            percentage
+        ));
+
+        (left, right)
+    }
+
+    /// Returns the columns of the words on a line that aren't in `dictionary`, checking only the
+    /// words that reach into the columns `cols`. Only prose, such as Markdown or plain text, is
+    /// checked; for anything else this is always empty.
+    #[cfg(feature = "spell")]
+    pub fn misspelled_ranges(
+        &self,
+        line_idx: usize,
+        cols: Range<usize>,
+        dictionary: &Dictionary,
+    ) -> Vec<Range<usize>> {
+        let extension = self
+            .file_path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if !matches!(extension.as_str(), "md" | "markdown" | "txt") {
+            return Vec::new();
+        }
+
+        // Widen the columns to whole words, so words cut off at either edge are checked whole.
+        let line = self.line_slice(line_idx);
+        let mut end = min(cols.end, line.len_chars());
+        let mut start = min(cols.start, end);
+        while start > 0 && line.char(start - 1).is_ascii_alphabetic() {
+            start -= 1;
+        }
+        while end < line.len_chars() && line.char(end).is_ascii_alphabetic() {
+            end += 1;
+        }
+        dictionary
+            .misspelled_ranges(&line.slice(start..end).to_string())
+            .into_iter()
+            .map(|range| range.start + start..range.end + start)
+            .collect()
+    }
+
+    /// Returns the path-like token under the cursor, if there is one. The token stops at
+    /// whitespace, quotes, brackets and punctuation that can't sensibly end a path.
+    pub fn path_under_cursor(&self) -> Option<String> {
+        let line = self.get_line(self.get_logical_cursor_line());
+        path_token_at(&line, self.get_logical_cursor_col()).map(String::from)
+    }
+
+    /// Resolves `path` against the directory of this buffer's file, unless it's already absolute.
+    pub fn resolve_relative_path(&self, path: &str) -> PathBuf {
+        match self.file_path.parent() {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+
+    /// Returns the name of the language of the file, based on its extension.
+    pub fn language(&self) -> &'static str {
+        let extension = self
+            .file_path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        match extension.as_str() {
+            "rs" => "Rust",
+            "py" => "Python",
+            "go" => "Go",
+            "c" | "h" => "C",
+            "cpp" | "cc" | "cxx" | "hpp" => "C++",
+            "js" | "mjs" => "JavaScript",
+            "ts" => "TypeScript",
+            "java" => "Java",
+            "sh" | "bash" => "Shell",
+            "md" | "markdown" => "Markdown",
+            "html" | "htm" => "HTML",
+            "css" => "CSS",
+            "json" => "JSON",
+            "toml" => "TOML",
+            "yaml" | "yml" => "YAML",
+            _ => "Plain",
+        }
+    }
+
+    /// Returns how comments are written in the file's language, based on its extension. Both kinds
+    /// are None for languages without comments or that aren't known.
+    pub fn comment_syntax(&self) -> CommentSyntax {
+        let extension = self
+            .file_path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let (line, block) = match extension.as_str() {
+            "rs" | "go" | "c" | "h" | "cpp" | "cc" | "cxx" | "hpp" | "js" | "mjs" | "ts"
+            | "java" => (Some("//"), Some(("/*", "*/"))),
+            "css" => (None, Some(("/*", "*/"))),
+            "html" | "htm" | "xml" | "md" | "markdown" => (None, Some(("<!--", "-->"))),
+            "py" | "sh" | "bash" | "toml" | "yaml" | "yml" => (Some("#"), None),
+            _ => (None, None),
+        };
+        CommentSyntax { line, block }
+    }
+
+    /// Wraps the chars in `range` in a block comment opened by `open` and closed by `close`, or
+    /// unwraps them if they're already wrapped in one, ignoring whitespace around them. A space
+    /// goes between the delimiters and the text, and is removed with them. The result is selected.
+    pub fn toggle_block_comment(&mut self, range: Range<usize>, open: &str, close: &str) {
+        let text = self.get_range(range.clone());
+        let inner = text.trim();
+        let unwrapped = inner
+            .strip_prefix(open)
+            .and_then(|inner| inner.strip_suffix(close))
+            .map(|inner| {
+                let inner = inner.strip_prefix(' ').unwrap_or(inner);
+                inner.strip_suffix(' ').unwrap_or(inner)
+            });
+
+        // Only the trimmed text is replaced, so the whitespace around it stays where it was.
+        let leading = text.chars().count() - text.trim_start().chars().count();
+        let start = range.start + leading;
+        let end = start + inner.chars().count();
+        let replacement = match unwrapped {
+            Some(inner) => inner.to_string(),
+            None => format!("{} {} {}", open, inner, close),
+        };
+
+        self.break_undo_group();
+        self.delete_range(start..end);
+        self.insert_text(start, &replacement);
+        self.break_undo_group();
+        self.select(start..start + replacement.chars().count());
+    }
+
+    /// Comments out the lines with indices in `lines` by putting `prefix` and a space before them,
+    /// lined up with the least indented line. If every non-blank line is already commented out, the
+    /// prefixes and the spaces after them are removed instead. Blank lines are left alone.
+    pub fn toggle_line_comments(&mut self, lines: Range<usize>, prefix: &str) {
+        let content_lines: Vec<usize> = lines
+            .filter(|&idx| !self.get_line(idx).trim().is_empty())
+            .collect();
+        let commented = content_lines
+            .iter()
+            .all(|&idx| self.get_line(idx).trim_start().starts_with(prefix));
+        let indent = content_lines
+            .iter()
+            .map(|&idx| {
+                let line = self.get_line(idx);
+                line.chars().count() - line.trim_start().chars().count()
+            })
+            .min()
+            .unwrap_or(0);
+
+        self.break_undo_group();
+        for idx in content_lines {
+            let line = self.get_line(idx);
+            let line_start = self.text.line_to_char(idx);
+            if commented {
+                let leading = line.chars().count() - line.trim_start().chars().count();
+                let after = &line.trim_start()[prefix.len()..];
+                let len = prefix.chars().count() + usize::from(after.starts_with(' '));
+                self.delete_range(line_start + leading..line_start + leading + len);
+            } else {
+                self.insert_text(line_start + indent, &format!("{} ", prefix));
+            }
+        }
+        self.break_undo_group();
+    }
+
+    /// Returns the name of the file shown in this buffer, without its directory.
+    pub fn file_name(&self) -> String {
+        self.file_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "<unnamed>".to_string())
+    }
+
+    /// Moves the cursor right by one character.
+    pub fn move_right(&mut self) {
+        if self.cursor_idx < self.len_chars() {
+            self.cursor_idx += 1;
+        }
+    }
+
+    /// Moves the cursor left by one character.
+    pub fn move_left(&mut self) {
+        if self.cursor_idx > 0 {
+            self.cursor_idx -= 1;
+        }
+    }
+
+    /// Moves the cursor to the next occurrence of `search.target` on the cursor's line, looking in
+    /// the direction and stopping where `search` says. With `repeat`, an occurrence right next to
+    /// the cursor is passed over when stopping short of it, so repeating the search keeps moving.
+    /// Returns false and leaves the cursor alone if there's no occurrence.
+    pub fn find_char_on_line(&mut self, search: CharSearch, repeat: bool) -> bool {
+        let line_idx = self.get_logical_cursor_line();
+        let line_start = self.text.line_to_char(line_idx);
+        let line_end = line_start + self.line_len(line_idx);
+        let skip = if search.till && repeat { 1 } else { 0 };
+        let is_target = |&idx: &usize| self.text.char(idx) == search.target;
+        let found = if search.forward {
+            (self.cursor_idx + 1 + skip..line_end).find(is_target)
+        } else {
+            (line_start..self.cursor_idx.saturating_sub(skip))
+                .rev()
+                .find(is_target)
+        };
+
+        let Some(idx) = found else {
+            return false;
+        };
+        self.cursor_idx = match (search.till, search.forward) {
+            (false, _) => idx,
+            (true, true) => idx - 1,
+            (true, false) => idx + 1,
+        };
+        true
+    }
+
+    /// Moves the cursor to column `col` of line `line`, both counted from 0. Positions past the last
+    /// line with content or the end of the line are clamped to them.
+    pub fn go_to_position(&mut self, line: usize, col: usize) {
+        let line = min(line, self.content_lines().saturating_sub(1));
+        self.cursor_idx = self.text.line_to_char(line) + min(col, self.line_len(line));
+    }
+
+    /// Moves the cursor to the start of the line `percent` of the way through the buffer, so 0 is
+    /// the first line and 100 is the last line with content. Percentages over 100 count as 100.
+    pub fn go_to_percentage(&mut self, percent: usize) {
+        let last_line = self.content_lines().saturating_sub(1);
+        let line = min(min(percent, 100) * self.content_lines() / 100, last_line);
+        self.cursor_idx = self.text.line_to_char(line);
+    }
+
+    /// Returns the display column vertical movement should aim for: the goal column if the cursor
+    /// hasn't moved since the last vertical move, or else the cursor's own display column.
+    fn vertical_goal_col(&self) -> usize {
+        match self.goal_col {
+            Some((col, idx)) if idx == self.cursor_idx => col,
+            _ => self.display_column(self.cursor_idx),
+        }
+    }
+
+    /// Returns the char index on line `line_idx` that vertical movement aiming for display column
+    /// `goal_col` lands on: the first char drawn at or past it, or the end of a shorter line.
+    fn char_at_goal_col(&self, line_idx: usize, goal_col: usize) -> usize {
+        let col = self
+            .first_char_past_column(line_idx, goal_col)
+            .unwrap_or_else(|| self.line_len(line_idx));
+        self.text.line_to_char(line_idx) + col
+    }
+
+    /// Moves the cursor up a line.
+    pub fn move_up(&mut self) {
+        let goal_col = self.vertical_goal_col();
+        let cursor_line = self.get_logical_cursor_line();
+        // If we're on the first line, go to the beginning of the line.
+        if cursor_line == 0 {
+            self.cursor_idx = 0;
+        } else {
+            self.cursor_idx = self.char_at_goal_col(cursor_line - 1, goal_col);
+        }
+        self.goal_col = Some((goal_col, self.cursor_idx));
+    }
+
+    /// Moves the cursor down a line. The empty line after a trailing line feed is never moved onto:
+    /// on the last line with content, the cursor goes to the end of that line instead.
+    pub fn move_down(&mut self) {
+        let goal_col = self.vertical_goal_col();
+        let cursor_line = self.get_logical_cursor_line();
+        let last_line = self.content_lines().saturating_sub(1);
+        if cursor_line > last_line {
+            // Already past the last line with content, e.g. after pressing Enter at the very end.
+            return;
+        }
+
+        // If we're on the last line, go the end of the line.
+        if cursor_line == last_line {
+            self.cursor_idx = self.text.line_to_char(last_line) + self.line_len(last_line);
+        } else {
+            self.cursor_idx = self.char_at_goal_col(cursor_line + 1, goal_col);
+        }
+        self.goal_col = Some((goal_col, self.cursor_idx));
+    }
+
+    pub fn handle_key_event(&mut self, key_event: KeyEvent) {
+        let (current_line_idx, _) = self.get_logical_cursor_pos();
+        if key_event.kind != KeyEventKind::Release {
+            // With occurrences collected, typing, Backspace and Delete edit all of them until
+            // another key is pressed, which forgets them.
+            if !self.occurrences.is_empty() {
+                if self.edit_occurrences(key_event.code) {
+                    return;
+                }
+                self.occurrences.clear();
+            }
+
+            // Tab and Shift-Tab indent and dedent the selected lines when there is a selection.
+            let selected_lines = self.selection_range().map(|_| self.selected_lines());
+            let is_indent = matches!(key_event.code, KeyCode::Tab | KeyCode::BackTab);
+
+            // Typing an opening delimiter with a selection wraps the selection in the pair.
+            let wrap = match key_event.code {
+                KeyCode::Char(c) => closing_pair(c).zip(self.selection_range()),
+                _ => None,
+            };
+
+            // Holding shift while moving extends the selection. Any other key clears it.
+            let is_movement = matches!(
+                key_event.code,
+                KeyCode::Right
+                    | KeyCode::Left
+                    | KeyCode::Up
+                    | KeyCode::Down
+                    | KeyCode::Home
+                    | KeyCode::End
+            );
+            if is_movement {
+                self.prepare_selection(key_event.modifiers.contains(KeyModifiers::SHIFT));
+            } else if !(is_indent && selected_lines.is_some()) && wrap.is_none() {
+                self.selection_anchor = None;
+            }
+            if is_movement {
+                self.break_undo_group();
+            }
+
+            match key_event.code {
+                KeyCode::Right => self.move_right(),
+                KeyCode::Left => {
+                    self.move_left();
+                }
+                KeyCode::Up if key_event.modifiers.contains(KeyModifiers::CONTROL) => {
+                    self.prev_paragraph();
+                }
+                KeyCode::Down if key_event.modifiers.contains(KeyModifiers::CONTROL) => {
+                    self.next_paragraph();
+                }
+                KeyCode::Up => {
+                    self.move_up();
+                }
+                KeyCode::Down => {
+                    self.move_down();
+                }
+                KeyCode::Home => {
+                    if key_event.modifiers.contains(KeyModifiers::CONTROL) {
+                        self.cursor_idx = 0;
+                    } else {
+                        self.cursor_idx = self.line_to_char(current_line_idx);
+                    }
+                }
+                KeyCode::End => {
+                    if key_event.modifiers.contains(KeyModifiers::CONTROL) {
+                        self.cursor_idx = self.len_chars();
+                    } else {
+                        let current_line = self.get_line(current_line_idx);
+                        let mut current_line_len = current_line.graphemes(true).count();
+
+                        if let Some('\n') = current_line.chars().last() {
+                            current_line_len -= 1;
+                        }
+
+                        let current_line_char_idx = self.line_to_char(current_line_idx);
+                        self.cursor_idx = current_line_char_idx + current_line_len;
+                    }
+                }
+                KeyCode::Char(open) if let Some((close, range)) = wrap => {
+                    self.wrap_range(range, open, close)
+                }
+                KeyCode::Char(x) => {
+                    let mut buf = [0u8; 4];
+                    self.begin_edit();
+                    if self.overwrite
+                        && self.cursor_idx < self.text.len_chars()
+                        && self.text.char(self.cursor_idx) != '\n'
+                    {
+                        self.text.remove(self.cursor_idx..self.cursor_idx + 1);
+                    }
+                    self.text.insert(self.cursor_idx, x.encode_utf8(&mut buf));
+                    self.cursor_idx += 1;
+                    self.dirty_buffer = true;
+                }
+                KeyCode::Insert => self.overwrite = !self.overwrite,
+                KeyCode::Enter => self.insert_newline(),
+                KeyCode::Backspace if self.cursor_idx != 0 => {
+                    // When indenting with spaces, backspace in leading whitespace removes back to
+                    // the previous indent stop.
+                    let col = self.get_logical_cursor_col();
+                    let line_start = self.cursor_idx - col;
+                    let in_indent = self
+                        .text
+                        .slice(line_start..self.cursor_idx)
+                        .chars()
+                        .all(|c| c == ' ');
+                    let count = if self.settings.indent_with_spaces && col > 0 && in_indent {
+                        (col - 1) % self.settings.indent_size.max(1) + 1
+                    } else {
+                        1
+                    };
+                    self.delete_range(self.cursor_idx - count..self.cursor_idx);
+                }
+                KeyCode::Tab => match selected_lines {
+                    Some(lines) => self.indent_lines(lines),
+                    None => self.insert_text(self.cursor_idx, &self.indent_unit()),
+                },
+                KeyCode::BackTab => self
+                    .dedent_lines(selected_lines.unwrap_or(current_line_idx..current_line_idx + 1)),
+                KeyCode::Delete if self.cursor_idx != self.text.len_chars() => {
+                    self.begin_edit();
+                    self.text.remove(self.cursor_idx..self.cursor_idx + 1);
+                    self.dirty_buffer = true;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Gets the selection ready for the cursor to move: if `extend` is set, a selection is started at
+    /// the cursor unless there already is one, and otherwise the selection is cleared.
+    pub fn prepare_selection(&mut self, extend: bool) {
+        if !extend {
+            self.selection_anchor = None;
+        } else if self.selection_anchor.is_none() {
+            self.selection_anchor = Some(self.cursor_idx);
+        }
+    }
+
+    /// Pulls the cursor and selection anchor back inside the buffer if something has moved them
+    /// past the end of the text.
+    pub(super) fn clamp_cursor(&mut self) {
+        let len = self.text.len_chars();
+        self.cursor_idx = min(self.cursor_idx, len);
+        self.selection_anchor = self.selection_anchor.map(|anchor| min(anchor, len));
+    }
+
+    /// Returns the byte offset of the cursor in the file as it would be saved, counting the carriage
+    /// returns of a CRLF file and any byte order mark.
+    pub fn cursor_byte_offset(&self) -> usize {
+        let carriage_returns = match self.line_ending {
+            LineEnding::Lf => 0,
+            LineEnding::Crlf => self.get_logical_cursor_line(),
+        };
+        let offset = match self.encoding {
+            DetectedEncoding::Utf16Le | DetectedEncoding::Utf16Be => {
+                2 * (self.text.char_to_utf16_cu(self.cursor_idx) + carriage_returns)
+            }
+            _ => self.text.char_to_byte(self.cursor_idx) + carriage_returns,
+        };
+        offset + self.file_byte_order_mark().len()
+    }
+
+    /// Returns the character at the cursor, or None at the end of the buffer.
+    pub fn char_at_cursor(&self) -> Option<char> {
+        (self.cursor_idx < self.text.len_chars()).then(|| self.text.char(self.cursor_idx))
+    }
+
+    /// Returns the char index of the cursor. Like the other metric getters below, this only reads
+    /// buffer state, so it's safe to call without a terminal or raw mode.
+    #[cfg(test)]
+    pub fn cursor_idx(&self) -> usize {
+        self.cursor_idx
+    }
+
+    /// Returns the viewport as (first line, first column, width, height). Safe to call without a
+    /// terminal or raw mode.
+    #[cfg(test)]
+    pub fn viewport(&self) -> (usize, usize, usize, usize) {
+        (
+            self.visual_origin_row,
+            self.visual_origin_col,
+            self.visual_width,
+            self.visual_height,
+        )
+    }
+
+    /// Returns the full contents of the buffer, with LF line endings. Safe to call without a
+    /// terminal or raw mode.
+    pub fn text(&self) -> String {
+        self.text.to_string()
+    }
+
+    /// Replaces the full contents of the buffer with `text`, as a single undo step, and marks the
+    /// buffer as modified. The text is read the same way as by `from_str`, so its line endings
+    /// become the buffer's. The cursor keeps its index, pulled back to the end of the new text if
+    /// that's shorter, and the selection is cleared.
+    pub fn set_text(&mut self, text: &str) {
+        self.begin_edit();
+        (self.line_ending, self.mixed_line_endings) = LineEnding::detect(text);
+        self.text = Rope::from_str(&text.replace("\r\n", "\n"));
+        self.selection_anchor = None;
+        self.occurrences.clear();
+        self.clamp_cursor();
+        self.dirty_buffer = true;
+        self.break_undo_group();
+    }
+
+    /// Returns the logical line and column that the cursor is on. (line, column).
+    pub fn get_logical_cursor_pos(&self) -> (usize, usize) {
+        (
+            self.get_logical_cursor_line(),
+            self.get_logical_cursor_col(),
+        )
+    }
+
+    /// Gets the logical line that the cursor is on.
+    pub fn get_logical_cursor_line(&self) -> usize {
+        self.text.char_to_line(self.cursor_idx)
+    }
+
+    /// Gets the logical column that the cursor is on.
+    pub fn get_logical_cursor_col(&self) -> usize {
+        self.cursor_idx - self.text.line_to_char(self.get_logical_cursor_line())
+    }
+
+    /// Gets the column that the cursor should be shown at visually.
+    pub fn get_visual_cursor_col(&self) -> usize {
+        self.display_column(self.cursor_idx) - self.visual_origin_col
+    }
+
+    /// Returns the display column char index `idx` is drawn at on its line, with tabs taking up
+    /// `tab_display_width` columns and wide characters two. Only the part of the line before `idx`
+    /// is looked at, and none of it is copied.
+    pub fn display_column(&self, idx: usize) -> usize {
+        let line_start = self.text.line_to_char(self.text.char_to_line(idx));
+        self.text
+            .slice(line_start..idx)
+            .chars()
+            .map(|c| self.char_display_width(c))
+            .sum()
+    }
+
+    /// Returns the number of display columns `c` takes up.
+    fn char_display_width(&self, c: char) -> usize {
+        if c == '\t' {
+            self.settings.tab_display_width
+        } else {
+            c.width_cjk().unwrap_or(0)
+        }
+    }
+
+    /// Gets the row that the cursor should be shown at visually.
+    pub fn get_visual_cursor_line(&self) -> usize {
+        self.get_logical_cursor_line() - self.visual_origin_row
+    }
+
+    /// Get the number of lines in the buffer. Like ropey, this counts the empty line after a trailing
+    /// line feed, so "a\nb\n" has three lines. See `content_lines` for a count without it.
+    pub fn len_lines(&self) -> usize {
+        self.text.len_lines()
+    }
+
+    /// Get the character index of a given line.
     pub fn line_to_char(&self, idx: usize) -> usize {
         self.text.line_to_char(idx)
     }
 
-    pub fn char_to_line(&self, idx: usize) -> usize {
-        self.text.char_to_line(idx)
+    pub fn char_to_line(&self, idx: usize) -> usize {
+        self.text.char_to_line(idx)
+    }
+
+    /// Get the length of the buffer in chars.
+    pub fn len_chars(&self) -> usize {
+        self.text.len_chars()
+    }
+
+    /// Returns the range of chars covered by the selection, or None if nothing is selected.
+    pub fn selection_range(&self) -> Option<Range<usize>> {
+        let anchor = self.selection_anchor?;
+        if anchor == self.cursor_idx {
+            return None;
+        }
+        Some(min(anchor, self.cursor_idx)..anchor.max(self.cursor_idx))
+    }
+
+    /// Returns the indices of the lines touched by the selection, or of every line in the buffer if
+    /// there is no selection. A selection ending at the very start of a line doesn't include that
+    /// line, and nor does the empty line after a trailing line feed.
+    pub fn selected_lines(&self) -> Range<usize> {
+        match self.selection_range() {
+            Some(range) => {
+                let first = self.text.char_to_line(range.start);
+                let last = self.text.char_to_line(range.end - 1);
+                first..last + 1
+            }
+            None => 0..self.content_lines(),
+        }
+    }
+
+    /// Get the number of lines in the buffer, not counting the empty line after a trailing line
+    /// feed.
+    pub fn content_lines(&self) -> usize {
+        let lines = self.text.len_lines();
+        if self.text.line(lines - 1).len_chars() == 0 {
+            lines - 1
+        } else {
+            lines
+        }
+    }
+
+    /// Get the length in chars of a line, not counting its line feed.
+    pub fn line_len(&self, idx: usize) -> usize {
+        let line = self.text.line(idx);
+        let len = line.len_chars();
+        if len > 0 && line.char(len - 1) == '\n' {
+            len - 1
+        } else {
+            len
+        }
+    }
+
+    /// Returns the column (in chars) of the first char on line `line_idx` drawn at or past display
+    /// column `limit`, with tabs taking up `tab_display_width` columns and wide characters two, or
+    /// None if the whole line fits.
+    pub fn first_char_past_column(&self, line_idx: usize, limit: usize) -> Option<usize> {
+        let mut display_col = 0;
+        for (col, c) in self.text.line(line_idx).chars().enumerate() {
+            if c == '\n' || c == '\r' {
+                break;
+            }
+            if display_col >= limit {
+                return Some(col);
+            }
+            display_col += self.char_display_width(c);
+        }
+        None
+    }
+
+    /// Returns the column where the whitespace at the end of a line starts, or None if the line
+    /// doesn't end in whitespace.
+    pub fn trailing_whitespace_start(&self, line_idx: usize) -> Option<usize> {
+        let line = self.text.line(line_idx);
+        let len = self.line_len(line_idx);
+        let mut start = len;
+        while start > 0 && line.char(start - 1).is_whitespace() {
+            start -= 1;
+        }
+        (start < len).then_some(start)
+    }
+
+    /// Get the text of the chars in `range` as a string.
+    pub fn get_range(&self, range: Range<usize>) -> String {
+        self.text.slice(range).to_string()
+    }
+
+    /// Get the text of the chars in `cols` of a line as a string, leaving out its line ending.
+    /// Only that part of the line is copied, however long the line is.
+    pub fn get_line_window(&self, idx: usize, cols: Range<usize>) -> String {
+        let line = self.line_slice(idx);
+        let end = min(cols.end, line.len_chars());
+        let start = min(cols.start, end);
+        line.slice(start..end).to_string()
+    }
+
+    /// Get a line from the buffer without its line ending, as a slice of the text rather than a
+    /// copy of it.
+    pub fn line_slice(&self, idx: usize) -> RopeSlice<'_> {
+        let line = self.text.line(idx);
+        let mut len = self.line_len(idx);
+        if len > 0 && line.char(len - 1) == '\r' {
+            len -= 1;
+        }
+        line.slice(..len)
+    }
+
+    /// Get the text of a line from the buffer as a string.
+    pub fn get_line(&self, idx: usize) -> String {
+        self.text.line(idx).to_string()
+    }
+}
+
+/// The most lines `diff_lines` will compare pairwise, as the product of the number of changed
+/// lines on each side. Past this, every changed line is simply marked as modified.
+const MAX_DIFF_CELLS: usize = 1_000_000;
+
+/// Compares `new` against `old` line by line and returns how each line of `new` differs. Lines
+/// are matched up using the longest common subsequence of the lines between the common prefix and
+/// suffix.
+fn diff_lines(old: &Rope, new: &Rope) -> Vec<Option<LineChange>> {
+    // Compare lines without their line feeds, so adding a trailing newline doesn't count as
+    // changing the last line.
+    let old: Vec<RopeSlice> = old.lines().map(without_line_feed).collect();
+    let new: Vec<RopeSlice> = new.lines().map(without_line_feed).collect();
+    let mut changes = vec![None; new.len()];
+
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let matches = if old_mid.len() * new_mid.len() <= MAX_DIFF_CELLS {
+        longest_common_subsequence(old_mid, new_mid)
+    } else {
+        Vec::new()
+    };
+
+    // Each gap between matched lines is a hunk. Lines in the new side of a hunk count as modified
+    // while there are old lines to pair them with, and as added after that. A hunk with no new
+    // lines marks the line after it instead.
+    let (mut old_idx, mut new_idx) = (0, 0);
+    for (old_match, new_match) in matches
+        .into_iter()
+        .chain(std::iter::once((old_mid.len(), new_mid.len())))
+    {
+        let removed = old_match - old_idx;
+        for idx in new_idx..new_match {
+            changes[prefix + idx] = Some(if idx - new_idx < removed {
+                LineChange::Modified
+            } else {
+                LineChange::Added
+            });
+        }
+        if removed > 0 && new_match == new_idx {
+            let line_idx = min(prefix + new_match, new.len() - 1);
+            changes[line_idx].get_or_insert(LineChange::Deleted);
+        }
+        (old_idx, new_idx) = (old_match + 1, new_match + 1);
+    }
+
+    changes
+}
+
+/// Returns `line` without its trailing line feed, if it has one.
+fn without_line_feed(line: RopeSlice<'_>) -> RopeSlice<'_> {
+    let len = line.len_chars();
+    if len > 0 && line.char(len - 1) == '\n' {
+        line.slice(..len - 1)
+    } else {
+        line
+    }
+}
+
+/// Returns the index pairs of the lines of `old` and `new` that make up their longest common
+/// subsequence, in order.
+fn longest_common_subsequence(old: &[RopeSlice], new: &[RopeSlice]) -> Vec<(usize, usize)> {
+    // lengths[i][j] is the length of the longest common subsequence of old[i..] and new[j..].
+    let width = new.len() + 1;
+    let mut lengths = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i * width + j] = if old[i] == new[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                max(lengths[(i + 1) * width + j], lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut matches = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            matches.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
     }
+    matches
+}
 
-    /// Get the length of the buffer in chars.
-    pub fn len_chars(&self) -> usize {
-        self.text.len_chars()
+/// Makes a file path absolute, so it keeps pointing at the same file if the working directory
+/// changes. The parent directory is canonicalized when it exists; otherwise the path is just
+/// joined onto the working directory. Empty paths are left empty.
+pub fn absolute_path(path: PathBuf) -> PathBuf {
+    if path.as_os_str().is_empty() {
+        return path;
     }
 
-    /// Get the text of a line from the buffer as a string.
-    pub fn get_line(&self, idx: usize) -> String {
-        self.text.line(idx).to_string()
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    match (parent.canonicalize(), path.file_name()) {
+        (Ok(dir), Some(name)) => dir.join(name),
+        _ => std::path::absolute(&path).unwrap_or(path),
+    }
+}
+
+/// The byte order mark some tools put at the start of UTF-8 files.
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Decodes the contents of a file, trying UTF-8 first, then UTF-16 LE or BE if there's a byte
+/// order mark for one, then falling back to UTF-8 with invalid sequences replaced. A UTF-8 byte
+/// order mark is left out of the text. Returns the text along with the encoding that was used.
+pub fn decode_file_bytes(bytes: &[u8]) -> (String, DetectedEncoding) {
+    if let Some(rest) = bytes.strip_prefix(UTF8_BOM)
+        && let Ok(s) = std::str::from_utf8(rest)
+    {
+        (s.to_string(), DetectedEncoding::Utf8WithBom)
+    } else if let Ok(s) = std::str::from_utf8(bytes) {
+        (s.to_string(), DetectedEncoding::Utf8)
+    } else if bytes.len() >= 2 && bytes[0] == 0xFF && bytes[1] == 0xFE {
+        // UTF-16 LE BOM detected
+        let (cow, _, _) = UTF_16LE.decode(&bytes[2..]); // skip BOM
+        (cow.into_owned(), DetectedEncoding::Utf16Le)
+    } else if bytes.len() >= 2 && bytes[0] == 0xFE && bytes[1] == 0xFF {
+        // UTF-16 BE BOM detected
+        let (cow, _, _) = UTF_16BE.decode(&bytes[2..]); // skip BOM
+        (cow.into_owned(), DetectedEncoding::Utf16Be)
+    } else {
+        // Fallback: replace invalid sequences
+        (
+            String::from_utf8_lossy(bytes).into_owned(),
+            DetectedEncoding::Lossy,
+        )
+    }
+}
+
+/// Returns the names of the entries of the directory `dir` for a directory listing: `../` first,
+/// then the subdirectories, then everything else. Directory names end in a slash, and each group
+/// is sorted by name.
+pub fn directory_entries(dir: &Path) -> std::io::Result<Vec<String>> {
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Follow symlinks, so links to directories can be browsed into.
+        if entry.path().is_dir() {
+            dirs.push(format!("{}/", name));
+        } else {
+            files.push(name);
+        }
+    }
+    dirs.sort();
+    files.sort();
+
+    let mut entries = vec![String::from("../")];
+    entries.extend(dirs);
+    entries.extend(files);
+    Ok(entries)
+}
+
+/// Returns true if `bytes` look like the contents of a binary file rather than text, judging by
+/// whether the first few KB contain a NUL byte. UTF-16 text is full of NULs, so anything starting
+/// with a UTF-16 byte order mark counts as text.
+pub fn looks_binary(bytes: &[u8]) -> bool {
+    const CHECK_LEN: usize = 8192;
+    if bytes.starts_with(&[0xFF, 0xFE]) || bytes.starts_with(&[0xFE, 0xFF]) {
+        return false;
+    }
+    bytes.iter().take(CHECK_LEN).any(|&b| b == 0)
+}
+
+/// Returns the path-like token in `line` that contains the char at `col`, or None if that char
+/// can't be part of a path.
+fn path_token_at(line: &str, col: usize) -> Option<&str> {
+    let is_delimiter = |c: char| c.is_whitespace() || "\"'`()[]{}<>,;".contains(c);
+
+    let chars: Vec<(usize, char)> = line.char_indices().collect();
+    let &(_, c) = chars.get(col)?;
+    if is_delimiter(c) {
+        return None;
+    }
+
+    let start = chars[..col]
+        .iter()
+        .rev()
+        .find(|&&(_, c)| is_delimiter(c))
+        .map_or(0, |&(i, c)| i + c.len_utf8());
+    let end = chars[col..]
+        .iter()
+        .find(|&&(_, c)| is_delimiter(c))
+        .map_or(line.len(), |&(i, _)| i);
+    Some(&line[start..end])
+}
+
+/// Parses the number at the start of `line`, ignoring leading whitespace.
+fn leading_number(line: &str) -> Option<f64> {
+    let line = line.trim_start();
+    let end = line
+        .char_indices()
+        .find(|&(i, c)| !(c.is_ascii_digit() || c == '.' || (i == 0 && c == '-')))
+        .map_or(line.len(), |(i, _)| i);
+    line[..end].parse().ok()
+}
+
+/// Returns the closing delimiter paired with `c`, if `c` opens a pair.
+fn closing_pair(c: char) -> Option<char> {
+    match c {
+        '(' => Some(')'),
+        '[' => Some(']'),
+        '{' => Some('}'),
+        '"' => Some('"'),
+        '\'' => Some('\''),
+        _ => None,
+    }
+}
+
+/// Returns where char index `idx` ends up once the chars in `range` have been removed.
+fn shift_for_delete(idx: usize, range: &Range<usize>) -> usize {
+    if idx >= range.end {
+        idx - range.len()
+    } else {
+        min(idx, range.start)
+    }
+}
+
+/// Returns where char index `idx` ends up once the line starting at `line_start` has been rewritten
+/// so that the char at column `i` moves to column `new_cols[i]`. The last entry of `new_cols` is
+/// the new length of the line.
+fn remap_column(idx: usize, line_start: usize, new_cols: &[usize]) -> usize {
+    let old_len = new_cols.len() - 1;
+    if idx < line_start {
+        idx
+    } else if idx <= line_start + old_len {
+        line_start + new_cols[idx - line_start]
+    } else {
+        idx - old_len + new_cols[old_len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer_with(text: &str) -> Buffer {
+        Buffer::from_str(text, BufferSettings::default())
+    }
+
+    fn press(buffer: &mut Buffer, code: KeyCode) {
+        buffer.handle_key_event(KeyEvent::new(code, KeyModifiers::NONE));
+    }
+
+    /// Creates an empty directory for a test's files, named after the test.
+    fn fixture_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("editor-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn typing_replaces_every_collected_occurrence() {
+        let mut buffer = buffer_with("let foo = foo + 1;\n");
+        buffer.select(4..7);
+        assert!(buffer.add_next_occurrence());
+        assert_eq!(buffer.occurrences, vec![4..7]);
+
+        for c in "bar".chars() {
+            press(&mut buffer, KeyCode::Char(c));
+        }
+        assert_eq!(buffer.text(), "let bar = bar + 1;\n");
+        assert_eq!(buffer.cursor_idx(), 13);
+
+        press(&mut buffer, KeyCode::Backspace);
+        assert_eq!(buffer.text(), "let ba = ba + 1;\n");
+
+        // A key that doesn't edit ends the multi-occurrence edit.
+        press(&mut buffer, KeyCode::Left);
+        press(&mut buffer, KeyCode::Char('z'));
+        assert_eq!(buffer.text(), "let ba = bza + 1;\n");
+    }
+
+    #[test]
+    fn edits_elsewhere_forget_the_occurrences() {
+        let mut buffer = buffer_with("ab ab ab\n");
+        buffer.select(0..2);
+        assert!(buffer.add_next_occurrence());
+        buffer.open_line(false);
+        assert!(buffer.occurrences.is_empty());
+        press(&mut buffer, KeyCode::Char('X'));
+        assert_eq!(buffer.text(), "X\nab ab ab\n");
+    }
+
+    #[test]
+    fn formatter_output_replaces_the_text() {
+        let mut buffer = buffer_with("abc\n");
+        assert_eq!(buffer.run_formatter("tr a-z A-Z"), Ok(true));
+        assert_eq!(buffer.text(), "ABC\n");
+        assert_eq!(buffer.run_formatter("cat"), Ok(false));
+    }
+
+    #[test]
+    fn slow_formatters_are_stopped() {
+        let mut buffer = buffer_with("abc\n");
+        let started = Instant::now();
+        let result = buffer.run_formatter_with_timeout("exec sleep 10", Duration::from_millis(100));
+        assert!(result.unwrap_err().contains("was stopped"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(buffer.text(), "abc\n");
+    }
+
+    #[test]
+    fn line_endings_are_detected_by_majority() {
+        assert_eq!(LineEnding::detect(""), (LineEnding::Lf, false));
+        assert_eq!(LineEnding::detect("a\r\nb\r\n"), (LineEnding::Crlf, false));
+        assert_eq!(
+            LineEnding::detect("a\nb\r\nc\r\n"),
+            (LineEnding::Crlf, true)
+        );
+        assert_eq!(LineEnding::detect("a\r\nb\nc\n"), (LineEnding::Lf, true));
+
+        let buffer = buffer_with("a\nb\r\nc\r\n");
+        assert_eq!(buffer.line_ending, LineEnding::Crlf);
+        assert!(buffer.mixed_line_endings);
+        assert_eq!(buffer.text(), "a\nb\nc\n");
+    }
+
+    #[test]
+    fn utf16_files_are_decoded_by_their_byte_order_mark() {
+        let le = [0xFF, 0xFE, b'h', 0, b'i', 0];
+        assert_eq!(
+            decode_file_bytes(&le),
+            (String::from("hi"), DetectedEncoding::Utf16Le)
+        );
+        let be = [0xFE, 0xFF, 0, b'h', 0, b'i'];
+        assert_eq!(
+            decode_file_bytes(&be),
+            (String::from("hi"), DetectedEncoding::Utf16Be)
+        );
+    }
+
+    #[test]
+    fn utf8_files_are_decoded_with_or_without_a_byte_order_mark() {
+        assert_eq!(
+            decode_file_bytes("héllo".as_bytes()),
+            (String::from("héllo"), DetectedEncoding::Utf8)
+        );
+        assert_eq!(
+            decode_file_bytes(b"\xEF\xBB\xBFhi"),
+            (String::from("hi"), DetectedEncoding::Utf8WithBom)
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_is_decoded_lossily() {
+        assert_eq!(
+            decode_file_bytes(b"a\xFFb"),
+            (String::from("a\u{FFFD}b"), DetectedEncoding::Lossy)
+        );
+    }
+
+    #[test]
+    fn files_are_saved_in_the_encoding_they_were_loaded_in() {
+        let dir = fixture_dir("encoding-round-trip");
+        let path = dir.join("utf16.txt");
+        let original = [0xFF, 0xFE, b'h', 0, b'i', 0, b'\r', 0, b'\n', 0];
+        fs::write(&path, original).unwrap();
+
+        let mut buffer =
+            Buffer::from_path(path.clone(), BufferSettings::default(), &HashMap::new()).unwrap();
+        assert_eq!(buffer.encoding, DetectedEncoding::Utf16Le);
+        assert_eq!(buffer.text(), "hi\n");
+        buffer.insert_text(2, "!");
+        buffer.save_file().unwrap();
+        let saved = fs::read(&path).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        let expected = [0xFF, 0xFE, b'h', 0, b'i', 0, b'!', 0, b'\r', 0, b'\n', 0];
+        assert_eq!(saved, expected);
+    }
+
+    #[test]
+    fn saving_unchanged_text_leaves_the_file_alone() {
+        let dir = fixture_dir("no-op-save");
+        let path = dir.join("utf16.txt");
+        let original = [0xFE, 0xFF, 0, b'o', 0, b'k', 0, b'\n'];
+        fs::write(&path, original).unwrap();
+
+        let mut buffer =
+            Buffer::from_path(path.clone(), BufferSettings::default(), &HashMap::new()).unwrap();
+        press(&mut buffer, KeyCode::Char('x'));
+        press(&mut buffer, KeyCode::Backspace);
+        assert!(buffer.dirty_buffer);
+        assert!(!buffer.save_file().unwrap());
+        assert!(!buffer.dirty_buffer);
+        assert_eq!(fs::read(&path).unwrap(), original);
+
+        // The same text in another encoding is a change.
+        buffer.encoding = DetectedEncoding::Utf8;
+        assert!(buffer.save_file().unwrap());
+        let saved = fs::read(&path).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(saved, b"ok\n");
+    }
+
+    #[cfg(feature = "spell")]
+    #[test]
+    fn spelling_is_checked_in_whole_words_around_the_columns() {
+        let dir = fixture_dir("spell-window");
+        fs::write(dir.join("words"), "one\ntwo\n").unwrap();
+        let dictionary = Dictionary::load(&dir.join("words")).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut buffer = buffer_with("one twoo one thre\n");
+        buffer.file_path = PathBuf::from("notes.txt");
+        assert_eq!(buffer.misspelled_ranges(0, 5..6, &dictionary), vec![4..8]);
+        assert_eq!(
+            buffer.misspelled_ranges(0, 9..100, &dictionary),
+            vec![13..17]
+        );
+    }
+
+    #[test]
+    fn vertical_moves_keep_the_goal_display_column() {
+        let mut buffer = buffer_with("\tx\nab\nabcdefg\n日本語x\n");
+        buffer.cursor_idx = 1;
+        buffer.move_down();
+        assert_eq!(buffer.get_logical_cursor_pos(), (1, 2));
+        buffer.move_down();
+        assert_eq!(buffer.get_logical_cursor_pos(), (2, 4));
+        buffer.move_up();
+        buffer.move_up();
+        assert_eq!(buffer.get_logical_cursor_pos(), (0, 1));
+
+        // Wide characters take up two columns each.
+        buffer.go_to_position(3, 3);
+        buffer.move_up();
+        assert_eq!(buffer.get_logical_cursor_pos(), (2, 6));
+
+        // Moving sideways sets a new goal.
+        buffer.move_left();
+        buffer.move_down();
+        assert_eq!(buffer.get_logical_cursor_pos(), (3, 3));
+    }
+
+    #[test]
+    fn enter_between_brackets_opens_an_indented_line() {
+        let mut buffer = buffer_with("    fn main() {}\n");
+        buffer.settings.auto_indent = true;
+        buffer.settings.indent_with_spaces = true;
+        buffer.cursor_idx = 15;
+        press(&mut buffer, KeyCode::Enter);
+        assert_eq!(buffer.text(), "    fn main() {\n        \n    }\n");
+        assert_eq!(buffer.get_logical_cursor_pos(), (1, 8));
+    }
+
+    #[test]
+    fn enter_keeps_the_indentation_before_the_cursor() {
+        let mut buffer = buffer_with("\tlet x = 1;\n");
+        buffer.settings.auto_indent = true;
+        buffer.cursor_idx = 4;
+        press(&mut buffer, KeyCode::Enter);
+        assert_eq!(buffer.text(), "\tlet\n\t x = 1;\n");
+        assert_eq!(buffer.get_logical_cursor_pos(), (1, 1));
+
+        // Without auto-indent, Enter only splits the line.
+        let mut buffer = buffer_with("    {}\n");
+        buffer.cursor_idx = 5;
+        press(&mut buffer, KeyCode::Enter);
+        assert_eq!(buffer.text(), "    {\n}\n");
+    }
+
+    #[test]
+    fn directory_entries_list_the_parent_then_directories_then_files() {
+        let dir = fixture_dir("directory-entries");
+        for name in ["b", "a"] {
+            fs::create_dir(dir.join(name)).unwrap();
+        }
+        for name in ["z.txt", "c.txt"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let entries = directory_entries(&dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(entries, ["../", "a/", "b/", "c.txt", "z.txt"]);
+    }
+
+    #[test]
+    fn retab_pads_to_the_next_tab_stop() {
+        let mut buffer = buffer_with("\tone\n  \ttwo\n\tx\ty\n");
+        buffer.settings.indent_size = 4;
+        buffer.settings.tab_display_width = 8;
+        buffer.retab(0..3, true);
+        assert_eq!(buffer.text(), "    one\n    two\n    x   y\n");
+    }
+
+    #[test]
+    fn retab_indents_by_indent_size_and_aligns_by_tab_width() {
+        let mut buffer = buffer_with("\t\tab\tc\n");
+        buffer.settings.indent_size = 2;
+        buffer.settings.tab_display_width = 8;
+        buffer.cursor_idx = 5;
+        buffer.retab(0..1, false);
+        assert_eq!(buffer.text(), "    ab\tc\n");
+        assert_eq!(buffer.cursor_idx(), 7);
+
+        buffer.retab(0..1, true);
+        assert_eq!(buffer.text(), "    ab  c\n");
+        assert_eq!(buffer.cursor_idx(), 8);
+    }
+
+    #[test]
+    fn retab_on_load_leaves_the_buffer_clean() {
+        let settings = BufferSettings {
+            retab_on_load: true,
+            ..BufferSettings::default()
+        };
+        let mut buffer = Buffer::from_str("\tindented\n", settings);
+        assert!(buffer.text().starts_with(' '));
+        assert!(!buffer.dirty_buffer);
+        assert!(buffer.undo_stack.is_empty());
+        assert!(buffer.line_changes().iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn reflow_keeps_hanging_indents() {
+        let mut buffer = buffer_with("- one two three\n  four five six\n");
+        buffer.reflow(0..2, 12);
+        assert_eq!(buffer.text(), "- one two\n  three four\n  five six\n");
+    }
+
+    #[test]
+    fn line_changes_follow_edits_and_saves() {
+        let mut buffer = buffer_with("one\ntwo\n");
+        assert!(buffer.line_changes().iter().all(Option::is_none));
+
+        buffer.insert_text(0, "new\n");
+        assert_eq!(buffer.line_changes()[0], Some(LineChange::Added));
+
+        buffer.undo();
+        assert!(buffer.line_changes().iter().all(Option::is_none));
+
+        buffer.insert_text(0, "x");
+        assert_eq!(buffer.line_changes()[0], Some(LineChange::Modified));
+        let path = std::env::temp_dir().join(format!("editor-diff-{}", std::process::id()));
+        buffer.file_path = path.clone();
+        buffer.save_file().unwrap();
+        let _ = fs::remove_file(path);
+        assert!(buffer.line_changes().iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn adding_to_numbers() {
+        let mut buffer = buffer_with("x = 9;\n");
+        assert_eq!(buffer.add_to_number(1), Ok(()));
+        assert_eq!(buffer.text(), "x = 10;\n");
+        assert_eq!(buffer.cursor_idx(), 5);
+
+        let mut buffer = buffer_with("-1 and 007\n");
+        assert_eq!(buffer.add_to_number(1), Ok(()));
+        assert_eq!(buffer.text(), "0 and 007\n");
+        buffer.cursor_idx = 2;
+        assert_eq!(buffer.add_to_number(i64::MAX), Ok(()));
+        assert_eq!(buffer.text(), "0 and 9223372036854775807\n");
+
+        let mut buffer = buffer_with("no number here\n");
+        assert!(buffer.add_to_number(1).is_err());
+        assert_eq!(buffer.text(), "no number here\n");
+
+        let mut buffer = buffer_with("99999999999999999999\n");
+        assert_eq!(
+            buffer.add_to_number(1),
+            Err(String::from("Number out of range."))
+        );
+    }
+
+    #[test]
+    fn changing_line_endings_can_be_undone() {
+        let mut buffer = buffer_with("a\r\nb\r\n");
+        assert!(buffer.set_line_ending(LineEnding::Lf));
+        assert!(buffer.dirty_buffer);
+        assert!(!buffer.set_line_ending(LineEnding::Lf));
+
+        assert!(buffer.undo());
+        assert_eq!(buffer.line_ending, LineEnding::Crlf);
+        assert!(!buffer.dirty_buffer);
+
+        assert!(buffer.redo());
+        assert_eq!(buffer.line_ending, LineEnding::Lf);
+        assert!(buffer.dirty_buffer);
+    }
+
+    #[test]
+    fn filetype_overrides_apply_by_extension() {
+        let dir = fixture_dir("filetype-overrides");
+        fs::write(dir.join("script.py"), "pass\n").unwrap();
+        fs::write(dir.join("main.go"), "package main\n").unwrap();
+        let mut config = crate::editor::config::Config::default();
+        let errors = config.read(
+            "indent_size = 2\n\
+             [py]\n\
+             indent_size = 4\n\
+             indent_with_spaces = true\n\
+             [go]\n\
+             indent_with_spaces = false\n\
+             tab_display_width = 8\n\
+             insert_final_newline = true\n",
+        );
+        assert!(errors.is_empty());
+
+        let open = |name: &str| {
+            let settings = config.buffer_settings.clone();
+            Buffer::from_path(dir.join(name), settings, &config.filetype_overrides).unwrap()
+        };
+        let python = open("script.py");
+        let go = open("main.go");
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(python.settings.indent_size, 4);
+        assert!(python.settings.indent_with_spaces);
+        assert!(!python.settings.insert_final_newline);
+        assert_eq!(go.settings.indent_size, 2);
+        assert!(!go.settings.indent_with_spaces);
+        assert_eq!(go.settings.tab_display_width, 8);
+        assert!(go.settings.insert_final_newline);
+    }
+
+    #[test]
+    fn moving_the_cursor_separates_undo_steps() {
+        let mut buffer = buffer_with("");
+        for c in "hello".chars() {
+            press(&mut buffer, KeyCode::Char(c));
+        }
+        press(&mut buffer, KeyCode::Left);
+        press(&mut buffer, KeyCode::Char('x'));
+        assert_eq!(buffer.text(), "hellxo");
+
+        assert!(buffer.undo());
+        assert_eq!(buffer.text(), "hello");
+        assert!(buffer.undo());
+        assert_eq!(buffer.text(), "");
+        assert!(!buffer.undo());
+    }
+
+    #[test]
+    fn saving_separates_undo_steps() {
+        let dir = fixture_dir("undo-save");
+        let mut buffer = buffer_with("");
+        buffer.file_path = dir.join("file.txt");
+        press(&mut buffer, KeyCode::Char('a'));
+        buffer.save_file().unwrap();
+        press(&mut buffer, KeyCode::Char('b'));
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(buffer.undo());
+        assert_eq!(buffer.text(), "a");
+        assert!(!buffer.dirty_buffer);
+    }
+
+    #[test]
+    fn editorconfig_sets_the_buffer_settings() {
+        let dir = fixture_dir("editorconfig-buffer");
+        fs::write(
+            dir.join(".editorconfig"),
+            "root = true\n\
+             [*]\n\
+             indent_style = tab\n\
+             tab_width = 8\n\
+             end_of_line = crlf\n",
+        )
+        .unwrap();
+        fs::write(dir.join("main.c"), "int main;\n").unwrap();
+        let overrides = HashMap::from([(
+            String::from("c"),
+            FiletypeOverrides {
+                indent_with_spaces: Some(true),
+                indent_size: Some(2),
+                ..FiletypeOverrides::default()
+            },
+        )]);
+        let buffer =
+            Buffer::from_path(dir.join("main.c"), BufferSettings::default(), &overrides).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        // The project's settings win over the filetype's.
+        assert!(!buffer.settings.indent_with_spaces);
+        assert_eq!(buffer.settings.indent_size, 8);
+        assert_eq!(buffer.settings.tab_display_width, 8);
+        assert_eq!(buffer.line_ending, LineEnding::Crlf);
+        assert!(!buffer.dirty_buffer);
+    }
+
+    #[test]
+    fn edits_that_shrink_the_text_never_leave_the_anchor_past_the_end() {
+        // Joining doesn't know where the anchor goes, so the selection is cleared.
+        let mut buffer = buffer_with("one\n    two\n");
+        buffer.selection_anchor = Some(buffer.len_chars());
+        buffer.cursor_idx = 0;
+        buffer.join_lines(0..2);
+        assert_eq!(buffer.text(), "one two\n");
+        assert_eq!(buffer.selection_anchor, None);
+
+        // Deleting moves the anchor along with its text instead.
+        let mut buffer = buffer_with("a   \nb\n");
+        buffer.selection_anchor = Some(buffer.len_chars());
+        buffer.cursor_idx = 0;
+        buffer.trim_lines(0..2);
+        assert_eq!(buffer.text(), "a\nb\n");
+        assert_eq!(buffer.selection_anchor, Some(buffer.len_chars()));
+        assert_eq!(buffer.selection_range(), Some(0..4));
+    }
+
+    #[test]
+    fn files_with_a_nul_byte_look_binary() {
+        assert!(looks_binary(b"\x7fELF\x02\x01\x01\x00\x00"));
+        assert!(!looks_binary(b"plain text\n"));
+        assert!(!looks_binary(b""));
+        // UTF-16 text is full of NULs, but starts with a byte order mark.
+        assert!(!looks_binary(b"\xFF\xFEh\x00i\x00"));
+    }
+
+    #[test]
+    fn binary_files_are_refused_unless_allowed() {
+        let dir = fixture_dir("binary");
+        let path = dir.join("data.bin");
+        fs::write(&path, b"abc\x00def").unwrap();
+
+        let refused = Buffer::from_path(path.clone(), BufferSettings::default(), &HashMap::new());
+        let settings = BufferSettings {
+            open_binary_files: true,
+            ..BufferSettings::default()
+        };
+        let opened = Buffer::from_path(path, settings, &HashMap::new()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(BinaryFileError::from_io_error(&refused.err().unwrap()).is_some());
+        assert!(opened.is_binary);
+        assert!(opened.get_status_bar_text().0.contains("[binary]"));
+    }
+
+    #[test]
+    fn shift_home_and_end_select_to_the_ends_of_the_line() {
+        let shift = |code| KeyEvent::new(code, KeyModifiers::SHIFT);
+        let mut buffer = buffer_with("first\nhello world\nlast\n");
+        buffer.cursor_idx = 12;
+        buffer.handle_key_event(shift(KeyCode::End));
+        assert_eq!(buffer.selection_range(), Some(12..17));
+
+        // The anchor stays put, so going back past it selects the other side.
+        buffer.handle_key_event(shift(KeyCode::Home));
+        assert_eq!(buffer.selection_range(), Some(6..12));
+        assert_eq!(buffer.cursor_idx(), 6);
+
+        press(&mut buffer, KeyCode::End);
+        assert_eq!(buffer.selection_range(), None);
+        assert_eq!(buffer.cursor_idx(), 17);
+    }
+
+    #[test]
+    fn find_char_moves_onto_or_next_to_a_char_on_the_line() {
+        let search = |target, forward, till| CharSearch {
+            target,
+            forward,
+            till,
+        };
+        let mut buffer = buffer_with("a,b,c,d\nx,y\n");
+        assert!(buffer.find_char_on_line(search('c', true, false), false));
+        assert_eq!(buffer.cursor_idx(), 4);
+        assert!(buffer.find_char_on_line(search('a', false, false), false));
+        assert_eq!(buffer.cursor_idx(), 0);
+        assert!(buffer.find_char_on_line(search('c', true, true), false));
+        assert_eq!(buffer.cursor_idx(), 3);
+
+        buffer.cursor_idx = 6;
+        assert!(buffer.find_char_on_line(search('b', false, true), false));
+        assert_eq!(buffer.cursor_idx(), 3);
+
+        // Repeating a till search passes over the char it stopped next to.
+        buffer.cursor_idx = 0;
+        assert!(buffer.find_char_on_line(search(',', true, true), false));
+        assert_eq!(buffer.cursor_idx(), 0);
+        assert!(buffer.find_char_on_line(search(',', true, true), true));
+        assert_eq!(buffer.cursor_idx(), 2);
+
+        // Searches stay on the cursor's line.
+        buffer.cursor_idx = 6;
+        assert!(!buffer.find_char_on_line(search('x', true, false), false));
+        assert!(!buffer.find_char_on_line(search('y', false, false), false));
+        assert_eq!(buffer.cursor_idx(), 6);
+    }
+
+    #[test]
+    fn files_over_the_size_threshold_are_not_loaded() {
+        let dir = fixture_dir("large-file");
+        let large = dir.join("large.txt");
+        // A sparse file, so nothing is actually written.
+        fs::File::create(&large)
+            .unwrap()
+            .set_len(120 * 1024 * 1024)
+            .unwrap();
+        let small = dir.join("small.txt");
+        fs::write(&small, "0123456789").unwrap();
+
+        let refused = Buffer::from_path(large, BufferSettings::default(), &HashMap::new());
+        let at_threshold = |threshold| {
+            let settings = BufferSettings {
+                large_file_threshold: threshold,
+                ..BufferSettings::default()
+            };
+            Buffer::from_path(small.clone(), settings, &HashMap::new())
+        };
+        let over = at_threshold(Some(9));
+        let exact = at_threshold(Some(10));
+        let unlimited = at_threshold(None);
+        let _ = fs::remove_dir_all(&dir);
+
+        let err = refused.err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::FileTooLarge);
+        let large = LargeFileError::from_io_error(&err).unwrap();
+        assert_eq!(large.readable_size(), "120MB");
+        assert_eq!(
+            LargeFileError::from_io_error(&over.err().unwrap())
+                .unwrap()
+                .0,
+            10
+        );
+        assert_eq!(exact.unwrap().text(), "0123456789");
+        assert_eq!(unlimited.unwrap().text(), "0123456789");
+    }
+
+    #[test]
+    fn set_text_round_trips_through_text() {
+        let mut buffer = buffer_with("old\n");
+        buffer.set_text("one\r\ntwo\r\n");
+        assert_eq!(buffer.text(), "one\ntwo\n");
+        assert_eq!(buffer.line_ending, LineEnding::Crlf);
+        assert!(buffer.dirty_buffer);
+
+        let text = buffer.text();
+        buffer.set_text(&text);
+        assert_eq!(buffer.text(), text);
+
+        // The whole replacement is one undo step.
+        buffer.undo();
+        assert_eq!(buffer.text(), "one\ntwo\n");
+        buffer.undo();
+        assert_eq!(buffer.text(), "old\n");
+    }
+
+    #[test]
+    fn set_text_pulls_the_cursor_back_into_shorter_text() {
+        let mut buffer = buffer_with("a long line of text\n");
+        buffer.cursor_idx = 15;
+        buffer.set_text("short\n");
+        assert_eq!(buffer.cursor_idx(), 6);
+
+        buffer.cursor_idx = 3;
+        buffer.set_text("longer again\n");
+        assert_eq!(buffer.cursor_idx(), 3);
+    }
+
+    #[test]
+    fn paragraph_motions_stop_at_blank_lines() {
+        // Paragraphs separated by one and then two blank lines.
+        let mut buffer = buffer_with("a\nb\n\nc\n\n\nd\ne\n");
+        let mut lines = Vec::new();
+        for _ in 0..3 {
+            buffer.next_paragraph();
+            lines.push(buffer.get_logical_cursor_line());
+        }
+        assert_eq!(lines, [2, 4, 8]);
+        assert_eq!(buffer.cursor_idx(), buffer.len_chars());
+
+        lines.clear();
+        for _ in 0..3 {
+            buffer.prev_paragraph();
+            lines.push(buffer.get_logical_cursor_line());
+        }
+        assert_eq!(lines, [5, 2, 0]);
+        assert_eq!(buffer.cursor_idx(), 0);
+    }
+
+    #[test]
+    fn sorting_lines_in_each_order() {
+        let text = "10 ten\n9 nine\n-1 minus\nword\n";
+        let sorted = |order| {
+            let mut buffer = buffer_with(text);
+            buffer.sort_lines(0..4, order);
+            buffer.text()
+        };
+        assert_eq!(
+            sorted(SortOrder::Ascending),
+            "-1 minus\n10 ten\n9 nine\nword\n"
+        );
+        assert_eq!(
+            sorted(SortOrder::Descending),
+            "word\n9 nine\n10 ten\n-1 minus\n"
+        );
+        // Lines without a number come first.
+        assert_eq!(
+            sorted(SortOrder::Numeric),
+            "word\n-1 minus\n9 nine\n10 ten\n"
+        );
+    }
+
+    #[test]
+    fn sorting_never_merges_the_last_line() {
+        let mut buffer = buffer_with("c\nb\na");
+        buffer.cursor_idx = 5;
+        buffer.sort_lines(0..3, SortOrder::Ascending);
+        assert_eq!(buffer.text(), "a\nb\nc");
+        assert_eq!(buffer.cursor_idx(), 5);
+
+        // Sorting part of the buffer leaves the lines around it alone.
+        let mut buffer = buffer_with("z\nc\nb\na\n");
+        buffer.sort_lines(1..3, SortOrder::Ascending);
+        assert_eq!(buffer.text(), "z\nb\nc\na\n");
+    }
+
+    #[test]
+    fn trimming_only_touches_the_given_lines() {
+        let mut buffer = buffer_with("a  \nb\t\nc  \n");
+        buffer.cursor_idx = 6;
+        buffer.trim_lines(0..2);
+        assert_eq!(buffer.text(), "a\nb\nc  \n");
+        assert_eq!(buffer.cursor_idx(), 3);
+    }
+
+    #[test]
+    fn joining_two_lines_leaves_the_cursor_at_the_join() {
+        let mut buffer = buffer_with("fn main() {\n    body();\n}\n");
+        buffer.join_lines(0..2);
+        assert_eq!(buffer.text(), "fn main() { body();\n}\n");
+        assert_eq!(buffer.cursor_idx(), 11);
+    }
+
+    #[test]
+    fn joining_a_selection_joins_every_line_in_it() {
+        let mut buffer = buffer_with("one\n  two\n\nthree\nfour\n");
+        buffer.select(0..12);
+        buffer.join_lines(buffer.selected_lines());
+        // No space is added after the empty line.
+        assert_eq!(buffer.text(), "one two three\nfour\n");
+        assert_eq!(buffer.cursor_idx(), 3);
+        assert_eq!(buffer.selection_range(), None);
+
+        // There's nothing after the last line to join it to.
+        let mut buffer = buffer_with("one\ntwo\n");
+        buffer.join_lines(1..2);
+        buffer.join_lines(1..3);
+        assert_eq!(buffer.text(), "one\ntwo\n");
+    }
+
+    #[test]
+    fn tabs_are_drawn_at_tab_width_but_indent_by_indent_size() {
+        let settings = BufferSettings {
+            tab_display_width: 8,
+            indent_size: 2,
+            indent_with_spaces: true,
+            ..BufferSettings::default()
+        };
+        let mut buffer = Buffer::from_str("\tx\n", settings);
+        buffer.cursor_idx = 1;
+        assert_eq!(buffer.get_visual_cursor_col(), 8);
+
+        buffer.cursor_idx = 3;
+        press(&mut buffer, KeyCode::Tab);
+        press(&mut buffer, KeyCode::Tab);
+        assert_eq!(buffer.text(), "\tx\n    ");
+
+        // Backspace in the indentation takes off one level.
+        press(&mut buffer, KeyCode::Backspace);
+        assert_eq!(buffer.text(), "\tx\n  ");
+        press(&mut buffer, KeyCode::BackTab);
+        assert_eq!(buffer.text(), "\tx\n");
+    }
+
+    #[test]
+    fn down_stops_on_the_last_line_with_content() {
+        for text in ["one\ntwo\n", "one\ntwo"] {
+            let mut buffer = buffer_with(text);
+            assert_eq!(buffer.content_lines(), 2, "{:?}", text);
+            press(&mut buffer, KeyCode::Down);
+            assert_eq!(buffer.cursor_idx(), 4, "{:?}", text);
+
+            // Down again goes to the end of the last line, not onto the empty line after it.
+            press(&mut buffer, KeyCode::Down);
+            press(&mut buffer, KeyCode::Down);
+            assert_eq!(buffer.cursor_idx(), 7, "{:?}", text);
+            assert_eq!(buffer.get_logical_cursor_line(), 1, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn path_tokens_stop_at_quotes_brackets_and_spaces() {
+        let line = r#"#include "lib/util.h" (see docs/notes.md) and 'x.rs'"#;
+        let token_at = |text: &str| path_token_at(line, line.find(text).unwrap());
+        assert_eq!(token_at("util"), Some("lib/util.h"));
+        assert_eq!(token_at("notes"), Some("docs/notes.md"));
+        assert_eq!(token_at("x.rs"), Some("x.rs"));
+        assert_eq!(token_at("#include"), Some("#include"));
+        assert_eq!(token_at("\"lib"), None);
+        assert_eq!(token_at(" and"), None);
+        assert_eq!(path_token_at(line, line.len()), None);
+    }
+
+    #[test]
+    fn chars_past_the_line_length_limit_are_found_by_display_column() {
+        let mut buffer = buffer_with("\tabcdefgh\n日本語x\nshort\n");
+        buffer.settings.tab_display_width = 4;
+        // The tab covers columns 0 to 3, so `e` (char 5) is the first at column 8.
+        assert_eq!(buffer.first_char_past_column(0, 8), Some(5));
+        buffer.settings.tab_display_width = 8;
+        assert_eq!(buffer.first_char_past_column(0, 8), Some(1));
+        assert_eq!(buffer.first_char_past_column(1, 4), Some(2));
+        assert_eq!(buffer.first_char_past_column(2, 5), None);
+        assert_eq!(buffer.first_char_past_column(2, 4), Some(4));
+    }
+
+    #[test]
+    fn matching_indent_skips_deeper_and_blank_lines() {
+        let text = "fn a() {\n    if x {\n        y();\n\n    }\n    z();\n}\n";
+        let mut buffer = buffer_with(text);
+        let line_of = |buffer: &Buffer| buffer.get_logical_cursor_line();
+
+        buffer.go_to_position(1, 0);
+        assert!(buffer.go_to_matching_indent(true));
+        assert_eq!(line_of(&buffer), 4);
+        assert_eq!(buffer.get_logical_cursor_col(), 4);
+        assert!(buffer.go_to_matching_indent(false));
+        assert_eq!(line_of(&buffer), 1);
+
+        buffer.go_to_position(0, 0);
+        assert!(buffer.go_to_matching_indent(true));
+        assert_eq!(line_of(&buffer), 6);
+
+        // Nothing at the ends, and nothing to match from a blank line.
+        assert!(!buffer.go_to_matching_indent(true));
+        buffer.go_to_position(0, 3);
+        assert!(!buffer.go_to_matching_indent(false));
+        assert_eq!(buffer.cursor_idx(), 3);
+        buffer.go_to_position(3, 0);
+        assert!(!buffer.go_to_matching_indent(true));
+    }
+
+    #[test]
+    fn inserting_a_file_decodes_it_and_moves_past_it() {
+        let dir = fixture_dir("insert-file");
+        let path = dir.join("part.txt");
+        // UTF-16 with a byte order mark and CRLF line endings.
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend("é\r\nf\r\n".encode_utf16().flat_map(u16::to_le_bytes));
+        fs::write(&path, bytes).unwrap();
+
+        let mut buffer = buffer_with("ab\ncd\n");
+        buffer.cursor_idx = 3;
+        buffer.insert_file(&path).unwrap();
+        let missing = buffer.insert_file(&dir.join("missing.txt"));
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(buffer.text(), "ab\né\nf\ncd\n");
+        assert_eq!(buffer.cursor_idx(), 7);
+        assert!(buffer.dirty_buffer);
+        assert!(missing.is_err());
+        assert_eq!(buffer.text(), "ab\né\nf\ncd\n");
+    }
+
+    #[test]
+    fn relative_paths_are_made_absolute_when_loaded() {
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(
+            absolute_path(PathBuf::from("src/main.rs")),
+            cwd.join("src").canonicalize().unwrap().join("main.rs")
+        );
+        assert_eq!(
+            absolute_path(PathBuf::from("no/such/file.txt")),
+            cwd.join("no/such/file.txt")
+        );
+        assert_eq!(absolute_path(PathBuf::new()), PathBuf::new());
+    }
+
+    #[test]
+    fn case_changes_can_change_the_length() {
+        let mut buffer = buffer_with("die Straße hier\n");
+        buffer.select(4..10);
+        buffer.transform_case(4..10, CaseOp::Upper);
+        assert_eq!(buffer.text(), "die STRASSE hier\n");
+        // The selection grows to cover the longer text.
+        assert_eq!(buffer.selection_range(), Some(4..11));
+
+        buffer.transform_case(4..11, CaseOp::Lower);
+        assert_eq!(buffer.text(), "die strasse hier\n");
+        assert_eq!(buffer.selection_range(), Some(4..11));
+
+        // Without a selection, the cursor stays inside the changed text.
+        let mut buffer = buffer_with("Hello ẞig\n");
+        buffer.cursor_idx = 8;
+        buffer.transform_case(0..9, CaseOp::Toggle);
+        assert_eq!(buffer.text(), "hELLO ßIG\n");
+        assert_eq!(buffer.cursor_idx(), 8);
+        assert_eq!(buffer.selection_range(), None);
+    }
+
+    #[test]
+    fn transposing_swaps_the_chars_around_the_cursor() {
+        let mut buffer = buffer_with("héllo\nab\n");
+        buffer.cursor_idx = 2;
+        buffer.transpose_chars();
+        assert_eq!(buffer.text(), "hlélo\nab\n");
+        assert_eq!(buffer.cursor_idx(), 3);
+
+        // At the end of a line, the last two chars are swapped.
+        buffer.cursor_idx = 5;
+        buffer.transpose_chars();
+        assert_eq!(buffer.text(), "hléol\nab\n");
+        assert_eq!(buffer.cursor_idx(), 5);
+
+        // Before the final char, it moves to the end of the line.
+        buffer.cursor_idx = 7;
+        buffer.transpose_chars();
+        assert_eq!(buffer.text(), "hléol\nba\n");
+        assert_eq!(buffer.cursor_idx(), 8);
+
+        // Nothing happens at the start of a line.
+        buffer.cursor_idx = 6;
+        buffer.transpose_chars();
+        assert_eq!(buffer.text(), "hléol\nba\n");
+        assert_eq!(buffer.cursor_idx(), 6);
+    }
+
+    #[test]
+    fn opening_lines_keeps_the_indentation_wherever_the_cursor_is() {
+        let mut buffer = buffer_with("fn a() {\n    b();\n}\n");
+        buffer.settings.auto_indent = true;
+        buffer.cursor_idx = 15;
+        buffer.open_line(true);
+        assert_eq!(buffer.text(), "fn a() {\n    b();\n    \n}\n");
+        assert_eq!(buffer.get_logical_cursor_pos(), (2, 4));
+
+        buffer.cursor_idx = 11;
+        buffer.open_line(false);
+        assert_eq!(buffer.text(), "fn a() {\n    \n    b();\n    \n}\n");
+        assert_eq!(buffer.get_logical_cursor_pos(), (1, 4));
+
+        // Without auto-indent, the new line is empty.
+        buffer.settings.auto_indent = false;
+        buffer.cursor_idx = 20;
+        buffer.open_line(true);
+        assert_eq!(buffer.text(), "fn a() {\n    \n    b();\n\n    \n}\n");
+        assert_eq!(buffer.get_logical_cursor_pos(), (3, 0));
+    }
+
+    #[test]
+    fn utf8_byte_order_marks_are_kept_on_save() {
+        let dir = fixture_dir("utf8-bom");
+        let with_bom = dir.join("bom.txt");
+        let without_bom = dir.join("plain.txt");
+        fs::write(&with_bom, b"\xEF\xBB\xBFhi\n").unwrap();
+        fs::write(&without_bom, b"hi\n").unwrap();
+
+        let open = |path: &PathBuf, write_utf8_bom| {
+            let settings = BufferSettings {
+                write_utf8_bom,
+                ..BufferSettings::default()
+            };
+            Buffer::from_path(path.clone(), settings, &HashMap::new()).unwrap()
+        };
+        let mut buffer = open(&with_bom, false);
+        assert_eq!(buffer.char_at_cursor(), Some('h'));
+        assert_eq!(buffer.line_len(0), 2);
+        buffer.cursor_idx = 1;
+        assert_eq!(buffer.cursor_byte_offset(), 4);
+        buffer.insert_text(2, "!");
+        buffer.save_file().unwrap();
+
+        let mut buffer = open(&without_bom, true);
+        buffer.insert_text(2, "!");
+        buffer.save_file().unwrap();
+
+        let saved_with_bom = fs::read(&with_bom).unwrap();
+        let saved_without_bom = fs::read(&without_bom).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(saved_with_bom, b"\xEF\xBB\xBFhi!\n");
+        assert_eq!(saved_without_bom, b"\xEF\xBB\xBFhi!\n");
+    }
+
+    #[test]
+    fn fsync_on_save_writes_the_same_bytes() {
+        let dir = fixture_dir("fsync-save");
+        let mut written = Vec::new();
+        for fsync_on_save in [false, true] {
+            let mut buffer = Buffer::from_str(
+                "fn main() {}\r\nünïcode\n",
+                BufferSettings {
+                    fsync_on_save,
+                    ..BufferSettings::default()
+                },
+            );
+            buffer.file_path = dir.join(format!("file-{fsync_on_save}.txt"));
+            assert!(buffer.save_file().unwrap());
+            assert!(!buffer.dirty_buffer);
+            written.push(fs::read(&buffer.file_path).unwrap());
+        }
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(written[0], written[1]);
+    }
+
+    #[test]
+    fn opening_delimiters_wrap_the_selection() {
+        let mut buffer = buffer_with("call foo now\n");
+        buffer.select(5..8);
+        press(&mut buffer, KeyCode::Char('('));
+        assert_eq!(buffer.text(), "call (foo) now\n");
+        assert_eq!(buffer.selection_range(), Some(6..9));
+
+        press(&mut buffer, KeyCode::Char('"'));
+        assert_eq!(buffer.text(), "call (\"foo\") now\n");
+        assert_eq!(buffer.selection_range(), Some(7..10));
+
+        let mut buffer = buffer_with("one\ntwo\nthree\n");
+        buffer.select(2..9);
+        press(&mut buffer, KeyCode::Char('\''));
+        assert_eq!(buffer.text(), "on'e\ntwo\nt'hree\n");
+        assert_eq!(buffer.selection_range(), Some(3..10));
+
+        press(&mut buffer, KeyCode::Char('('));
+        assert_eq!(buffer.text(), "on'(e\ntwo\nt)'hree\n");
+        assert_eq!(buffer.selection_range(), Some(4..11));
+    }
+
+    #[test]
+    fn cleaning_tidies_blank_lines_and_whitespace() {
+        let text = "a  \n\n\n\nb\t\n\n\n\n";
+        let mut buffer = buffer_with(text);
+        buffer.cursor_idx = 6;
+        assert!(buffer.clean(CleanOptions::default()));
+        assert_eq!(buffer.text(), "a\n\nb\n");
+        assert_eq!(buffer.cursor_idx(), 3);
+        assert!(!buffer.clean(CleanOptions::default()));
+
+        let mut buffer = buffer_with(text);
+        buffer.cursor_idx = buffer.text.len_chars();
+        assert!(buffer.clean(CleanOptions {
+            collapse_blank_lines: true,
+            trim_trailing_whitespace: false,
+            single_final_newline: false,
+        }));
+        assert_eq!(buffer.text(), "a  \n\nb\t\n\n");
+        assert_eq!(buffer.cursor_idx(), buffer.text.len_chars());
+
+        let mut buffer = buffer_with(text);
+        assert!(buffer.clean(CleanOptions {
+            collapse_blank_lines: false,
+            trim_trailing_whitespace: true,
+            single_final_newline: false,
+        }));
+        assert_eq!(buffer.text(), "a\n\n\n\nb\n\n\n\n");
+
+        let mut buffer = buffer_with(text);
+        assert!(buffer.clean(CleanOptions {
+            collapse_blank_lines: false,
+            trim_trailing_whitespace: false,
+            single_final_newline: true,
+        }));
+        assert_eq!(buffer.text(), "a  \n\n\n\nb\t\n");
+    }
+
+    #[test]
+    fn status_bar_reports_the_selection_size_in_chars() {
+        let mut buffer = buffer_with("héllo\nwörld\nend\n");
+        assert!(!buffer.get_status_bar_text().1.contains("SEL"));
+
+        buffer.select(1..9);
+        let (_, right) = buffer.get_status_bar_text();
+        assert!(right.starts_with("SEL 8 chars, 2 lines | "), "{right}");
+
+        buffer.select(0..0);
+        assert!(!buffer.get_status_bar_text().1.contains("SEL"));
+    }
+
+    #[test]
+    fn spaces_are_inserted_up_to_the_next_tab_stop() {
+        let settings = BufferSettings {
+            tab_display_width: 4,
+            indent_with_spaces: false,
+            ..BufferSettings::default()
+        };
+        for (text, cursor_idx, expected) in [
+            ("x\n", 0, "    x\n"),
+            ("ab\n", 2, "ab  \n"),
+            ("abcde\n", 5, "abcde   \n"),
+            ("\tx\n", 1, "\t    x\n"),
+        ] {
+            let mut buffer = Buffer::from_str(text, settings.clone());
+            buffer.cursor_idx = cursor_idx;
+            buffer.insert_spaces_to_tab_stop();
+            assert_eq!(buffer.text(), expected);
+        }
+    }
+
+    #[test]
+    fn text_object_bounds() {
+        let mut buffer = buffer_with("foo  bar.baz\n\nsecond para\nline two\n\nend");
+        let mut bounds_at = |cursor_idx: usize| {
+            buffer.cursor_idx = cursor_idx;
+            (
+                buffer.word_bounds(),
+                buffer.line_bounds(),
+                buffer.paragraph_bounds(),
+            )
+        };
+
+        assert_eq!(bounds_at(1), (0..3, 0..13, 0..13));
+        assert_eq!(bounds_at(3).0, 3..5);
+        assert_eq!(bounds_at(8).0, 8..9);
+        assert_eq!(bounds_at(12).0, 9..12);
+        assert_eq!(bounds_at(13), (13..13, 13..14, 13..14));
+        assert_eq!(bounds_at(27), (26..30, 26..35, 14..35));
+        assert_eq!(bounds_at(39), (36..39, 36..39, 36..39));
     }
 }
@@ -0,0 +1,77 @@
+use std::{collections::BTreeSet, ops::Bound};
+
+/// A source of completions for the word being typed.
+pub trait Completer {
+    /// Returns the words that `prefix` could be completed to, not including `prefix` itself.
+    fn candidates(&self, prefix: &str) -> Vec<String>;
+}
+
+/// Completes words from the words already in a buffer. A word is a run of letters, digits and
+/// underscores.
+pub struct BufferWords {
+    words: BTreeSet<String>,
+}
+
+impl BufferWords {
+    pub fn new(text: &str) -> Self {
+        BufferWords {
+            words: text
+                .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .filter(|word| !word.is_empty())
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+impl Completer for BufferWords {
+    /// Returns the words in the buffer starting with `prefix`, in sorted order.
+    fn candidates(&self, prefix: &str) -> Vec<String> {
+        self.words
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|word| word.starts_with(prefix))
+            .filter(|word| word.as_str() != prefix)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Returns the longest string that all of `words` start with.
+pub fn common_prefix(words: &[String]) -> &str {
+    let Some((first, rest)) = words.split_first() else {
+        return "";
+    };
+    let mut prefix = first.as_str();
+    for word in rest {
+        let len = prefix
+            .char_indices()
+            .zip(word.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(prefix.len().min(word.len()), |((idx, _), _)| idx);
+        prefix = &prefix[..len];
+    }
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_words_are_collected_once_and_sorted() {
+        let words = BufferWords::new("let value = values[0] + value_2;\nvalue.len()");
+        assert_eq!(words.candidates("val"), ["value", "value_2", "values"]);
+        assert_eq!(words.candidates("value"), ["value_2", "values"]);
+        assert!(words.candidates("x").is_empty());
+    }
+
+    #[test]
+    fn common_prefix_of_candidates() {
+        let words = |list: &[&str]| list.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        assert_eq!(common_prefix(&words(&["value_2", "values"])), "value");
+        assert_eq!(common_prefix(&words(&["values", "val"])), "val");
+        assert_eq!(common_prefix(&words(&["café", "cafés"])), "café");
+        assert_eq!(common_prefix(&words(&["abc", "xyz"])), "");
+        assert_eq!(common_prefix(&[]), "");
+    }
+}
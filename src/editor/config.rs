@@ -0,0 +1,566 @@
+use crossterm::cursor::SetCursorStyle;
+use std::{collections::HashMap, env, fs, path::PathBuf};
+
+/// How line numbers are shown to the left of the text.
+#[derive(Clone, Copy, PartialEq)]
+pub enum LineNumbers {
+    Off,
+    /// Each line's own number.
+    Absolute,
+    /// Each line's distance from the cursor's line, so the cursor's line shows 0.
+    Relative,
+    /// Like `Relative`, but the cursor's line shows its own number.
+    Hybrid,
+}
+
+/// User-facing options that change how the editor behaves.
+pub struct Config {
+    /// Highlight the line the cursor is on across the full width of the text area.
+    pub cursorline: bool,
+
+    /// The string shown on lines past the end of the buffer. May be empty to leave them blank.
+    pub empty_line_marker: String,
+
+    /// Start in a Vim-like Normal mode, where letters move the cursor and run commands, and only
+    /// type text after switching to Insert mode.
+    pub modal: bool,
+
+    /// Keys that quit the editor when pressed together with Ctrl.
+    pub quit_keys: Vec<char>,
+
+    /// Reserve columns on the right of the text area for a condensed overview of the whole file.
+    pub minimap: bool,
+
+    /// Mark lines added, modified or deleted since the file was loaded or last saved in a column
+    /// to the left of the text.
+    pub diff_gutter: bool,
+
+    /// Jump from the last change in the buffer to the first, and back, when moving between changes.
+    pub wrap_change_navigation: bool,
+
+    /// Show line numbers to the left of the text.
+    pub line_numbers: LineNumbers,
+
+    /// Highlight the characters of any line drawn past this column.
+    pub line_length_limit: Option<usize>,
+
+    /// Highlight whitespace at the end of lines. The cursor's line is left alone, so the highlight
+    /// doesn't flicker on and off while typing at the end of it.
+    pub highlight_trailing_whitespace: bool,
+
+    /// Dim the text outside the paragraph the cursor is in, so it stands out from the rest of the
+    /// buffer.
+    pub focus_mode: bool,
+
+    /// Mark words missing from the dictionary in Markdown and text files.
+    #[cfg(feature = "spell")]
+    pub spell_check: bool,
+
+    /// The word list used for spell checking, with one word per line.
+    #[cfg(feature = "spell")]
+    pub dictionary_path: std::path::PathBuf,
+
+    /// The command that starts a language server for files with a particular extension, keyed by
+    /// the extension without its leading dot. Problems the server finds are underlined. None are
+    /// set up by default, so no server is started unless the config file names one.
+    #[cfg(feature = "lsp")]
+    pub language_servers: HashMap<String, String>,
+
+    /// The width, in columns, that the reflow command wraps paragraphs to.
+    pub text_width: usize,
+
+    /// Capture the mouse so the scroll wheel scrolls the text. This stops the terminal's own mouse
+    /// selection from working.
+    pub mouse_scroll: bool,
+
+    /// Complete the word before the cursor from the other words in the buffer when Tab is pressed
+    /// at the end of a word.
+    pub tab_completion: bool,
+
+    /// The number of lines one step of the scroll wheel scrolls by.
+    pub scroll_lines: usize,
+
+    /// The number of lines kept on screen from the previous page by PageUp and PageDown.
+    pub page_overlap: usize,
+
+    /// Keep the selection after copying it, so it can be copied again or acted on. Cutting and
+    /// pasting always clear the selection, since the selected text is gone.
+    pub keep_selection_after_copy: bool,
+
+    /// Clear the selection once the buffer has been saved.
+    pub clear_selection_on_save: bool,
+
+    /// Save dirty buffers when the terminal loses focus or when switching away from them.
+    pub save_on_focus_lost: bool,
+
+    /// Ask before running a command that changes `bulk_edit_threshold` or more lines at once, such
+    /// as sorting, trimming or retabbing the whole buffer.
+    pub confirm_bulk_edits: bool,
+    pub bulk_edit_threshold: usize,
+
+    /// Save a named buffer once this many edits have been made to it since it was last saved.
+    pub autosave_after_edits: Option<usize>,
+
+    /// Quit the editor when the last open buffer is closed, rather than replacing it with an empty
+    /// buffer.
+    pub quit_on_last_buffer_close: bool,
+
+    /// The name to save a new, unnamed buffer as without asking for one, if set. If a file with
+    /// this name already exists, the editor asks for a name as usual.
+    pub default_new_filename: Option<String>,
+
+    /// Change the shape of the terminal cursor to show whether typing inserts or overwrites text.
+    pub set_cursor_shape: bool,
+
+    /// Cursor shapes used when typing inserts text and when it overwrites text.
+    pub insert_cursor_shape: SetCursorStyle,
+    pub overwrite_cursor_shape: SetCursorStyle,
+
+    /// Set the terminal window title to the name of the file being edited.
+    pub set_window_title: bool,
+
+    /// Draw in the terminal's alternate screen, so the shell's output is back as it was on exit.
+    /// When off, the shell's output is scrolled up to make room, and the editor's rows are cleared
+    /// on exit.
+    pub use_alternate_screen: bool,
+
+    /// Settings given to each newly opened buffer.
+    pub buffer_settings: BufferSettings,
+
+    /// Changes to `buffer_settings` for files with a particular extension, keyed by the extension
+    /// without its leading dot.
+    pub filetype_overrides: HashMap<String, FiletypeOverrides>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            cursorline: false,
+            empty_line_marker: String::from("~"),
+            modal: false,
+            quit_keys: vec!['q', 'd'],
+            minimap: false,
+            diff_gutter: false,
+            wrap_change_navigation: true,
+            line_numbers: LineNumbers::Off,
+            line_length_limit: None,
+            highlight_trailing_whitespace: false,
+            focus_mode: false,
+            #[cfg(feature = "spell")]
+            spell_check: false,
+            #[cfg(feature = "spell")]
+            dictionary_path: std::path::PathBuf::from("/usr/share/dict/words"),
+            #[cfg(feature = "lsp")]
+            language_servers: HashMap::new(),
+            text_width: 80,
+            mouse_scroll: false,
+            tab_completion: false,
+            scroll_lines: 3,
+            page_overlap: 1,
+            keep_selection_after_copy: true,
+            clear_selection_on_save: false,
+            save_on_focus_lost: false,
+            autosave_after_edits: None,
+            confirm_bulk_edits: false,
+            bulk_edit_threshold: 100,
+            quit_on_last_buffer_close: false,
+            default_new_filename: None,
+            set_cursor_shape: false,
+            insert_cursor_shape: SetCursorStyle::SteadyBar,
+            overwrite_cursor_shape: SetCursorStyle::SteadyBlock,
+            set_window_title: false,
+            use_alternate_screen: true,
+            buffer_settings: BufferSettings::default(),
+            // Go is conventionally shown with tabs 8 columns wide, as gofmt lines code up that way.
+            filetype_overrides: HashMap::from([(
+                String::from("go"),
+                FiletypeOverrides {
+                    tab_display_width: Some(8),
+                    ..FiletypeOverrides::default()
+                },
+            )]),
+        }
+    }
+}
+
+impl Config {
+    /// Returns the path of the config file: `editor/config` in `$XDG_CONFIG_HOME`, or in
+    /// `~/.config` if that isn't set.
+    pub fn path() -> Option<PathBuf> {
+        let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(config_dir.join("editor").join("config"))
+    }
+
+    /// Returns the default config with the settings in the config file laid over it, along with a
+    /// message for each line of the file that couldn't be used. A missing file isn't an error.
+    pub fn load() -> (Config, Vec<String>) {
+        let mut config = Config::default();
+        let Some(path) = Config::path() else {
+            return (config, Vec::new());
+        };
+        let errors = match fs::read_to_string(&path) {
+            Ok(contents) => config.read(&contents),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(_) => vec![format!("Could not read {}.", path.display())],
+        };
+        (config, errors)
+    }
+
+    /// Applies the settings in `contents`, the text of a config file, and returns a message for
+    /// each line that couldn't be used.
+    ///
+    /// Each line is a `key = value` pair, and lines starting with `#` are comments. Keys are the
+    /// names of the fields of `Config` and `BufferSettings`. A `[<extension>]` line starts a
+    /// section of `FiletypeOverrides` for files with that extension, and `[language_servers]`
+    /// starts a section of `<extension> = <command>` pairs.
+    pub fn read(&mut self, contents: &str) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut section: Option<String> = None;
+        for (idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line
+                .strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+            {
+                section = Some(name.trim().trim_start_matches('.').to_string());
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                errors.push(format!("Line {}: expected `key = value`.", idx + 1));
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            let result = match section.as_deref() {
+                None => self.set(key, value),
+                #[cfg(feature = "lsp")]
+                Some("language_servers") => {
+                    self.language_servers
+                        .insert(key.to_string(), value.to_string());
+                    Ok(())
+                }
+                Some(extension) => self
+                    .filetype_overrides
+                    .entry(extension.to_string())
+                    .or_default()
+                    .set(key, value),
+            };
+            if let Err(err) = result {
+                errors.push(format!("Line {}: {}", idx + 1, err));
+            }
+        }
+        errors
+    }
+
+    /// Sets the option named `key` from its text in a config file.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "cursorline" => self.cursorline = parse_bool(value)?,
+            "empty_line_marker" => self.empty_line_marker = value.to_string(),
+            "modal" => self.modal = parse_bool(value)?,
+            "quit_keys" => {
+                self.quit_keys = value
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|key| !key.is_empty())
+                    .map(|key| {
+                        let mut chars = key.chars();
+                        match (chars.next(), chars.next()) {
+                            (Some(c), None) => Ok(c.to_ascii_lowercase()),
+                            _ => Err(format!("quit keys are single letters, not `{}`.", key)),
+                        }
+                    })
+                    .collect::<Result<_, _>>()?;
+            }
+            "minimap" => self.minimap = parse_bool(value)?,
+            "diff_gutter" => self.diff_gutter = parse_bool(value)?,
+            "wrap_change_navigation" => self.wrap_change_navigation = parse_bool(value)?,
+            "line_numbers" => {
+                self.line_numbers = match value {
+                    "off" => LineNumbers::Off,
+                    "absolute" => LineNumbers::Absolute,
+                    "relative" => LineNumbers::Relative,
+                    "hybrid" => LineNumbers::Hybrid,
+                    _ => return Err(format!("unknown line number mode `{}`.", value)),
+                }
+            }
+            "line_length_limit" => self.line_length_limit = parse_optional_number(value)?,
+            "highlight_trailing_whitespace" => {
+                self.highlight_trailing_whitespace = parse_bool(value)?
+            }
+            "focus_mode" => self.focus_mode = parse_bool(value)?,
+            #[cfg(feature = "spell")]
+            "spell_check" => self.spell_check = parse_bool(value)?,
+            #[cfg(feature = "spell")]
+            "dictionary_path" => self.dictionary_path = PathBuf::from(value),
+            "text_width" => self.text_width = parse_number(value)?,
+            "mouse_scroll" => self.mouse_scroll = parse_bool(value)?,
+            "tab_completion" => self.tab_completion = parse_bool(value)?,
+            "scroll_lines" => self.scroll_lines = parse_number(value)?,
+            "page_overlap" => self.page_overlap = parse_number(value)?,
+            "keep_selection_after_copy" => self.keep_selection_after_copy = parse_bool(value)?,
+            "clear_selection_on_save" => self.clear_selection_on_save = parse_bool(value)?,
+            "save_on_focus_lost" => self.save_on_focus_lost = parse_bool(value)?,
+            "confirm_bulk_edits" => self.confirm_bulk_edits = parse_bool(value)?,
+            "bulk_edit_threshold" => self.bulk_edit_threshold = parse_number(value)?,
+            "autosave_after_edits" => self.autosave_after_edits = parse_optional_number(value)?,
+            "quit_on_last_buffer_close" => self.quit_on_last_buffer_close = parse_bool(value)?,
+            "default_new_filename" => {
+                self.default_new_filename = (!value.is_empty()).then(|| value.to_string())
+            }
+            "set_cursor_shape" => self.set_cursor_shape = parse_bool(value)?,
+            "insert_cursor_shape" => self.insert_cursor_shape = parse_cursor_shape(value)?,
+            "overwrite_cursor_shape" => self.overwrite_cursor_shape = parse_cursor_shape(value)?,
+            "set_window_title" => self.set_window_title = parse_bool(value)?,
+            "use_alternate_screen" => self.use_alternate_screen = parse_bool(value)?,
+            _ => return self.buffer_settings.set(key, value),
+        }
+        Ok(())
+    }
+}
+
+/// Options that can differ between buffers.
+#[derive(Clone)]
+pub struct BufferSettings {
+    /// The number of columns a literal tab character takes up on screen.
+    pub tab_display_width: usize,
+
+    /// The number of columns added or removed by indent and dedent, and the width of the indent
+    /// inserted by the Tab key when indenting with spaces.
+    pub indent_size: usize,
+
+    /// Indent with `indent_size` spaces rather than a tab character.
+    pub indent_with_spaces: bool,
+
+    /// Start new lines with the indentation of the line before, splitting empty bracket pairs
+    /// across three lines.
+    pub auto_indent: bool,
+
+    /// Replace tabs with spaces when the file is loaded.
+    pub retab_on_load: bool,
+
+    /// When retabbing, replace every tab rather than only those in a line's leading indentation.
+    pub retab_all_tabs: bool,
+
+    /// Open files that look binary, with a warning in the status bar, rather than refusing them.
+    pub open_binary_files: bool,
+
+    /// Refuse to load a file larger than this many bytes, so the user can be asked first. Loading
+    /// a very large file is slow and uses a lot of memory.
+    pub large_file_threshold: Option<u64>,
+
+    /// Make sure the file ends with a newline when it's saved.
+    pub insert_final_newline: bool,
+
+    /// Remove whitespace from the ends of lines when the file is saved.
+    pub trim_trailing_whitespace: bool,
+
+    /// Make sure saved files have reached the disk before reporting the save as done.
+    pub fsync_on_save: bool,
+
+    /// Start saved files with a UTF-8 byte order mark, even if they didn't have one when loaded.
+    pub write_utf8_bom: bool,
+
+    /// A shell command that reads text on stdin and writes it back formatted on stdout, like
+    /// `rustfmt` or `black -q -`.
+    pub formatter: Option<String>,
+
+    /// Run the buffer through `formatter` before saving it. If the formatter fails, the text is
+    /// saved as it was.
+    pub format_on_save: bool,
+}
+
+impl Default for BufferSettings {
+    fn default() -> Self {
+        BufferSettings {
+            tab_display_width: 4,
+            indent_size: 4,
+            indent_with_spaces: false,
+            auto_indent: false,
+            retab_on_load: false,
+            retab_all_tabs: false,
+            open_binary_files: false,
+            large_file_threshold: Some(50 * 1024 * 1024),
+            insert_final_newline: false,
+            trim_trailing_whitespace: false,
+            fsync_on_save: false,
+            write_utf8_bom: false,
+            formatter: None,
+            format_on_save: false,
+        }
+    }
+}
+
+impl BufferSettings {
+    /// Sets the setting named `key` from its text in a config file.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "tab_display_width" => self.tab_display_width = parse_number(value)?,
+            "indent_size" => self.indent_size = parse_number(value)?,
+            "indent_with_spaces" => self.indent_with_spaces = parse_bool(value)?,
+            "auto_indent" => self.auto_indent = parse_bool(value)?,
+            "retab_on_load" => self.retab_on_load = parse_bool(value)?,
+            "retab_all_tabs" => self.retab_all_tabs = parse_bool(value)?,
+            "open_binary_files" => self.open_binary_files = parse_bool(value)?,
+            "large_file_threshold" => {
+                self.large_file_threshold = parse_optional_number(value)?.map(|size| size as u64)
+            }
+            "insert_final_newline" => self.insert_final_newline = parse_bool(value)?,
+            "trim_trailing_whitespace" => self.trim_trailing_whitespace = parse_bool(value)?,
+            "fsync_on_save" => self.fsync_on_save = parse_bool(value)?,
+            "write_utf8_bom" => self.write_utf8_bom = parse_bool(value)?,
+            "formatter" => self.formatter = (!value.is_empty()).then(|| value.to_string()),
+            "format_on_save" => self.format_on_save = parse_bool(value)?,
+            _ => return Err(format!("unknown setting `{}`.", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Buffer settings for one filetype. Each setting left as `None` keeps its value from the base
+/// config.
+#[derive(Clone, Default)]
+pub struct FiletypeOverrides {
+    pub tab_display_width: Option<usize>,
+    pub indent_size: Option<usize>,
+    pub indent_with_spaces: Option<bool>,
+    pub insert_final_newline: Option<bool>,
+    pub trim_trailing_whitespace: Option<bool>,
+    pub write_utf8_bom: Option<bool>,
+    pub formatter: Option<String>,
+    pub format_on_save: Option<bool>,
+}
+
+impl FiletypeOverrides {
+    /// Replaces each setting in `settings` that this block sets.
+    pub fn apply(&self, settings: &mut BufferSettings) {
+        if let Some(width) = self.tab_display_width {
+            settings.tab_display_width = width;
+        }
+        if let Some(size) = self.indent_size {
+            settings.indent_size = size;
+        }
+        if let Some(spaces) = self.indent_with_spaces {
+            settings.indent_with_spaces = spaces;
+        }
+        if let Some(final_newline) = self.insert_final_newline {
+            settings.insert_final_newline = final_newline;
+        }
+        if let Some(trim) = self.trim_trailing_whitespace {
+            settings.trim_trailing_whitespace = trim;
+        }
+        if let Some(bom) = self.write_utf8_bom {
+            settings.write_utf8_bom = bom;
+        }
+        if let Some(formatter) = &self.formatter {
+            settings.formatter = Some(formatter.clone());
+        }
+        if let Some(format_on_save) = self.format_on_save {
+            settings.format_on_save = format_on_save;
+        }
+    }
+
+    /// Sets the setting named `key` from its text in a config file.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "tab_display_width" => self.tab_display_width = Some(parse_number(value)?),
+            "indent_size" => self.indent_size = Some(parse_number(value)?),
+            "indent_with_spaces" => self.indent_with_spaces = Some(parse_bool(value)?),
+            "insert_final_newline" => self.insert_final_newline = Some(parse_bool(value)?),
+            "trim_trailing_whitespace" => self.trim_trailing_whitespace = Some(parse_bool(value)?),
+            "write_utf8_bom" => self.write_utf8_bom = Some(parse_bool(value)?),
+            "formatter" => self.formatter = Some(value.to_string()),
+            "format_on_save" => self.format_on_save = Some(parse_bool(value)?),
+            _ => return Err(format!("unknown filetype setting `{}`.", key)),
+        }
+        Ok(())
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("expected true or false, not `{}`.", value)),
+    }
+}
+
+fn parse_number(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("expected a number, not `{}`.", value))
+}
+
+/// Parses a number, or `off` for none.
+fn parse_optional_number(value: &str) -> Result<Option<usize>, String> {
+    match value {
+        "off" | "none" => Ok(None),
+        _ => parse_number(value).map(Some),
+    }
+}
+
+fn parse_cursor_shape(value: &str) -> Result<SetCursorStyle, String> {
+    match value {
+        "default" => Ok(SetCursorStyle::DefaultUserShape),
+        "block" => Ok(SetCursorStyle::SteadyBlock),
+        "blinking_block" => Ok(SetCursorStyle::BlinkingBlock),
+        "underscore" => Ok(SetCursorStyle::SteadyUnderScore),
+        "blinking_underscore" => Ok(SetCursorStyle::BlinkingUnderScore),
+        "bar" => Ok(SetCursorStyle::SteadyBar),
+        "blinking_bar" => Ok(SetCursorStyle::BlinkingBar),
+        _ => Err(format!("unknown cursor shape `{}`.", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_sets_options_and_filetype_overrides() {
+        let mut config = Config::default();
+        let errors = config.read(
+            "# A comment\n\
+             modal = yes\n\
+             quit_keys = q, x\n\
+             line_numbers = relative\n\
+             autosave_after_edits = 50\n\
+             fsync_on_save = on\n\
+             \n\
+             [.py]\n\
+             indent_size = 4\n\
+             formatter = black -\n",
+        );
+        assert!(errors.is_empty());
+        assert!(config.modal);
+        assert_eq!(config.quit_keys, vec!['q', 'x']);
+        assert!(matches!(config.line_numbers, LineNumbers::Relative));
+        assert_eq!(config.autosave_after_edits, Some(50));
+        assert!(config.buffer_settings.fsync_on_save);
+        let python = &config.filetype_overrides["py"];
+        assert_eq!(python.indent_size, Some(4));
+        assert_eq!(python.formatter.as_deref(), Some("black -"));
+    }
+
+    #[test]
+    fn read_reports_bad_lines_and_keeps_going() {
+        let mut config = Config::default();
+        let errors = config.read("modal = maybe\nno equals sign\nunknown = 1\nminimap = true\n");
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].starts_with("Line 1:"));
+        assert!(errors[1].starts_with("Line 2:"));
+        assert!(errors[2].starts_with("Line 3:"));
+        assert!(config.minimap);
+    }
+
+    #[test]
+    fn off_clears_optional_numbers() {
+        let mut config = Config::default();
+        assert!(config.read("line_length_limit = off\n").is_empty());
+        assert_eq!(config.line_length_limit, None);
+    }
+}
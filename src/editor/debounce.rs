@@ -0,0 +1,69 @@
+use std::time::{Duration, Instant};
+
+/// A cached value that is only worth recomputing once its inputs have stopped changing for a
+/// while, so slow work doesn't run on every keystroke.
+pub struct Debounced<T> {
+    value: Option<T>,
+    delay: Duration,
+    /// When the inputs last changed, if they have since the value was last set.
+    changed_at: Option<Instant>,
+}
+
+impl<T> Debounced<T> {
+    pub fn new(delay: Duration) -> Self {
+        Debounced {
+            value: None,
+            delay,
+            changed_at: None,
+        }
+    }
+
+    /// Returns the cached value, which may be out of date.
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    /// Replaces the cached value, which is then up to date.
+    pub fn set(&mut self, value: T) {
+        self.value = Some(value);
+        self.changed_at = None;
+    }
+
+    /// Records that the inputs have changed, restarting the delay.
+    pub fn touch(&mut self) {
+        self.changed_at = Some(Instant::now());
+    }
+
+    /// Returns how long until the value is due to be recomputed, or None if it's up to date.
+    pub fn time_until_due(&self) -> Option<Duration> {
+        self.changed_at
+            .map(|changed_at| self.delay.saturating_sub(changed_at.elapsed()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_due_once_the_delay_has_passed_since_the_last_touch() {
+        let mut debounced = Debounced::new(Duration::from_millis(50));
+        assert!(debounced.get().is_none());
+        assert_eq!(debounced.time_until_due(), None);
+
+        debounced.set(1);
+        debounced.touch();
+        let due = debounced.time_until_due().unwrap();
+        assert!(due > Duration::ZERO && due <= Duration::from_millis(50));
+        assert_eq!(debounced.get(), Some(&1));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(debounced.time_until_due(), Some(Duration::ZERO));
+        debounced.touch();
+        assert!(debounced.time_until_due().unwrap() > Duration::ZERO);
+
+        debounced.set(2);
+        assert_eq!(debounced.time_until_due(), None);
+        assert_eq!(debounced.get(), Some(&2));
+    }
+}
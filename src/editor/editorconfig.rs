@@ -0,0 +1,257 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::editor::{buffer::LineEnding, config::FiletypeOverrides};
+
+/// The settings `.editorconfig` files give a file. Supports `indent_style`, `indent_size`,
+/// `tab_width`, `end_of_line`, `charset`, `trim_trailing_whitespace` and `insert_final_newline`;
+/// other keys are ignored.
+#[derive(Default)]
+pub struct EditorConfig {
+    /// The buffer settings that are set, laid over the global and filetype settings.
+    pub overrides: FiletypeOverrides,
+
+    /// The line endings the file should be saved with.
+    pub end_of_line: Option<LineEnding>,
+
+    /// Whether `indent_size` was given as `tab`, meaning the same as `tab_width`.
+    indent_size_is_tab: bool,
+}
+
+impl EditorConfig {
+    /// Reads the settings for the file at `path`, which should be absolute, from the
+    /// `.editorconfig` files in its directory and the directories above it. The search stops at a
+    /// file with `root = true`. Where files disagree, the one nearest `path` wins, and within a
+    /// file, the last matching section wins.
+    pub fn for_path(path: &Path) -> Self {
+        let mut config = EditorConfig::default();
+        let mut config_files = Vec::new();
+        for dir in path.ancestors().skip(1) {
+            let config_path = dir.join(".editorconfig");
+            let Ok(contents) = fs::read_to_string(&config_path) else {
+                continue;
+            };
+            // `root` is only read from before the first section.
+            let is_root = contents
+                .lines()
+                .take_while(|line| !line.trim().starts_with('['))
+                .filter_map(parse_pair)
+                .any(|(key, value)| key == "root" && value == "true");
+            config_files.push((dir.to_path_buf(), contents));
+            if is_root {
+                break;
+            }
+        }
+
+        for (dir, contents) in config_files.iter().rev() {
+            config.read(dir, contents, path);
+        }
+
+        // Each of `indent_size` and `tab_width` defaults to the other, and indenting with tabs
+        // implies an indent size of one tab.
+        let overrides = &mut config.overrides;
+        let indent_with_tabs = overrides.indent_with_spaces == Some(false);
+        if config.indent_size_is_tab || (indent_with_tabs && overrides.indent_size.is_none()) {
+            overrides.indent_size = overrides.tab_display_width;
+        }
+        if overrides.tab_display_width.is_none() {
+            overrides.tab_display_width = overrides.indent_size;
+        }
+        config
+    }
+
+    /// Applies the sections of the `.editorconfig` in `dir`, with contents `contents`, that match
+    /// `path`.
+    fn read(&mut self, dir: &Path, contents: &str, path: &Path) {
+        let relative = path
+            .strip_prefix(dir)
+            .map(PathBuf::from)
+            .unwrap_or_default();
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let mut in_matching_section = false;
+        for line in contents.lines() {
+            let line = line.trim();
+            if let Some(section) = line
+                .strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+            {
+                // Patterns without a slash match files with that name in any directory; others
+                // are matched against the path from the `.editorconfig`'s directory.
+                in_matching_section = if section.contains('/') {
+                    glob_matches(section.trim_start_matches('/'), &relative)
+                } else {
+                    glob_matches(section, &file_name)
+                };
+            } else if in_matching_section && let Some((key, value)) = parse_pair(line) {
+                self.set(&key, &value);
+            }
+        }
+    }
+
+    /// Sets the setting named by `key` to `value`. Unknown keys and values are ignored.
+    fn set(&mut self, key: &str, value: &str) {
+        let overrides = &mut self.overrides;
+        match (key, value) {
+            ("indent_style", "space") => overrides.indent_with_spaces = Some(true),
+            ("indent_style", "tab") => overrides.indent_with_spaces = Some(false),
+            ("indent_size", "tab") => {
+                overrides.indent_size = None;
+                self.indent_size_is_tab = true;
+            }
+            ("indent_size", size) => {
+                overrides.indent_size = size.parse().ok();
+                self.indent_size_is_tab = false;
+            }
+            ("tab_width", width) => overrides.tab_display_width = width.parse().ok(),
+            ("end_of_line", "lf") => self.end_of_line = Some(LineEnding::Lf),
+            ("end_of_line", "crlf") => self.end_of_line = Some(LineEnding::Crlf),
+            ("charset", "utf-8") => overrides.write_utf8_bom = Some(false),
+            ("charset", "utf-8-bom") => overrides.write_utf8_bom = Some(true),
+            ("trim_trailing_whitespace", trim) => {
+                overrides.trim_trailing_whitespace = trim.parse().ok();
+            }
+            ("insert_final_newline", insert) => {
+                overrides.insert_final_newline = insert.parse().ok()
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Splits a `key = value` line into its key and value, both lowercased. Returns None for comments
+/// and other lines without an `=`.
+fn parse_pair(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if line.starts_with('#') || line.starts_with(';') {
+        return None;
+    }
+    let (key, value) = line.split_once('=')?;
+    Some((key.trim().to_lowercase(), value.trim().to_lowercase()))
+}
+
+/// Returns true if `text` matches the `.editorconfig` glob `pattern`. `*` matches anything but a
+/// slash, `**` matches anything, `?` matches any one character but a slash, `[abc]` and `[!abc]`
+/// match one character in or not in the brackets, and `{a,b}` matches any of its comma-separated
+/// alternatives.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    expand_braces(pattern)
+        .iter()
+        .any(|pattern| wildcard_matches(pattern.as_bytes(), text.as_bytes()))
+}
+
+/// Expands each `{a,b}` in `pattern` into one pattern for each alternative.
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let (Some(open), Some(close)) = (pattern.find('{'), pattern.find('}')) else {
+        return vec![pattern.to_string()];
+    };
+    if close < open {
+        return vec![pattern.to_string()];
+    }
+    let (before, after) = (&pattern[..open], &pattern[close + 1..]);
+    pattern[open + 1..close]
+        .split(',')
+        .flat_map(|alternative| expand_braces(&format!("{}{}{}", before, alternative, after)))
+        .collect()
+}
+
+/// Matches `text` against a pattern holding only the `*`, `**`, `?` and `[...]` wildcards.
+fn wildcard_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|idx| wildcard_matches(rest, &text[idx..])),
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&idx| idx == 0 || text[idx - 1] != b'/')
+            .any(|idx| wildcard_matches(rest, &text[idx..])),
+        [b'?', rest @ ..] => {
+            text.first().is_some_and(|&c| c != b'/') && wildcard_matches(rest, &text[1..])
+        }
+        [b'[', class @ ..] if let Some(close) = class.iter().position(|&c| c == b']') => {
+            let (negated, chars) = match &class[..close] {
+                [b'!', chars @ ..] => (true, chars),
+                chars => (false, chars),
+            };
+            text.first()
+                .is_some_and(|c| *c != b'/' && chars.contains(c) != negated)
+                && wildcard_matches(&class[close + 1..], &text[1..])
+        }
+        [c, rest @ ..] => text.first() == Some(c) && wildcard_matches(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty directory for a test's files, named after the test.
+    fn fixture_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("editor-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn sections_apply_only_to_matching_files() {
+        let dir = fixture_dir("editorconfig-sections");
+        fs::write(
+            dir.join(".editorconfig"),
+            "root = true\n\
+             \n\
+             [*.py]\n\
+             indent_style = space\n\
+             indent_size = 4\n\
+             trim_trailing_whitespace = true\n",
+        )
+        .unwrap();
+        let python = EditorConfig::for_path(&dir.join("script.py"));
+        let rust = EditorConfig::for_path(&dir.join("main.rs"));
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(python.overrides.indent_with_spaces, Some(true));
+        assert_eq!(python.overrides.indent_size, Some(4));
+        assert_eq!(python.overrides.tab_display_width, Some(4));
+        assert_eq!(python.overrides.trim_trailing_whitespace, Some(true));
+        assert_eq!(rust.overrides.indent_with_spaces, None);
+        assert_eq!(rust.overrides.indent_size, None);
+        assert_eq!(rust.overrides.trim_trailing_whitespace, None);
+    }
+
+    #[test]
+    fn nearer_files_win_and_root_stops_the_search() {
+        let dir = fixture_dir("editorconfig-nesting");
+        let project = dir.join("project");
+        fs::create_dir_all(project.join("src")).unwrap();
+        fs::write(dir.join(".editorconfig"), "[*]\nend_of_line = crlf\n").unwrap();
+        fs::write(
+            project.join(".editorconfig"),
+            "root = true\n[*]\nindent_style = tab\ntab_width = 8\n",
+        )
+        .unwrap();
+        fs::write(project.join("src/.editorconfig"), "[*.rs]\ntab_width = 4\n").unwrap();
+        let config = EditorConfig::for_path(&project.join("src/main.rs"));
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(config.overrides.indent_with_spaces, Some(false));
+        assert_eq!(config.overrides.tab_display_width, Some(4));
+        assert_eq!(config.overrides.indent_size, Some(4));
+        assert!(config.end_of_line.is_none());
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("*.{js,ts}", "app.ts"));
+        assert!(!glob_matches("*.{js,ts}", "app.rs"));
+        assert!(glob_matches("src/*.rs", "src/main.rs"));
+        assert!(!glob_matches("src/*.rs", "src/editor/mod.rs"));
+        assert!(glob_matches("src/**.rs", "src/editor/mod.rs"));
+        assert!(glob_matches("file?.[ch]", "file1.h"));
+        assert!(!glob_matches("file?.[!ch]", "file1.h"));
+    }
+}
@@ -0,0 +1,306 @@
+use std::fmt;
+
+/// A JSON value, as sent to and from language servers. Objects keep their keys in order.
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// How deeply `parse` lets arrays and objects nest, so a hostile message can't overflow the
+    /// stack.
+    const MAX_DEPTH: usize = 128;
+
+    /// Builds an object from its keys and values.
+    pub fn object<const N: usize>(pairs: [(&str, Json); N]) -> Json {
+        Json::Object(
+            pairs
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// Parses `text` as a single JSON value. Returns None if it isn't valid JSON, or if arrays
+    /// and objects are nested more than `MAX_DEPTH` deep.
+    pub fn parse(text: &str) -> Option<Json> {
+        let mut parser = Parser {
+            chars: text.chars().peekable(),
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        parser.chars.next().is_none().then_some(value)
+    }
+
+    /// Returns the value of `key` if this is an object holding it.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(pairs) => pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// Returns the string if this is one.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    /// Returns the value if it's a whole number that fits in a usize.
+    pub fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Json::Number(number) if number >= 0.0 && number.fract() == 0.0 => Some(number as usize),
+            _ => None,
+        }
+    }
+
+    /// Returns the values if this is an array.
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(string: &str) -> Self {
+        Json::String(string.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(string: String) -> Self {
+        Json::String(string)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(number: i64) -> Self {
+        Json::Number(number as f64)
+    }
+}
+
+impl fmt::Display for Json {
+    /// Writes the value as compact JSON.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Number(number) if number.fract() == 0.0 && number.abs() < 1e15 => {
+                write!(f, "{}", *number as i64)
+            }
+            Json::Number(number) => write!(f, "{}", number),
+            Json::String(string) => write_string(f, string),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (idx, value) in values.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(pairs) => {
+                write!(f, "{{")?;
+                for (idx, (key, value)) in pairs.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+/// Writes `string` as a quoted JSON string, escaping quotes, backslashes and control characters.
+fn write_string(f: &mut fmt::Formatter<'_>, string: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for ch in string.chars() {
+        match ch {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            ch if ch.is_control() => write!(f, "\\u{:04x}", ch as u32)?,
+            ch => write!(f, "{}", ch)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Reads JSON values from the front of a string.
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    /// How many arrays and objects enclose the value being read.
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|ch| ch.is_ascii_whitespace()).is_some() {}
+    }
+
+    /// Consumes `word`, such as `true`, and returns `value`.
+    fn keyword(&mut self, word: &str, value: Json) -> Option<Json> {
+        for expected in word.chars() {
+            self.chars.next_if_eq(&expected)?;
+        }
+        Some(value)
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.skip_whitespace();
+        let next = *self.chars.peek()?;
+        if matches!(next, '[' | '{') {
+            if self.depth == Json::MAX_DEPTH {
+                return None;
+            }
+            self.depth += 1;
+            let value = self.container();
+            self.depth -= 1;
+            return value;
+        }
+        match next {
+            'n' => self.keyword("null", Json::Null),
+            't' => self.keyword("true", Json::Bool(true)),
+            'f' => self.keyword("false", Json::Bool(false)),
+            '"' => self.string().map(Json::String),
+            _ => {
+                let mut number = String::new();
+                while let Some(ch) = self
+                    .chars
+                    .next_if(|ch| ch.is_ascii_digit() || "+-.eE".contains(*ch))
+                {
+                    number.push(ch);
+                }
+                number.parse().ok().map(Json::Number)
+            }
+        }
+    }
+
+    /// Reads an array or an object.
+    fn container(&mut self) -> Option<Json> {
+        match self.chars.peek()? {
+            '[' => {
+                self.chars.next();
+                let mut values = Vec::new();
+                self.skip_whitespace();
+                if self.chars.next_if_eq(&']').is_some() {
+                    return Some(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.chars.next()? {
+                        ',' => {}
+                        ']' => return Some(Json::Array(values)),
+                        _ => return None,
+                    }
+                }
+            }
+            '{' => {
+                self.chars.next();
+                let mut pairs = Vec::new();
+                self.skip_whitespace();
+                if self.chars.next_if_eq(&'}').is_some() {
+                    return Some(Json::Object(pairs));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.chars.next_if_eq(&':')?;
+                    pairs.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.chars.next()? {
+                        ',' => {}
+                        '}' => return Some(Json::Object(pairs)),
+                        _ => return None,
+                    }
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Reads a quoted string, undoing its escapes.
+    fn string(&mut self) -> Option<String> {
+        self.chars.next_if_eq(&'"')?;
+        let mut string = String::new();
+        loop {
+            match self.chars.next()? {
+                '"' => return Some(string),
+                '\\' => match self.chars.next()? {
+                    'n' => string.push('\n'),
+                    'r' => string.push('\r'),
+                    't' => string.push('\t'),
+                    'b' => string.push('\u{8}'),
+                    'f' => string.push('\u{c}'),
+                    'u' => {
+                        let unit = self.hex_code_unit()?;
+                        // Chars outside the Basic Multilingual Plane come as a surrogate pair.
+                        let ch = if (0xd800..0xdc00).contains(&unit) {
+                            self.chars.next_if_eq(&'\\')?;
+                            self.chars.next_if_eq(&'u')?;
+                            let low = self.hex_code_unit()?;
+                            char::decode_utf16([unit, low]).next()?.ok()?
+                        } else {
+                            char::from_u32(unit as u32)?
+                        };
+                        string.push(ch);
+                    }
+                    ch => string.push(ch),
+                },
+                ch => string.push(ch),
+            }
+        }
+    }
+
+    /// Reads the four hex digits of a `\u` escape.
+    fn hex_code_unit(&mut self) -> Option<u16> {
+        let digits: String = (0..4).filter_map(|_| self.chars.next()).collect();
+        u16::from_str_radix(&digits, 16).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nested_values_and_escapes() {
+        let json = Json::parse(r#"{"a": [1, -2.5, true, null], "b": "x\"é😀"}"#).unwrap();
+        let values = json.get("a").and_then(Json::as_array).unwrap();
+        assert_eq!(values[0].as_usize(), Some(1));
+        assert!(matches!(values[1], Json::Number(n) if n == -2.5));
+        assert_eq!(json.get("b").and_then(Json::as_str), Some("x\"é😀"));
+        assert_eq!(
+            Json::parse(&json.to_string()).unwrap().to_string(),
+            json.to_string()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_and_too_deeply_nested_values() {
+        assert!(Json::parse("[1, 2").is_none());
+        assert!(Json::parse("{} extra").is_none());
+
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(Json::parse(&nested(Json::MAX_DEPTH)).is_some());
+        assert!(Json::parse(&nested(Json::MAX_DEPTH + 1)).is_none());
+        assert!(Json::parse(&nested(1_000_000)).is_none());
+    }
+}
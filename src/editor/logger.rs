@@ -0,0 +1,54 @@
+use crossterm::event::KeyEvent;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+/// Writes a line for every key the editor handles, along with where the cursor and view ended up,
+/// so reports of odd cursor movement can be reproduced.
+pub struct Logger {
+    file: File,
+}
+
+impl Logger {
+    /// Opens the log at `path`, adding to the end of it if it already exists.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Logger { file })
+    }
+
+    /// Writes one line for `key_event`. Failing to write isn't worth interrupting editing for, so
+    /// errors are ignored.
+    pub fn log_key(&mut self, key_event: KeyEvent, cursor_idx: usize, origin: (usize, usize)) {
+        let _ = writeln!(
+            self.file,
+            "{:?} {:?} cursor_idx={} visual_origin={},{}",
+            key_event.code, key_event.modifiers, cursor_idx, origin.0, origin.1
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::{KeyCode, KeyModifiers};
+
+    #[test]
+    fn each_key_is_appended_as_a_line() {
+        let path = std::env::temp_dir().join(format!("editor-log-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        for code in [KeyCode::Char('a'), KeyCode::Left] {
+            let mut logger = Logger::open(&path).unwrap();
+            logger.log_key(KeyEvent::new(code, KeyModifiers::NONE), 1, (0, 2));
+        }
+        let log = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(
+            log,
+            "Char('a') KeyModifiers(0x0) cursor_idx=1 visual_origin=0,2\n\
+             Left KeyModifiers(0x0) cursor_idx=1 visual_origin=0,2\n"
+        );
+    }
+}
@@ -0,0 +1,500 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
+    ops::Range,
+    path::Path,
+    process::{Child, Command, Stdio},
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use ropey::RopeSlice;
+
+use crate::editor::json::Json;
+
+/// How serious a diagnostic is, from most to least.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Information,
+    Hint,
+}
+
+/// A problem a language server has found in a file. Positions are as the server sent them: a line
+/// index and an offset into the line in UTF-16 code units.
+pub struct Diagnostic {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Reads a diagnostic from its JSON form. A missing severity counts as an error.
+    fn from_json(json: &Json) -> Option<Self> {
+        let position = |key: &str| {
+            let position = json.get("range")?.get(key)?;
+            Some((
+                position.get("line")?.as_usize()?,
+                position.get("character")?.as_usize()?,
+            ))
+        };
+        let severity = match json.get("severity").and_then(Json::as_usize) {
+            Some(2) => Severity::Warning,
+            Some(3) => Severity::Information,
+            Some(4) => Severity::Hint,
+            _ => Severity::Error,
+        };
+        Some(Diagnostic {
+            start: position("start")?,
+            end: position("end")?,
+            severity,
+            message: json.get("message")?.as_str()?.to_string(),
+        })
+    }
+
+    /// Returns the char columns the diagnostic covers on line `line_idx`, whose text without its
+    /// line ending is `line`, or None if it doesn't reach that line. An empty diagnostic covers one
+    /// char, so it can be seen.
+    pub fn columns_on_line(&self, line_idx: usize, line: RopeSlice<'_>) -> Option<Range<usize>> {
+        if !(self.start.0..=self.end.0).contains(&line_idx) {
+            return None;
+        }
+        let start = if self.start.0 == line_idx {
+            char_column(line, self.start.1)
+        } else {
+            0
+        };
+        let end = if self.end.0 == line_idx {
+            char_column(line, self.end.1)
+        } else {
+            line.len_chars()
+        };
+        Some(start..end.max(start + 1))
+    }
+}
+
+/// Converts an offset into `line` in UTF-16 code units to an offset in chars. Offsets past the end
+/// of the line are clamped to it.
+fn char_column(line: RopeSlice<'_>, utf16_col: usize) -> usize {
+    line.utf16_cu_to_char(utf16_col.min(line.len_utf16_cu()))
+}
+
+/// A language server run as a child process, talked to over its standard input and output. Only
+/// the messages for keeping documents open and receiving diagnostics are supported.
+pub struct LanguageServer {
+    child: Child,
+
+    /// Messages to write to the server's input, sent on by a thread so a busy server can't block
+    /// the editor.
+    outgoing: Sender<String>,
+
+    /// Messages read from the server's output by another thread.
+    incoming: Receiver<Json>,
+
+    /// Whether the server has answered the `initialize` request. Until then, other messages are
+    /// held in `queued`.
+    initialized: bool,
+    queued: Vec<Json>,
+
+    /// The version and text last sent for each open document, keyed by URI.
+    documents: HashMap<String, (i64, String)>,
+
+    /// The latest diagnostics for each document, keyed by URI.
+    pub diagnostics: HashMap<String, Vec<Diagnostic>>,
+}
+
+impl LanguageServer {
+    /// The ids of the `initialize` and `shutdown` requests, the only requests sent.
+    const INITIALIZE_ID: usize = 1;
+    const SHUTDOWN_ID: usize = 2;
+
+    /// How long to give the server to exit by itself when it's dropped.
+    const EXIT_TIMEOUT: Duration = Duration::from_millis(500);
+
+    /// Starts the server by running `command` with `sh -c`, and asks it to initialize for the
+    /// project at `root`.
+    pub fn start(command: &str, root: &Path) -> io::Result<Self> {
+        let mut child = Command::new("sh")
+            .args(["-c", command])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let (Some(mut stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(io::Error::other("could not connect to language server"));
+        };
+
+        let (outgoing, to_write) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            for message in to_write {
+                let framed = format!("Content-Length: {}\r\n\r\n{}", message.len(), message);
+                if stdin.write_all(framed.as_bytes()).is_err() || stdin.flush().is_err() {
+                    break;
+                }
+            }
+        });
+        let (received, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            while let Ok(Some(message)) = read_message(&mut reader) {
+                if let Some(json) = Json::parse(&message)
+                    && received.send(json).is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        let server = LanguageServer {
+            child,
+            outgoing,
+            incoming,
+            initialized: false,
+            queued: Vec::new(),
+            documents: HashMap::new(),
+            diagnostics: HashMap::new(),
+        };
+        server.send(Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("id", (Self::INITIALIZE_ID as i64).into()),
+            ("method", "initialize".into()),
+            (
+                "params",
+                Json::object([
+                    ("processId", (std::process::id() as i64).into()),
+                    ("rootUri", uri_for_path(root).into()),
+                    ("capabilities", Json::object([])),
+                ]),
+            ),
+        ]))?;
+        Ok(server)
+    }
+
+    /// Tells the server the document at `uri` now holds `text`, opening it first if the server
+    /// hasn't been told about it. Nothing is sent if the text hasn't changed.
+    pub fn sync(&mut self, uri: &str, language_id: &str, text: String) -> io::Result<()> {
+        let version = match self.documents.get_mut(uri) {
+            Some((_, sent)) if *sent == text => return Ok(()),
+            Some((version, sent)) => {
+                *version += 1;
+                sent.clone_from(&text);
+                *version
+            }
+            None => {
+                self.documents.insert(uri.to_string(), (1, text.clone()));
+                1
+            }
+        };
+
+        if version == 1 {
+            let document = Json::object([
+                ("uri", uri.into()),
+                ("languageId", language_id.into()),
+                ("version", version.into()),
+                ("text", text.into()),
+            ]);
+            self.notify(
+                "textDocument/didOpen",
+                Json::object([("textDocument", document)]),
+            )
+        } else {
+            // The whole text is sent with each change, rather than working out what changed.
+            let document = Json::object([("uri", uri.into()), ("version", version.into())]);
+            let change = Json::object([("text", text.into())]);
+            self.notify(
+                "textDocument/didChange",
+                Json::object([
+                    ("textDocument", document),
+                    ("contentChanges", Json::Array(vec![change])),
+                ]),
+            )
+        }
+    }
+
+    /// Handles the messages the server has sent since this was last called. Returns true if any
+    /// diagnostics changed, or an error if the server has exited.
+    pub fn receive(&mut self) -> io::Result<bool> {
+        let mut changed = false;
+        loop {
+            let message = match self.incoming.try_recv() {
+                Ok(message) => message,
+                Err(TryRecvError::Empty) => return Ok(changed),
+                Err(TryRecvError::Disconnected) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "language server exited",
+                    ));
+                }
+            };
+
+            let method = message.get("method").and_then(Json::as_str);
+            match (method, message.get("id")) {
+                (Some("textDocument/publishDiagnostics"), _) => {
+                    let Some(params) = message.get("params") else {
+                        continue;
+                    };
+                    let Some(uri) = params.get("uri").and_then(Json::as_str) else {
+                        continue;
+                    };
+                    let diagnostics = params
+                        .get("diagnostics")
+                        .and_then(Json::as_array)
+                        .unwrap_or_default()
+                        .iter()
+                        .filter_map(Diagnostic::from_json)
+                        .collect();
+                    self.diagnostics.insert(uri.to_string(), diagnostics);
+                    changed = true;
+                }
+                // Requests from the server aren't supported, but are answered so it isn't left
+                // waiting.
+                (Some(_), Some(id)) => self.send(Json::object([
+                    ("jsonrpc", "2.0".into()),
+                    ("id", clone_id(id)),
+                    ("result", Json::Null),
+                ]))?,
+                (None, Some(id)) if id.as_usize() == Some(Self::INITIALIZE_ID) => {
+                    self.initialized = true;
+                    self.notify("initialized", Json::object([]))?;
+                    for queued in std::mem::take(&mut self.queued) {
+                        self.send(queued)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Sends a notification, or holds it until the server has initialized.
+    fn notify(&mut self, method: &str, params: Json) -> io::Result<()> {
+        let message = Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", method.into()),
+            ("params", params),
+        ]);
+        if self.initialized || method == "initialized" {
+            self.send(message)
+        } else {
+            self.queued.push(message);
+            Ok(())
+        }
+    }
+
+    fn send(&self, message: Json) -> io::Result<()> {
+        self.outgoing
+            .send(message.to_string())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "language server exited"))
+    }
+}
+
+impl Drop for LanguageServer {
+    /// Asks the server to shut down and exit, and closes its input. A server still running after
+    /// `EXIT_TIMEOUT` is killed.
+    fn drop(&mut self) {
+        let _ = self.send(Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("id", (Self::SHUTDOWN_ID as i64).into()),
+            ("method", "shutdown".into()),
+        ]));
+        let _ = self.send(Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", "exit".into()),
+        ]));
+        self.outgoing = mpsc::channel().0;
+
+        let deadline = Instant::now() + Self::EXIT_TIMEOUT;
+        while Instant::now() < deadline {
+            match self.child.try_wait() {
+                Ok(None) => thread::sleep(Duration::from_millis(10)),
+                _ => return,
+            }
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Copies a request id, which is either a number or a string.
+fn clone_id(id: &Json) -> Json {
+    match (id.as_usize(), id.as_str()) {
+        (Some(number), _) => (number as i64).into(),
+        (_, Some(string)) => string.into(),
+        _ => Json::Null,
+    }
+}
+
+/// The largest message `read_message` accepts, so a misbehaving server can't make the editor
+/// allocate without limit.
+const MAX_MESSAGE_LENGTH: usize = 64 * 1024 * 1024;
+
+/// Reads one message's content from `reader`, skipping its headers. Returns None at the end of the
+/// output.
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            content_length = value.trim().parse().ok();
+        }
+    }
+
+    let Some(content_length) = content_length else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message without a Content-Length header",
+        ));
+    };
+    if content_length > MAX_MESSAGE_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message longer than the Content-Length limit",
+        ));
+    }
+    let mut content = vec![0; content_length];
+    reader.read_exact(&mut content)?;
+    Ok(Some(String::from_utf8_lossy(&content).into_owned()))
+}
+
+/// Returns the `file://` URI for the absolute path `path`, percent-encoding the bytes that can't
+/// appear in one as they are.
+pub fn uri_for_path(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    uri
+}
+
+/// Returns the language identifier servers expect for files with the extension `extension`.
+pub fn language_id(extension: &str) -> &str {
+    match extension {
+        "rs" => "rust",
+        "py" => "python",
+        "js" => "javascript",
+        "ts" => "typescript",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "hpp" => "cpp",
+        "md" => "markdown",
+        "sh" => "shellscript",
+        extension => extension,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_framed_messages() {
+        let mut input: &[u8] =
+            b"Content-Length: 2\r\nContent-Type: x\r\n\r\n{}Content-Length: 1\r\n\r\n1";
+        assert_eq!(read_message(&mut input).unwrap().as_deref(), Some("{}"));
+        assert_eq!(read_message(&mut input).unwrap().as_deref(), Some("1"));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn refuses_oversized_messages() {
+        let header = format!("Content-Length: {}\r\n\r\n", MAX_MESSAGE_LENGTH + 1);
+        let mut input = header.as_bytes();
+        let err = read_message(&mut input).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// A shell script that acts as a language server: it answers `initialize`, reports one
+    /// diagnostic for `uri`, and copies everything it's sent to the file at `log`.
+    fn mock_server(uri: &str, log: &Path) -> String {
+        let initialized = r#"{"jsonrpc":"2.0","id":1,"result":{"capabilities":{}}}"#;
+        let position =
+            |character: i64| Json::object([("line", 0.into()), ("character", character.into())]);
+        let diagnostic = Json::object([
+            (
+                "range",
+                Json::object([("start", position(4)), ("end", position(7))]),
+            ),
+            ("severity", 2.into()),
+            ("message", "unused variable".into()),
+        ]);
+        let diagnostics = Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", "textDocument/publishDiagnostics".into()),
+            (
+                "params",
+                Json::object([
+                    ("uri", uri.into()),
+                    ("diagnostics", Json::Array(vec![diagnostic])),
+                ]),
+            ),
+        ]);
+        format!(
+            "a='{}'; b='{}'; \
+             printf 'Content-Length: %d\\r\\n\\r\\n%s' ${{#a}} \"$a\" ${{#b}} \"$b\"; \
+             cat > '{}'",
+            initialized,
+            diagnostics,
+            log.display()
+        )
+    }
+
+    #[test]
+    fn talks_to_a_server_over_stdio() {
+        let dir = std::env::temp_dir().join(format!("editor-lsp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("sent");
+        let uri = uri_for_path(&dir.join("main.rs"));
+
+        let mut server = LanguageServer::start(&mock_server(&uri, &log), &dir).unwrap();
+        server
+            .sync(&uri, "rust", String::from("let foo = 1;\n"))
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !server.receive().unwrap() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let diagnostics = &server.diagnostics[&uri];
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].severity == Severity::Warning);
+        assert_eq!(diagnostics[0].message, "unused variable");
+        assert_eq!(
+            diagnostics[0].columns_on_line(0, RopeSlice::from("let foo = 1;")),
+            Some(4..7)
+        );
+        assert_eq!(diagnostics[0].columns_on_line(1, RopeSlice::from("")), None);
+
+        // Dropping the server asks it to shut down, and closing its input lets it exit.
+        drop(server);
+        let sent = std::fs::read_to_string(&log).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        let methods = [
+            "initialize",
+            "initialized",
+            "textDocument/didOpen",
+            "shutdown",
+            "exit",
+        ];
+        let mut position = 0;
+        for method in methods {
+            let found = sent[position..]
+                .find(&format!(r#""method":"{}""#, method))
+                .unwrap_or_else(|| panic!("{} wasn't sent in order", method));
+            position += found;
+        }
+    }
+}
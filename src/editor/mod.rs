@@ -1,399 +1,4125 @@
 mod buffer;
+mod complete;
+mod config;
+mod debounce;
+mod editorconfig;
+#[cfg(feature = "lsp")]
+mod json;
+mod logger;
+#[cfg(feature = "lsp")]
+mod lsp;
+#[cfg(feature = "spell")]
+mod spell;
+mod theme;
 
-use buffer::Buffer;
+pub use buffer::{BinaryFileError, LargeFileError};
+
+use buffer::{
+    Buffer, CaseOp, CharSearch, CleanOptions, LineChange, LineEnding, SortOrder, SyncError,
+};
+use complete::{BufferWords, Completer, common_prefix};
+use config::{Config, LineNumbers};
 use crossterm::{
-    cursor::{Hide, MoveTo, Show},
-    event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, read},
+    cursor::{Hide, MoveTo, SetCursorStyle, Show},
+    event::{
+        DisableBracketedPaste, DisableFocusChange, DisableMouseCapture, EnableBracketedPaste,
+        EnableFocusChange, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind,
+        KeyModifiers, MouseEventKind, poll, read,
+    },
     execute,
-    style::{Color::*, ResetColor, SetBackgroundColor, SetForegroundColor},
+    style::{
+        Attribute, Color, Color::*, ResetColor, SetAttribute, SetBackgroundColor,
+        SetForegroundColor, SetUnderlineColor,
+    },
     terminal::{
-        Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode,
-        enable_raw_mode, size,
+        Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen, ScrollDown, ScrollUp,
+        SetTitle, disable_raw_mode, enable_raw_mode, size,
     },
 };
+use debounce::Debounced;
+use logger::Logger;
+use std::{
+    cmp::min,
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::{Path, PathBuf},
+};
 use std::{
-    cmp::max,
     io::{Stdout, Write, stdout},
+    time::Duration,
 };
-use std::{cmp::min, path::PathBuf};
-use unicode_width::UnicodeWidthStr;
+use theme::{ColorSupport, Theme};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Where the editor draws: the terminal, or memory for a headless editor.
+enum Output {
+    Terminal(Stdout),
+    #[cfg(test)]
+    Memory(Vec<u8>),
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::Terminal(stdout) => stdout.write(buf),
+            #[cfg(test)]
+            Output::Memory(bytes) => bytes.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Terminal(stdout) => stdout.flush(),
+            #[cfg(test)]
+            Output::Memory(_) => Ok(()),
+        }
+    }
+}
+
+/// A single character drawn in the text area, along with its colours.
+#[derive(Clone, Copy, PartialEq)]
+struct Cell {
+    ch: char,
+    bg: Option<Color>,
+    fg: Option<Color>,
+    underline: Option<Color>,
+}
+
+/// What the text area looked like when it was last drawn, so rows that haven't changed needn't be
+/// sent to the terminal again.
+#[derive(Default)]
+struct DrawnText {
+    /// The first screen row, number of rows and width of the text area.
+    area: (usize, usize, usize),
+    /// The buffer line shown on the first row.
+    origin_row: usize,
+    /// The cells on each row, or None if what's on the row isn't known.
+    rows: Vec<Option<Vec<Cell>>>,
+}
+
+/// A row of the text area, used by `Editor::scroll_cursor_to` and `Editor::move_cursor_to`.
+pub enum ScreenPosition {
+    Top,
+    Center,
+    Bottom,
+}
+
+/// Whether keys type text or run commands, when `modal` editing is enabled.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    Normal,
+    Insert,
+}
 
 /// Main editor data structure.
 pub struct Editor {
-    /// Main text buffer. One buffer represents one open file. Currently a single editor contains
-    /// only a single buffer.
-    pub buffer: Buffer,
+    /// Open text buffers. One buffer represents one open file. There is always at least one.
+    pub buffers: Vec<Buffer>,
+
+    /// Index into `buffers` of the buffer being shown and edited.
+    pub active_buffer: usize,
+
+    /// Index into `buffers` of the buffer that was active before the current one, which
+    /// `alternate_buffer` switches back to.
+    previous_buffer: Option<usize>,
 
     /// Text to be displayed in the footer.
     pub footer_text: String,
 
-    stdout: Stdout,
-}
+    /// Options controlling editor behaviour.
+    pub config: Config,
 
-impl Editor {
-    /// The string shown on an out-of-bounds line.
-    const EMPTY_LINE_NOTATION: &str = "~";
-    const TAB_WIDTH: usize = 4;
+    /// Colours used when drawing.
+    pub theme: Theme,
 
-    pub fn from_path(path: PathBuf) -> Result<Self, std::io::Error> {
-        let buffer = match Buffer::from_path(path) {
-            Ok(buf) => buf,
-            Err(err) => return Err(err),
-        };
-        let mut stdout = stdout();
-        enable_raw_mode()?;
-        execute!(stdout, EnterAlternateScreen)?;
-        return Ok(Editor {
-            buffer: buffer,
-            footer_text: String::from(""),
-            stdout,
-        });
-    }
+    /// The colours the terminal can draw. Theme colours it can't draw are replaced with the
+    /// nearest ones it can.
+    color_support: ColorSupport,
 
-    /// Renders the entire editor to stdout. This is the only `render` function that should be
-    /// called in `main.rs`.
-    pub fn render(&mut self) -> std::io::Result<()> {
-        execute!(self.stdout, Hide)?; // Hide the cursor while drawing.
+    /// Clipboard registers, keyed by name. `UNNAMED_REGISTER` is used unless another is chosen.
+    pub registers: HashMap<char, String>,
 
-        let (_, rows) = size().unwrap();
+    /// Registers holding whole lines, which are pasted as lines above or below the cursor's line
+    /// rather than at the cursor.
+    linewise_registers: HashSet<char>,
 
-        if rows >= 3 {
-            // -1 for the footer bar and -1 for the buffer status bar.
-            for i in 0..((self.buffer.visual_height - 2) as usize) {
-                let line_idx = self.buffer.visual_origin_row + i;
+    /// The text most recently searched for, used by `find_next`.
+    pub last_search: Option<String>,
 
-                let mut text: String;
+    /// The key events recorded so far while a macro is being recorded, or None if one isn't.
+    recording: Option<Vec<KeyEvent>>,
 
-                if line_idx < self.buffer.len_lines() {
-                    // Fetch the line from from the buffer and strip the trailing newline.
-                    text = self.buffer.get_line(line_idx);
+    /// The most recently recorded macro.
+    pub last_macro: Vec<KeyEvent>,
 
-                    // Remove `n` characters from the front of the line, where `n` is
-                    // buffer.visual_origin_col.
-                    text = text.chars().skip(self.buffer.visual_origin_col).collect();
+    /// Set while a macro is being replayed, so prompts can't wait for input that isn't coming.
+    replaying: bool,
 
-                    // Replace tab characters with spaces when rendering.
-                    text = text.replace('\t', &" ".repeat(Editor::TAB_WIDTH));
-                } else {
-                    text = Editor::EMPTY_LINE_NOTATION.to_owned();
-                }
+    /// The current editing mode. Always Insert unless `modal` is enabled.
+    pub mode: Mode,
 
-                // Remove line feeds and carriage returns, in that order.
-                if text.ends_with('\n') {
-                    text.pop();
-                }
+    /// A normal-mode operator waiting for its second key, like the first `d` of `dd`.
+    pending_operator: Option<char>,
 
-                if text.ends_with('\r') {
-                    text.pop();
-                }
+    /// The last character search made with `f`, `F`, `t` or `T`, which `;` and `,` repeat.
+    last_char_search: Option<CharSearch>,
 
-                // If the resulting string is longer than the width of the display, trim it.
-                if text.chars().count() > self.buffer.visual_width {
-                    text = text.chars().take(self.buffer.visual_width).collect();
-                }
+    /// The number of times to repeat the next normal-mode command, typed before it as in `5j`.
+    pending_count: Option<usize>,
 
-                // If the resulting string is shorter than the width of the display, pad it.
-                if text.chars().count() < self.buffer.visual_width {
-                    text += &(" ".repeat(self.buffer.visual_width - text.width_cjk()));
-                }
+    /// Insert typed keys exactly as they are, without auto-indenting or the other help typing gets,
+    /// until Esc is pressed. For pasting into terminals that don't mark pasted text as such.
+    paste_mode: bool,
 
-                execute!(self.stdout, MoveTo(0, i as u16))?;
-                write!(self.stdout, "{}", text)?;
-            }
+    /// The register chosen for the next yank or paste, if any.
+    pending_register: Option<char>,
+
+    /// The working directory when the editor started, which new files are saved relative to.
+    startup_dir: PathBuf,
+
+    /// The diff gutter markers last computed, and the index of the buffer they're for. They're
+    /// only recomputed once typing pauses.
+    line_changes: Debounced<(usize, Vec<Option<LineChange>>)>,
+
+    /// The cursor shape most recently set, if any.
+    cursor_style: Option<SetCursorStyle>,
+
+    /// The terminal window title most recently set, if any.
+    window_title: Option<String>,
+
+    /// The contents of the text area as last drawn.
+    drawn: DrawnText,
+
+    /// The log of handled keys, if `--log` was given.
+    logger: Option<Logger>,
+
+    /// The dictionary used for spell checking, loaded the first time it's needed.
+    #[cfg(feature = "spell")]
+    dictionary: Option<spell::Dictionary>,
+
+    /// The language servers started so far, keyed by the command that started them. A server that
+    /// couldn't be started or has exited is kept as None, so it isn't started again.
+    #[cfg(feature = "lsp")]
+    language_servers: HashMap<String, Option<lsp::LanguageServer>>,
+
+    /// The diagnostic message shown in the footer, so it can be cleared once the cursor leaves it.
+    #[cfg(feature = "lsp")]
+    shown_diagnostic: Option<String>,
+
+    /// Overrides the size reported by the terminal, for running without one.
+    terminal_size: Option<(u16, u16)>,
+
+    /// Events to read in place of the terminal's input, for headless editors.
+    #[cfg(test)]
+    scripted_input: Option<std::collections::VecDeque<Event>>,
+
+    output: Output,
+}
+
+impl Editor {
+    /// The number of columns on the right of the text area taken up by the minimap.
+    const MINIMAP_WIDTH: usize = 8;
+    /// The number of non-whitespace characters each filled minimap cell stands for.
+    const MINIMAP_CHARS_PER_CELL: usize = 8;
+    /// The register used by yank and paste when no other register has been chosen.
+    const UNNAMED_REGISTER: char = '"';
+    /// How long input has to pause before decorations such as the diff gutter are recomputed.
+    const DECORATION_DELAY: Duration = Duration::from_millis(150);
+    /// How often to check for messages from language servers while they're running.
+    #[cfg(feature = "lsp")]
+    const LANGUAGE_SERVER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+    /// How often open files are checked for changes made by other programs while input is idle.
+    const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Opens the file at `path` with the options from the config file, and sets the terminal up
+    /// for editing it. Unless `open_large_file` is set, a file larger than `large_file_threshold`
+    /// isn't loaded, and the error wraps a `LargeFileError`. A file that looks binary is refused
+    /// the same way with a `BinaryFileError`. If `forced_size` (columns, rows) is given, the editor
+    /// is drawn at that size rather than the terminal's, and resize events are ignored, which is
+    /// useful for reproducible output in scripted runs.
+    pub fn from_path(
+        path: PathBuf,
+        open_large_file: bool,
+        forced_size: Option<(u16, u16)>,
+    ) -> Result<Self, std::io::Error> {
+        let (config, config_errors) = Config::load();
+        let mut settings = config.buffer_settings.clone();
+        if open_large_file {
+            settings.large_file_threshold = None;
         }
-        if rows >= 2 {
-            self.render_status_bar()?;
+        let buffer = Buffer::from_path(path, settings, &config.filetype_overrides)?;
+        let mut editor = Editor::new(buffer, config, forced_size);
+        if let Some(err) = config_errors.first() {
+            editor.footer_text = format!("Config file: {}", err);
+        } else if let Some(warning) = editor.mixed_line_endings_warning() {
+            editor.footer_text = warning;
         }
-        if rows >= 1 {
-            self.render_footer_bar()?;
+        enable_raw_mode()?;
+        editor.enter_screen()?;
+        execute!(editor.output, EnableFocusChange, EnableBracketedPaste)?;
+        if editor.config.mouse_scroll {
+            execute!(editor.output, EnableMouseCapture)?;
         }
-        execute!(
-            self.stdout,
-            MoveTo(
-                self.buffer.get_visual_cursor_col() as u16,
-                self.buffer.get_visual_cursor_line() as u16
-            )
-        )?;
-        execute!(self.stdout, Show)?; // Show the cursor again once we've finished drawing.
-
-        Ok(())
+        Ok(editor)
     }
 
-    fn render_status_bar(&mut self) -> std::io::Result<()> {
-        let (cols, rows) = size()?;
+    /// Gets the screen ready to draw on. With `use_alternate_screen` off, the shell's output is
+    /// scrolled up into the scrollback rather than cleared, leaving the screen blank to draw in.
+    fn enter_screen(&mut self) -> std::io::Result<()> {
+        if self.config.use_alternate_screen {
+            execute!(self.output, EnterAlternateScreen)
+        } else {
+            let (_, rows) = self.terminal_size()?;
+            write!(self.output, "{}", "\r\n".repeat(rows as usize))?;
+            execute!(self.output, MoveTo(0, 0))
+        }
+    }
 
-        // We only want to render the status bar if there are 2 or more rows being rendered to the
-        // screen.
-        if rows < 2 {
-            return Ok(());
+    /// Undoes `enter_screen`. With `use_alternate_screen` off, only the rows the editor drew on are
+    /// cleared, and the cursor is left at the top of them, just below the shell's earlier output.
+    fn leave_screen(&mut self) -> std::io::Result<()> {
+        if self.config.use_alternate_screen {
+            execute!(self.output, LeaveAlternateScreen)
+        } else {
+            execute!(self.output, MoveTo(0, 0), Clear(ClearType::FromCursorDown))
         }
+    }
 
-        let text = self.buffer.get_status_bar_text();
-        let blank_space = cols - min(text.len() as u16, cols);
+    /// Moves the cursor to column `col` of line `line` of the active buffer, both counted from 0,
+    /// and centres the view on it.
+    pub fn go_to_position(&mut self, line: usize, col: usize) {
+        self.buffer_mut().go_to_position(line, col);
+        self.scroll_cursor_to(ScreenPosition::Center);
+    }
 
-        execute!(self.stdout, MoveTo(0, rows - 2))?;
-        write!(self.stdout, "{}{}", text, " ".repeat(blank_space as usize))?;
+    /// Starts logging every handled key to the file at `path`.
+    pub fn enable_logging(&mut self, path: &Path) -> std::io::Result<()> {
+        self.logger = Some(Logger::open(path)?);
         Ok(())
     }
 
-    /// Draws the footer bar. The footer bar is a property of the entire editor rather than a single
-    /// buffer.
-    fn render_footer_bar(&mut self) -> std::io::Result<()> {
-        let (cols, rows) = size()?;
-        execute!(self.stdout, MoveTo(0, rows - 1))?;
-        execute!(
-            self.stdout,
-            SetBackgroundColor(White),
-            SetForegroundColor(Black)
-        )?;
+    /// Creates an editor around `buffer` without touching the terminal: raw mode and the alternate
+    /// screen are left alone, `size` (columns, rows) is used in place of the terminal's size, and
+    /// drawing goes to memory. Prompts read from `script_input` rather than the terminal. This lets
+    /// tests feed key events to `handle_key_event` and check `buffer`, `footer_text` and what was
+    /// drawn.
+    #[cfg(test)]
+    pub fn new_headless(buffer: Buffer, size: (u16, u16)) -> Self {
+        let mut editor = Editor::new(buffer, Config::default(), Some(size));
+        editor.output = Output::Memory(Vec::new());
+        editor.scripted_input = Some(std::collections::VecDeque::new());
+        editor
+    }
 
-        let footer_bar = &self.footer_text;
+    /// Queues `events` to be read by a headless editor, such as the answers to its prompts.
+    #[cfg(test)]
+    pub fn script_input(&mut self, events: impl IntoIterator<Item = Event>) {
+        self.scripted_input.get_or_insert_default().extend(events);
+    }
 
-        let message_len = min(footer_bar.len() as u16, cols);
+    /// Takes what a headless editor has drawn since this was last called.
+    #[cfg(test)]
+    pub fn take_output(&mut self) -> String {
+        match &mut self.output {
+            Output::Memory(bytes) => String::from_utf8_lossy(&std::mem::take(bytes)).into_owned(),
+            Output::Terminal(_) => String::new(),
+        }
+    }
 
-        let footer_text: String = footer_bar.chars().take(message_len as usize).collect();
+    /// Reads the next event from the terminal, or from the scripted input of a headless editor.
+    /// A headless editor that has run out of input reads an error, which cancels prompts.
+    fn read_event(&mut self) -> std::io::Result<Event> {
+        #[cfg(test)]
+        if let Some(input) = &mut self.scripted_input {
+            return input.pop_front().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "no more scripted input")
+            });
+        }
+        read()
+    }
 
-        let blank_space = cols - message_len;
-        write!(
-            self.stdout,
-            "{}{}",
-            footer_text,
-            " ".repeat(blank_space as usize)
-        )?;
-        execute!(self.stdout, ResetColor)?;
-        Ok(())
+    /// Creates an editor around `buffer`. If `terminal_size` is given, it's used in place of the
+    /// terminal's size, and the buffer is sized to it.
+    fn new(buffer: Buffer, config: Config, terminal_size: Option<(u16, u16)>) -> Self {
+        let mode = if config.modal {
+            Mode::Normal
+        } else {
+            Mode::Insert
+        };
+        let mut editor = Editor {
+            buffers: vec![buffer],
+            active_buffer: 0,
+            previous_buffer: None,
+            footer_text: String::from(""),
+            config,
+            theme: Theme::default(),
+            color_support: ColorSupport::detect(),
+            last_search: None,
+            registers: HashMap::new(),
+            linewise_registers: HashSet::new(),
+            recording: None,
+            last_macro: Vec::new(),
+            replaying: false,
+            mode,
+            pending_operator: None,
+            last_char_search: None,
+            pending_count: None,
+            pending_register: None,
+            paste_mode: false,
+            startup_dir: std::env::current_dir().unwrap_or_default(),
+            line_changes: Debounced::new(Editor::DECORATION_DELAY),
+            cursor_style: None,
+            window_title: None,
+            #[cfg(feature = "spell")]
+            dictionary: None,
+            #[cfg(feature = "lsp")]
+            language_servers: HashMap::new(),
+            #[cfg(feature = "lsp")]
+            shown_diagnostic: None,
+            logger: None,
+            drawn: DrawnText::default(),
+            terminal_size,
+            #[cfg(test)]
+            scripted_input: None,
+            output: Output::Terminal(stdout()),
+        };
+        if let Some((cols, rows)) = terminal_size {
+            editor.resize(cols, rows);
+        }
+        editor
     }
 
-    fn save_buffer(&mut self) {
-        // If the buffer does not have a file path, prompt the user for one.
-        if self.buffer.file_path.as_os_str().is_empty() {
-            let new_filename = self.editor_prompt("Enter new filename> ");
-            match new_filename {
-                Some(name) => {
-                    self.buffer.file_path.push(&name);
-                    match self.buffer.save_file() {
-                        Ok(()) => self.footer_text = format!("New file saved as {}", &name),
-                        Err(_) => self.footer_text = format!("File save failed. Please try again."),
-                    }
+    /// Returns the size of the terminal as (columns, rows), or the size the editor was forced to.
+    fn terminal_size(&self) -> std::io::Result<(u16, u16)> {
+        match self.terminal_size {
+            Some(size) => Ok(size),
+            None => size(),
+        }
+    }
+
+    /// The buffer being shown and edited.
+    pub fn buffer(&self) -> &Buffer {
+        &self.buffers[self.active_buffer]
+    }
+
+    /// The buffer being shown and edited.
+    pub fn buffer_mut(&mut self) -> &mut Buffer {
+        &mut self.buffers[self.active_buffer]
+    }
+
+    /// Opens the file at `path` in a new buffer and makes it the active one. Asks first if the file
+    /// is larger than `large_file_threshold`.
+    pub fn open_buffer(&mut self, path: PathBuf) {
+        let mut settings = self.config.buffer_settings.clone();
+        let mut result = Buffer::from_path(
+            path.clone(),
+            settings.clone(),
+            &self.config.filetype_overrides,
+        );
+        if let Err(err) = &result
+            && let Some(large) = LargeFileError::from_io_error(err)
+        {
+            let prompt = format!(
+                "This file is large ({}). Open anyway? (y/n): ",
+                large.readable_size()
+            );
+            if !matches!(
+                self.editor_prompt(&prompt).as_deref(),
+                Some("y" | "Y" | "yes")
+            ) {
+                self.footer_text = String::from("Cancelled.");
+                return;
+            }
+            settings.large_file_threshold = None;
+            result = Buffer::from_path(path, settings, &self.config.filetype_overrides);
+        }
+
+        match result {
+            Ok(mut buffer) => {
+                buffer.visual_width = self.buffer().visual_width;
+                buffer.visual_height = self.buffer().visual_height;
+                self.buffers.push(buffer);
+                self.set_active_buffer(self.buffers.len() - 1);
+                self.footer_text = format!("Opened {}.", self.buffer().file_name());
+                if let Some(warning) = self.mixed_line_endings_warning() {
+                    self.footer_text = format!("{} {}", self.footer_text, warning);
                 }
-                None => self.footer_text = String::from("No file name given, cancelled save."),
             }
-        } else {
-            match self.buffer.save_file() {
-                Ok(_) => self.footer_text = format!("File saved."),
-                Err(_) => self.footer_text = format!("File save failed. Please try again."),
+            Err(err) if BinaryFileError::from_io_error(&err).is_some() => {
+                self.footer_text = String::from("File appears to be binary, not opened.")
             }
+            Err(_) => self.footer_text = String::from("Could not read file."),
         }
     }
 
-    /// If the buffer is dirty, we need to ask the user whether they really meant to exit without
-    /// saving. Otherwise, just exit.
-    fn attempt_exit(&mut self) -> bool {
-        if self.buffer.dirty_buffer {
-            let response =
-                self.editor_prompt("The buffer is unsaved. Do you really want to exit? (y/n): ");
-            match response {
-                Some(str) => {
-                    if str == "y" || str == "Y" || str == "yes" {
-                        return true;
-                    } else {
+    /// Returns a warning for the footer if the active buffer was loaded with both LF and CRLF line
+    /// endings, since saving will make them all the same.
+    fn mixed_line_endings_warning(&self) -> Option<String> {
+        let buffer = self.buffer();
+        buffer.mixed_line_endings.then(|| {
+            let name = match buffer.line_ending {
+                LineEnding::Lf => "LF",
+                LineEnding::Crlf => "CRLF",
+            };
+            format!(
+                "The file mixes LF and CRLF line endings; saving will use {}.",
+                name
+            )
+        })
+    }
+
+    /// Opens the file named by the path under the cursor in a new buffer. Relative paths are
+    /// resolved against the directory of the current file.
+    pub fn open_path_under_cursor(&mut self) {
+        let Some(token) = self.buffer().path_under_cursor() else {
+            self.footer_text = String::from("No file name under cursor.");
+            return;
+        };
+
+        let path = self.buffer().resolve_relative_path(&token);
+        if path.is_file() {
+            self.open_buffer(path);
+        } else {
+            self.footer_text = format!("File not found: {}", path.display());
+        }
+    }
+
+    /// Closes the active buffer, asking whether to save it first if it's dirty, and falls back to
+    /// the next buffer. Closing the last buffer leaves an empty unnamed buffer, unless
+    /// `quit_on_last_buffer_close` is set. Returns true if the user wants to quit, false otherwise.
+    pub fn close_active_buffer(&mut self) -> bool {
+        if self.buffer().dirty_buffer {
+            match self.editor_prompt("The buffer is unsaved. Save before closing? (y/n): ") {
+                Some(str) if str == "y" || str == "Y" || str == "yes" => {
+                    self.save_buffer();
+                    // Don't throw away the changes if the save failed or was cancelled.
+                    if self.buffer().dirty_buffer {
                         return false;
                     }
                 }
+                Some(_) => {}
                 None => {
+                    self.footer_text = String::from("Close cancelled.");
                     return false;
                 }
             }
-        } else {
-            return true;
         }
-    }
-
-    /// Returns true if the user wants to quit, false otherwise.
-    pub fn handle_key_event(&mut self, key_event: KeyEvent) -> bool {
-        if key_event.kind == KeyEventKind::Press {
-            // Handle Ctrl-<X>
-            if key_event.modifiers.contains(KeyModifiers::CONTROL) {
-                match key_event.code {
-                    KeyCode::Char('d') => {
-                        return self.attempt_exit();
-                    }
-                    KeyCode::Char('s') => {
-                        self.save_buffer();
-                    }
-                    KeyCode::Char('f') => {
-                        let target = match self.editor_prompt("Enter target text> ") {
-                            Some(text) => text,
-                            None => {
-                                self.footer_text = String::from("Search cancelled.");
-                                return false;
-                            }
-                        };
 
-                        let found = self.buffer.go_to_next_instance(&target);
-
-                        if !found {
-                            let user_response =
-                                match self.editor_prompt("No match found. Search from top? y/n> ") {
-                                    Some(text) => text,
-                                    None => {
-                                        self.footer_text = String::from("Search cancelled.");
-                                        return false;
-                                    }
-                                };
-                            if user_response == "y" {
-                                let past_cursor_idx = self.buffer.cursor_idx;
-                                self.buffer.cursor_idx = 0;
-                                let found = self.buffer.go_to_next_instance(&target);
-                                if !found {
-                                    self.buffer.cursor_idx = past_cursor_idx;
-                                    self.footer_text = String::from("No match found.");
-                                } else {
-                                    self.footer_text = String::from("Match found.");
-                                }
-                            }
-                        } else {
-                            self.footer_text = String::from("Match found.");
-                        }
-                    }
-                    _ => self.buffer.handle_key_event(key_event),
-                }
-            } else {
-                match key_event.code {
-                    KeyCode::F(10) => return true,
-                    // KeyCode::F(1) => {
-                    //     let user_text = self.editor_prompt("> ");
-                    //     match user_text {
-                    //         Some(text) => {
-                    //             self.footer_text = format!("You entered a command: {}", text)
-                    //         }
-                    //         None => {}
-                    //     }
-                    // }
-                    _ => {
-                        self.buffer.handle_key_event(key_event);
-                    }
+        if self.buffers.len() > 1 {
+            self.remove_buffer(self.active_buffer);
+        } else if self.config.quit_on_last_buffer_close {
+            return true;
+        } else {
+            match Buffer::from_path(
+                PathBuf::new(),
+                self.config.buffer_settings.clone(),
+                &self.config.filetype_overrides,
+            ) {
+                Ok(mut buffer) => {
+                    buffer.visual_width = self.buffer().visual_width;
+                    buffer.visual_height = self.buffer().visual_height;
+                    self.buffers[0] = buffer;
                 }
+                Err(_) => self.footer_text = String::from("Could not create a new buffer."),
             }
         }
         false
     }
 
-    /// Ensures the cursor remains on screen at all times by moving the viewport if the cursor has
-    /// gone out-of-bounds since the last input event.
-    pub fn align_cursor(&mut self) {
-        let (_, rows) = size().unwrap();
+    /// Makes the buffer `offset` places after the active one active, wrapping around at the ends.
+    pub fn cycle_buffer(&mut self, offset: isize) {
+        let count = self.buffers.len() as isize;
+        self.set_active_buffer((self.active_buffer as isize + offset).rem_euclid(count) as usize);
+    }
 
-        // It doesn't matter where the cursor is in this case because no part of the buffer will be
-        // shown on-screen.
-        if rows < 3 {
-            return;
+    /// Switches back to the buffer that was active before the current one.
+    pub fn alternate_buffer(&mut self) {
+        match self.previous_buffer {
+            Some(idx) if idx != self.active_buffer => self.set_active_buffer(idx),
+            _ => self.footer_text = String::from("No other buffer to switch to."),
         }
+    }
 
-        let line_idx = self.buffer.char_to_line(self.buffer.cursor_idx);
-        let col_idx = self.buffer.cursor_idx - self.buffer.line_to_char(line_idx);
-
-        // If the cursor is above the first visual line, then set the line the cursor is on to be
-        // the first visual line.
-        if line_idx < self.buffer.visual_origin_row {
-            self.buffer.visual_origin_row = line_idx;
+    /// Switches to the buffer at index `idx`, auto-saving the buffer being left if enabled.
+    fn set_active_buffer(&mut self, idx: usize) {
+        if idx != self.active_buffer {
+            if self.config.save_on_focus_lost {
+                self.autosave(self.active_buffer..self.active_buffer + 1);
+            }
+            self.previous_buffer = Some(self.active_buffer);
         }
+        self.active_buffer = idx;
+    }
 
-        // Similarly, if the cursor is below the last line, then the last line needs to be the line
-        // the cursor is on. NOTE: the `-2` in the conditional is to ensure the cursor doesn't enter
-        // the status bar or the footer bar.
-        if line_idx >= self.buffer.visual_origin_row + self.buffer.visual_height - 2 {
-            self.buffer.visual_origin_row = line_idx - (self.buffer.visual_height - 3);
+    /// Removes the buffer at index `idx`, which mustn't be the only one. The active buffer stays the
+    /// same if it's still open, and otherwise becomes the buffer that took the removed one's place.
+    /// The previous buffer stays the same if it's still open and not the active one, and otherwise
+    /// becomes the nearest buffer to it that isn't active, if there is one.
+    fn remove_buffer(&mut self, idx: usize) {
+        self.buffers.remove(idx);
+        let shift = |other: usize| if other > idx { other - 1 } else { other };
+        self.active_buffer = min(shift(self.active_buffer), self.buffers.len() - 1);
+        self.previous_buffer = self.previous_buffer.map(shift).and_then(|previous| {
+            (0..self.buffers.len())
+                .filter(|&other| other != self.active_buffer)
+                .min_by_key(|&other| other.abs_diff(previous))
+        });
+    }
+
+    /// Checks every open file for changes made on disk, mentioning it in the footer if the active
+    /// buffer's file has just been found to have changed.
+    fn check_disk_changes(&mut self) {
+        for (idx, buffer) in self.buffers.iter_mut().enumerate() {
+            if buffer.check_disk_changes() && idx == self.active_buffer {
+                self.footer_text = String::from("The file has changed on disk.");
+            }
         }
+    }
 
-        // If the cursor is left of the first column being displayed, then the first column needs to
-        // be the column that the cursor is on.
-        if col_idx < self.buffer.visual_origin_col {
-            self.buffer.visual_origin_col = col_idx;
+    /// Saves the dirty buffers with indices in `indices` and reports what happened in the footer.
+    /// Buffers without a file name are skipped, since we don't want to prompt for one here.
+    fn autosave(&mut self, indices: Range<usize>) {
+        let mut saved = 0;
+        let mut failed = 0;
+        let mut unnamed = 0;
+        let mut changed = 0;
+        for buffer in &mut self.buffers[indices] {
+            if !buffer.dirty_buffer {
+                continue;
+            }
+            buffer.check_disk_changes();
+            if buffer.file_path.as_os_str().is_empty() {
+                unnamed += 1;
+            } else if buffer.file_changed_externally {
+                // Leave it to the user to decide whether to overwrite the changes on disk.
+                changed += 1;
+            } else if buffer.save_file().is_ok() {
+                saved += 1;
+            } else {
+                failed += 1;
+            }
         }
 
-        // And finally, if the cursor is right of the last column being displayed, then the last
-        // line needs to be the line that the cursor is on.
-        if col_idx >= self.buffer.visual_origin_col + self.buffer.visual_width {
-            self.buffer.visual_origin_col = col_idx - self.buffer.visual_width + 1;
+        let mut messages = Vec::new();
+        if saved > 0 {
+            messages.push(format!("Auto-saved {} buffer(s).", saved));
+        }
+        if failed > 0 {
+            messages.push(format!("Auto-save failed for {} buffer(s).", failed));
+        }
+        if unnamed > 0 {
+            messages.push(format!("Skipped {} unnamed buffer(s).", unnamed));
+        }
+        if changed > 0 {
+            messages.push(format!("Skipped {} buffer(s) changed on disk.", changed));
+        }
+        if !messages.is_empty() {
+            self.footer_text = messages.join(" ");
         }
     }
 
-    pub fn clear_terminal(&mut self) -> std::io::Result<()> {
-        execute!(self.stdout, Clear(ClearType::All))?;
-        execute!(self.stdout, MoveTo(0, 0))?;
-        self.stdout.flush()?;
-        Ok(())
+    /// Returns the number of rows at the top of the screen used by the tab bar. The tab bar is only
+    /// shown when more than one buffer is open.
+    fn text_area_top(&self) -> usize {
+        if self.buffers.len() > 1 { 1 } else { 0 }
     }
 
-    /// Prompt the user for some input, and return that input as a string. The prompt will appear in
-    /// the footer bar, a la Vim.
-    pub fn editor_prompt(&mut self, prompt_text: &str) -> Option<String> {
-        self.footer_text = prompt_text.to_owned();
-        let mut user_input = String::new();
+    /// Returns the number of rows available for drawing buffer text, leaving room for the tab bar,
+    /// the status bar and the footer bar.
+    fn text_area_height(&self) -> usize {
+        self.buffer()
+            .visual_height
+            .saturating_sub(2 + self.text_area_top())
+    }
+
+    /// Renders the entire editor to stdout. This is the only `render` function that should be
+    /// called in `main.rs`.
+    pub fn render(&mut self) -> std::io::Result<()> {
+        execute!(self.output, Hide)?; // Hide the cursor while drawing.
+        self.buffer_mut().clamp_cursor();
 
-        let (cols, _) = size().unwrap();
+        let (_, rows) = self.terminal_size()?;
+        let cursor_line = self.buffer().get_logical_cursor_line();
+        let top = self.text_area_top();
+        let text_rows = self.text_area_height();
 
-        loop {
-            self.footer_text = format!("{}{}", prompt_text, user_input);
-            self.render().ok();
-            let _ = execute!(self.stdout, MoveTo(self.footer_text.len() as u16, cols - 1));
-            let _ = self.stdout.flush();
+        if top > 0 && rows as usize >= 3 + top {
+            self.render_tab_bar()?;
+        }
+        if text_rows > 0 {
+            let changes = if self.config.diff_gutter {
+                self.diff_gutter_changes()
+            } else {
+                Vec::new()
+            };
+            let area = (
+                top,
+                text_rows,
+                self.text_area_left() + self.text_area_width(),
+            );
+            let origin_row = self.buffer().visual_origin_row;
+            if self.drawn.area != area {
+                self.drawn = DrawnText {
+                    area,
+                    origin_row,
+                    rows: vec![None; text_rows],
+                };
+            } else if origin_row != self.drawn.origin_row {
+                self.scroll_text_area(origin_row)?;
+            }
 
-            match read() {
-                Ok(Event::Key(key_event)) if key_event.kind == KeyEventKind::Press => {
-                    match key_event.code {
-                        KeyCode::Char(x) => {
-                            user_input.push(x);
-                        }
-                        KeyCode::Backspace => {
-                            user_input.pop();
-                        }
-                        KeyCode::Enter => {
-                            self.footer_text.clear();
-                            return Some(user_input);
-                        }
-                        KeyCode::Esc => {
-                            self.footer_text.clear();
-                            return None;
-                        }
-                        _ => {}
-                    }
-                }
-                Err(_) => return None,
-                _ => {}
+            let focused = self.focused_lines();
+            let underlines = self.diagnostic_underlines(origin_row..origin_row + text_rows);
+            for (i, underlines) in underlines.into_iter().enumerate() {
+                let line_idx = origin_row + i;
+                let change = changes.get(line_idx).copied().flatten();
+                let dimmed = focused
+                    .as_ref()
+                    .is_some_and(|focused| !focused.contains(&line_idx));
+                self.render_text_row(top + i, line_idx, cursor_line, change, dimmed, underlines)?;
+            }
+            if self.config.minimap {
+                self.render_minimap(top, text_rows)?;
+            }
+        }
+        if rows >= 2 {
+            let (mut left, right) = self.buffer().get_status_bar_text();
+            if self.config.modal {
+                left.insert_str(
+                    0,
+                    if self.mode == Mode::Normal {
+                        "NORMAL "
+                    } else {
+                        "INSERT "
+                    },
+                );
             }
+            if self.recording.is_some() {
+                left.insert_str(0, "REC ");
+            }
+            if self.paste_mode {
+                left.insert_str(0, "PASTE ");
+            }
+            self.render_status_bar(&left, &right)?;
+        }
+        if rows >= 1 {
+            self.render_footer_bar()?;
         }
+        let cursor_col = self.text_area_left() + self.buffer().get_visual_cursor_col();
+        let cursor_row = top + self.buffer().get_visual_cursor_line();
+        execute!(self.output, MoveTo(cursor_col as u16, cursor_row as u16))?;
+        self.update_cursor_style()?;
+        execute!(self.output, Show)?; // Show the cursor again once we've finished drawing.
+
+        Ok(())
     }
 
-    pub fn mainloop(&mut self) -> std::io::Result<()> {
-        loop {
-            self.render()?;
-            self.stdout.flush()?;
-            match read() {
-                Ok(Event::Key(key_event)) => {
-                    let quit = self.handle_key_event(key_event);
-                    if quit {
-                        break;
-                    }
-                }
-                Ok(Event::Resize(w, h)) => {
-                    self.buffer.visual_width = w as usize;
-                    self.buffer.visual_height = max(h, 0) as usize;
-                }
-                Err(err) => {
-                    return Err(err);
-                }
-                _ => {}
-            }
+    /// Returns the indices of the lines left undimmed by focus mode: the paragraph the cursor is
+    /// in, or just the cursor's line if it's blank. Returns None if focus mode is off.
+    fn focused_lines(&self) -> Option<Range<usize>> {
+        if !self.config.focus_mode {
+            return None;
+        }
+        let lines = self.buffer().paragraph_at_cursor();
+        if lines.is_empty() {
+            let cursor_line = self.buffer().get_logical_cursor_line();
+            return Some(cursor_line..cursor_line + 1);
+        }
+        Some(lines)
+    }
 
-            // After every input event, we need to ensure that the cursor remains on screen.
-            self.align_cursor();
+    /// Returns the diff gutter markers for the active buffer. While input is arriving, the markers
+    /// from the last pause are reused rather than diffing again; they're computed straight away
+    /// for a buffer that hasn't had them computed yet.
+    fn diff_gutter_changes(&mut self) -> Vec<Option<LineChange>> {
+        let computed = self
+            .line_changes
+            .get()
+            .is_some_and(|(buffer_idx, _)| *buffer_idx == self.active_buffer);
+        if !computed {
+            self.refresh_decorations();
+        }
+        match self.line_changes.get() {
+            Some((_, changes)) => changes.clone(),
+            None => Vec::new(),
         }
+    }
 
-        disable_raw_mode()?;
-        execute!(self.stdout, LeaveAlternateScreen, Show)?;
+    /// Recomputes the debounced decorations for the active buffer.
+    fn refresh_decorations(&mut self) {
+        let changes = if self.config.diff_gutter {
+            self.buffer_mut().line_changes().to_vec()
+        } else {
+            Vec::new()
+        };
+        self.line_changes.set((self.active_buffer, changes));
+        // Language servers are sent the text at the same points, once typing pauses.
+        #[cfg(feature = "lsp")]
+        self.sync_language_server();
+    }
+
+    /// Sizes every buffer's viewport to a terminal of `cols` columns and `rows` rows.
+    fn resize(&mut self, cols: u16, rows: u16) {
+        // The terminal may have moved or cleared what was on screen.
+        self.drawn = DrawnText::default();
+        for buffer in &mut self.buffers {
+            buffer.visual_width = cols as usize;
+            buffer.visual_height = rows as usize;
+        }
+    }
+
+    /// Clears the screen and forgets what was drawn on it, so the next frame is drawn from scratch
+    /// with the cursor shape and window title set again. The terminal size is checked too, in case
+    /// it changed without a resize event arriving.
+    fn redraw(&mut self) -> std::io::Result<()> {
+        let (cols, rows) = self.terminal_size()?;
+        self.resize(cols, rows);
+        self.cursor_style = None;
+        self.window_title = None;
+        execute!(self.output, ResetColor, Clear(ClearType::All))
+    }
+
+    /// Moves the rows already drawn in the text area to where they belong now that the view starts
+    /// at buffer line `origin_row`. If the view has moved by less than half the text area, the
+    /// terminal is asked to scroll the area, so only the rows scrolled into view need drawing.
+    /// Otherwise nothing is scrolled, and rows are redrawn wherever they differ.
+    fn scroll_text_area(&mut self, origin_row: usize) -> std::io::Result<()> {
+        let (top, text_rows, _) = self.drawn.area;
+        let delta = origin_row.abs_diff(self.drawn.origin_row);
+        let scroll_down = origin_row > self.drawn.origin_row;
+        self.drawn.origin_row = origin_row;
+        if delta == 0 || delta >= text_rows.div_ceil(2) {
+            return Ok(());
+        }
+
+        // Limit scrolling to the text area, so the tab bar and status bar stay put, then scroll
+        // and reset the region to the whole screen.
+        write!(self.output, "\x1b[{};{}r", top + 1, top + text_rows)?;
+        let rows = &mut self.drawn.rows;
+        if scroll_down {
+            execute!(self.output, ScrollUp(delta as u16))?;
+            rows.drain(..delta);
+            rows.extend(std::iter::repeat_n(None, delta));
+        } else {
+            execute!(self.output, ScrollDown(delta as u16))?;
+            rows.truncate(text_rows - delta);
+            rows.splice(0..0, std::iter::repeat_n(None, delta));
+        }
+        write!(self.output, "\x1b[r")?;
+        Ok(())
+    }
+
+    /// Draws line `line_idx` of the buffer on screen row `row`, padded or trimmed to the width of
+    /// the display, with `change` marked in the diff gutter if it's enabled and `underlines` from
+    /// `diagnostic_underlines`. The text is drawn in the unfocused colour if `dimmed` is set.
+    /// Nothing is sent to the terminal if the row already shows exactly that.
+    fn render_text_row(
+        &mut self,
+        row: usize,
+        line_idx: usize,
+        cursor_line: usize,
+        change: Option<LineChange>,
+        dimmed: bool,
+        underlines: Vec<(Range<usize>, Color)>,
+    ) -> std::io::Result<()> {
+        let line_bg = if self.config.cursorline && line_idx == cursor_line {
+            Some(self.theme.cursorline_bg)
+        } else {
+            None
+        };
+        let blank = Cell {
+            ch: ' ',
+            bg: line_bg,
+            fg: None,
+            underline: None,
+        };
+
+        let mut cells: Vec<Cell> = Vec::new();
+        let mut hidden_left = false;
+
+        if line_idx < self.buffer().len_lines() {
+            let origin = self.buffer().visual_origin_col;
+            let line_start = self.buffer().line_to_char(line_idx);
+            let selection = self.buffer().selection_range();
+            let tab_width = self.buffer().settings.tab_display_width;
+            // Only take the part of the line that can be on screen, so very long lines don't cost
+            // more to draw. Every char but a zero-width one takes at least a cell, so twice the
+            // width of the text area leaves plenty of room for those.
+            let window = origin..origin + 2 * self.text_area_width();
+            let misspelled = self.misspelled_ranges(line_idx, window.clone());
+            let overflow_col = self
+                .config
+                .line_length_limit
+                .and_then(|limit| self.buffer().first_char_past_column(line_idx, limit));
+            let trailing_col =
+                if self.config.highlight_trailing_whitespace && line_idx != cursor_line {
+                    self.buffer().trailing_whitespace_start(line_idx)
+                } else {
+                    None
+                };
+
+            let content = self.buffer().get_line_window(line_idx, window);
+            hidden_left = origin > 0 && self.buffer().line_len(line_idx) > 0;
+
+            for (col, ch) in (origin..).zip(content.chars()) {
+                let selected = selection
+                    .as_ref()
+                    .is_some_and(|range| range.contains(&(line_start + col)));
+                let in_occurrence = self
+                    .buffer()
+                    .occurrences
+                    .iter()
+                    .any(|range| range.contains(&(line_start + col)));
+                let bg = if selected {
+                    Some(self.theme.selection_bg)
+                } else if in_occurrence {
+                    Some(self.theme.occurrence_bg)
+                } else if trailing_col.is_some_and(|trailing_col| col >= trailing_col) {
+                    Some(self.theme.trailing_whitespace_bg)
+                } else if overflow_col.is_some_and(|overflow_col| col >= overflow_col) {
+                    Some(self.theme.line_too_long_bg)
+                } else {
+                    line_bg
+                };
+                let fg = if misspelled.iter().any(|range| range.contains(&col)) {
+                    Some(self.theme.misspelled_fg)
+                } else if dimmed {
+                    Some(self.theme.unfocused_fg)
+                } else {
+                    None
+                };
+                let underline = underlines
+                    .iter()
+                    .find(|(range, _)| range.contains(&col))
+                    .map(|(_, color)| *color);
+                let cell = Cell {
+                    ch,
+                    bg,
+                    fg,
+                    underline,
+                };
+
+                // Replace tab characters with spaces when rendering.
+                if ch == '\t' {
+                    cells.extend(std::iter::repeat_n(Cell { ch: ' ', ..cell }, tab_width));
+                } else {
+                    cells.push(cell);
+                }
+            }
+        } else {
+            let fg = self.theme.empty_line_marker_fg;
+            cells.extend(
+                self.config
+                    .empty_line_marker
+                    .chars()
+                    .map(|ch| Cell { ch, fg, ..blank }),
+            );
+        }
+
+        // If the resulting line is longer than the width of the display, trim it. If it's shorter,
+        // pad it.
+        let text_area_width = self.text_area_width();
+        let hidden_right = cells.len() > text_area_width;
+        cells.truncate(text_area_width);
+        let width: usize = cells
+            .iter()
+            .map(|cell| cell.ch.width_cjk().unwrap_or(0))
+            .sum();
+        cells.extend(std::iter::repeat_n(
+            blank,
+            text_area_width.saturating_sub(width),
+        ));
+
+        // Mark the last column if the line was trimmed, and the first column if part of the line is
+        // scrolled off to the left, so it's clear there's more text off screen.
+        let marker_fg = Some(self.theme.truncation_marker_fg);
+        if hidden_right
+            && let Some(ch) = self.theme.truncation_marker_right
+            && let Some(cell) = cells.last_mut()
+        {
+            *cell = Cell {
+                ch,
+                fg: marker_fg,
+                ..*cell
+            };
+        }
+        if hidden_left
+            && let Some(ch) = self.theme.truncation_marker_left
+            && let Some(cell) = cells.first_mut()
+        {
+            *cell = Cell {
+                ch,
+                fg: marker_fg,
+                ..*cell
+            };
+        }
+
+        if self.config.diff_gutter {
+            let (ch, fg) = match change {
+                Some(LineChange::Added) => ('+', Some(self.theme.diff_added_fg)),
+                Some(LineChange::Modified) => ('~', Some(self.theme.diff_modified_fg)),
+                Some(LineChange::Deleted) => ('-', Some(self.theme.diff_deleted_fg)),
+                None => (' ', None),
+            };
+            cells.insert(
+                0,
+                Cell {
+                    ch,
+                    bg: None,
+                    fg,
+                    underline: None,
+                },
+            );
+        }
+        if self.config.line_numbers != LineNumbers::Off {
+            let label = if line_idx < self.buffer().len_lines() {
+                line_number_label(self.config.line_numbers, line_idx, cursor_line)
+            } else {
+                String::new()
+            };
+            let column = format!("{:>1$} ", label, self.line_number_width() - 1);
+            let fg = Some(self.theme.line_number_fg);
+            let cells_before = column.chars().map(|ch| Cell {
+                ch,
+                bg: None,
+                fg,
+                underline: None,
+            });
+            cells.splice(0..0, cells_before);
+        }
+
+        let drawn_idx = row - self.drawn.area.0;
+        if self.drawn.rows[drawn_idx].as_ref() == Some(&cells) {
+            return Ok(());
+        }
+
+        execute!(self.output, MoveTo(0, row as u16))?;
+
+        // Draw runs of cells sharing the same colours together.
+        let mut start = 0;
+        while start < cells.len() {
+            let Cell {
+                bg, fg, underline, ..
+            } = cells[start];
+            let run: String = cells[start..]
+                .iter()
+                .take_while(|cell| cell.bg == bg && cell.fg == fg && cell.underline == underline)
+                .map(|cell| cell.ch)
+                .collect();
+            start += run.chars().count();
+
+            if let Some(color) = bg {
+                let color = self.color_support.downgrade(color);
+                execute!(self.output, SetBackgroundColor(color))?;
+            }
+            if let Some(color) = fg {
+                let color = self.color_support.downgrade(color);
+                execute!(self.output, SetForegroundColor(color))?;
+            }
+            if let Some(color) = underline {
+                let color = self.color_support.downgrade(color);
+                execute!(
+                    self.output,
+                    SetUnderlineColor(color),
+                    SetAttribute(Attribute::Underlined)
+                )?;
+            }
+            write!(self.output, "{}", run)?;
+            // Resetting the colours resets the underline too.
+            if bg.is_some() || fg.is_some() || underline.is_some() {
+                execute!(self.output, ResetColor)?;
+            }
+        }
+        self.drawn.rows[drawn_idx] = Some(cells);
+
+        Ok(())
+    }
+
+    /// Returns the columns of the misspelled words on a line that reach into the columns `cols`, or
+    /// nothing if spell checking is off or the active buffer isn't prose. Loads the dictionary if
+    /// it hasn't been loaded yet, turning spell checking off if that fails.
+    #[cfg(feature = "spell")]
+    fn misspelled_ranges(&mut self, line_idx: usize, cols: Range<usize>) -> Vec<Range<usize>> {
+        if !self.config.spell_check {
+            return Vec::new();
+        }
+        if self.dictionary.is_none() {
+            match spell::Dictionary::load(&self.config.dictionary_path) {
+                Ok(dictionary) => self.dictionary = Some(dictionary),
+                Err(_) => {
+                    self.config.spell_check = false;
+                    self.footer_text = String::from("Could not load dictionary.");
+                    return Vec::new();
+                }
+            }
+        }
+
+        match &self.dictionary {
+            Some(dictionary) => self.buffer().misspelled_ranges(line_idx, cols, dictionary),
+            None => Vec::new(),
+        }
+    }
+
+    #[cfg(not(feature = "spell"))]
+    fn misspelled_ranges(&mut self, _line_idx: usize, _cols: Range<usize>) -> Vec<Range<usize>> {
+        Vec::new()
+    }
+
+    /// Returns the command that starts the language server for the active buffer, with the URI
+    /// and language of the buffer's file, or None if no server is configured for it.
+    #[cfg(feature = "lsp")]
+    fn language_server_for_buffer(&self) -> Option<(String, String, &str)> {
+        let buffer = self.buffer();
+        if buffer.listing.is_some() || buffer.file_path.as_os_str().is_empty() {
+            return None;
+        }
+        let extension = buffer.file_path.extension()?.to_str()?;
+        let command = self.config.language_servers.get(extension)?;
+        let path = std::path::absolute(&buffer.file_path).ok()?;
+        Some((
+            command.clone(),
+            lsp::uri_for_path(&path),
+            lsp::language_id(extension),
+        ))
+    }
+
+    /// Sends the active buffer's text to its language server, starting the server if it hasn't
+    /// been started yet.
+    #[cfg(feature = "lsp")]
+    fn sync_language_server(&mut self) {
+        let Some((command, uri, language_id)) = self.language_server_for_buffer() else {
+            return;
+        };
+        let language_id = language_id.to_string();
+        let text = self.buffer().text();
+        let server = self
+            .language_servers
+            .entry(command)
+            .or_insert_with_key(|command| {
+                lsp::LanguageServer::start(command, &self.startup_dir)
+                    .inspect_err(|_| {
+                        self.footer_text = format!("Could not start language server {}.", command)
+                    })
+                    .ok()
+            });
+        if let Some(running) = server
+            && running.sync(&uri, &language_id, text).is_err()
+        {
+            *server = None;
+            self.footer_text = String::from("The language server has exited.");
+        }
+    }
+
+    /// Handles the messages the language servers have sent, then shows the diagnostic under the
+    /// cursor, if there is one.
+    #[cfg(feature = "lsp")]
+    fn receive_from_language_servers(&mut self) {
+        for server in self.language_servers.values_mut() {
+            if let Some(running) = server
+                && running.receive().is_err()
+            {
+                *server = None;
+                self.footer_text = String::from("The language server has exited.");
+            }
+        }
+        self.show_diagnostic_at_cursor();
+    }
+
+    /// Returns the diagnostics the language server has reported for the active buffer.
+    #[cfg(feature = "lsp")]
+    fn buffer_diagnostics(&self) -> &[lsp::Diagnostic] {
+        let Some((command, uri, _)) = self.language_server_for_buffer() else {
+            return &[];
+        };
+        match self.language_servers.get(&command) {
+            Some(Some(server)) => server.diagnostics.get(&uri).map_or(&[], Vec::as_slice),
+            _ => &[],
+        }
+    }
+
+    /// Returns, for each line in `lines`, the columns to underline for the diagnostics on it, with
+    /// the colour of each underline. The buffer's diagnostics are looked up once for all the lines.
+    #[cfg(feature = "lsp")]
+    fn diagnostic_underlines(&self, lines: Range<usize>) -> Vec<Vec<(Range<usize>, Color)>> {
+        let diagnostics = self.buffer_diagnostics();
+        lines
+            .map(|line_idx| {
+                if diagnostics.is_empty() || line_idx >= self.buffer().len_lines() {
+                    return Vec::new();
+                }
+                let line = self.buffer().line_slice(line_idx);
+                diagnostics
+                    .iter()
+                    .filter_map(|diagnostic| {
+                        let columns = diagnostic.columns_on_line(line_idx, line)?;
+                        let color = match diagnostic.severity {
+                            lsp::Severity::Error => self.theme.diagnostic_error,
+                            lsp::Severity::Warning => self.theme.diagnostic_warning,
+                            lsp::Severity::Information | lsp::Severity::Hint => {
+                                self.theme.diagnostic_info
+                            }
+                        };
+                        Some((columns, color))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[cfg(not(feature = "lsp"))]
+    fn diagnostic_underlines(&self, lines: Range<usize>) -> Vec<Vec<(Range<usize>, Color)>> {
+        lines.map(|_| Vec::new()).collect()
+    }
+
+    /// Shows the message of the diagnostic under the cursor in the footer, or clears the message
+    /// last shown once the cursor has moved off it. Other footer messages are left alone.
+    #[cfg(feature = "lsp")]
+    fn show_diagnostic_at_cursor(&mut self) {
+        let line_idx = self.buffer().get_logical_cursor_line();
+        let col = self.buffer().cursor_idx - self.buffer().line_to_char(line_idx);
+        let line = self.buffer().line_slice(line_idx);
+        let message = self
+            .buffer_diagnostics()
+            .iter()
+            .find(|diagnostic| {
+                diagnostic
+                    .columns_on_line(line_idx, line)
+                    .is_some_and(|columns| columns.contains(&col))
+            })
+            .map(|diagnostic| diagnostic.message.lines().next().unwrap_or("").to_string());
+
+        let showing = self.shown_diagnostic.as_ref() == Some(&self.footer_text);
+        match message {
+            Some(message) if showing || self.footer_text.is_empty() => {
+                self.footer_text = message.clone();
+                self.shown_diagnostic = Some(message);
+            }
+            None if showing => {
+                self.footer_text.clear();
+                self.shown_diagnostic = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// Draws a condensed overview of the whole buffer down the right-hand side of the text area.
+    /// Each row stands for a block of lines, drawn as a bar whose length shows how much text the
+    /// block contains. Rows covering the lines currently on screen are highlighted.
+    fn render_minimap(&mut self, top: usize, text_rows: usize) -> std::io::Result<()> {
+        let left = (self.text_area_left() + self.text_area_width()) as u16;
+        let len_lines = self.buffer().len_lines();
+        let lines_per_row = len_lines.div_ceil(text_rows).max(1);
+        let viewport_start = self.buffer().visual_origin_row;
+        let viewport_end = viewport_start + text_rows;
+
+        for row in 0..text_rows {
+            let first = row * lines_per_row;
+            let last = min(first + lines_per_row, len_lines);
+
+            let bar_width = if first < last {
+                let chars: usize = (first..last)
+                    .map(|idx| {
+                        let line = self.buffer().get_line(idx);
+                        line.chars().filter(|c| !c.is_whitespace()).count()
+                    })
+                    .sum();
+                min(
+                    (chars / (last - first)).div_ceil(Editor::MINIMAP_CHARS_PER_CELL),
+                    Editor::MINIMAP_WIDTH - 1,
+                )
+            } else {
+                0
+            };
+
+            // The first column is left blank to separate the minimap from the text.
+            let bar = format!(
+                " {}{}",
+                "▮".repeat(bar_width),
+                " ".repeat(Editor::MINIMAP_WIDTH - 1 - bar_width)
+            );
+
+            execute!(self.output, MoveTo(left, (top + row) as u16))?;
+            if first < last && first < viewport_end && last > viewport_start {
+                execute!(
+                    self.output,
+                    SetBackgroundColor(
+                        self.color_support.downgrade(self.theme.minimap_viewport_bg)
+                    )
+                )?;
+                write!(self.output, "{}", bar)?;
+                execute!(self.output, ResetColor)?;
+            } else {
+                write!(self.output, "{}", bar)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Draws the tab bar listing the open buffers along the top of the screen, highlighting the
+    /// active one.
+    fn render_tab_bar(&mut self) -> std::io::Result<()> {
+        let (cols, _) = self.terminal_size()?;
+        execute!(self.output, MoveTo(0, 0))?;
+
+        let mut used = 0;
+        for idx in 0..self.buffers.len() {
+            let buffer = &self.buffers[idx];
+            let dirty_marker = if buffer.dirty_buffer { "*" } else { "" };
+            let label = format!(" {}{} ", buffer.file_name(), dirty_marker);
+            let label: String = label.chars().take(cols as usize - used).collect();
+            used += label.chars().count();
+
+            let (bg, fg) = if idx == self.active_buffer {
+                (self.theme.tab_active_bg, self.theme.tab_active_fg)
+            } else {
+                (self.theme.tab_bar_bg, self.theme.tab_bar_fg)
+            };
+            execute!(
+                self.output,
+                SetBackgroundColor(self.color_support.downgrade(bg)),
+                SetForegroundColor(self.color_support.downgrade(fg))
+            )?;
+            write!(self.output, "{}", label)?;
+        }
+
+        let bg = self.color_support.downgrade(self.theme.tab_bar_bg);
+        execute!(self.output, SetBackgroundColor(bg))?;
+        write!(self.output, "{}", " ".repeat(cols as usize - used))?;
+        execute!(self.output, ResetColor)?;
+        Ok(())
+    }
+
+    /// Returns the column buffer text starts at: the width of the line numbers, plus 1 when the
+    /// diff gutter is shown.
+    fn text_area_left(&self) -> usize {
+        let diff_gutter_width = if self.config.diff_gutter { 1 } else { 0 };
+        self.line_number_width() + diff_gutter_width
+    }
+
+    /// Returns the number of columns taken up by line numbers, including the space after them, or
+    /// 0 if they're turned off.
+    fn line_number_width(&self) -> usize {
+        if self.config.line_numbers == LineNumbers::Off {
+            return 0;
+        }
+        self.buffer().len_lines().to_string().len() + 1
+    }
+
+    /// Returns the number of columns available for drawing buffer text.
+    fn text_area_width(&self) -> usize {
+        let width = self
+            .buffer()
+            .visual_width
+            .saturating_sub(self.text_area_left());
+        if self.config.minimap {
+            width.saturating_sub(Editor::MINIMAP_WIDTH)
+        } else {
+            width
+        }
+    }
+
+    /// Draws the status bar with `left` against the left edge of the terminal and `right` against
+    /// the right edge. If both don't fit, `left` is truncated first.
+    fn render_status_bar(&mut self, left: &str, right: &str) -> std::io::Result<()> {
+        let (cols, rows) = self.terminal_size()?;
+
+        // We only want to render the status bar if there are 2 or more rows being rendered to the
+        // screen.
+        if rows < 2 {
+            return Ok(());
+        }
+
+        let cols = cols as usize;
+        let right = truncate_to_width(right, cols);
+        let right_width = right.width();
+        let left = truncate_to_width(left, cols - right_width);
+        let blank_space = cols - right_width - left.width();
+
+        execute!(self.output, MoveTo(0, rows - 2))?;
+        write!(self.output, "{}{}{}", left, " ".repeat(blank_space), right)?;
+        Ok(())
+    }
+
+    /// Draws the footer bar. The footer bar is a property of the entire editor rather than a single
+    /// buffer.
+    fn render_footer_bar(&mut self) -> std::io::Result<()> {
+        let (cols, rows) = self.terminal_size()?;
+        execute!(self.output, MoveTo(0, rows - 1))?;
+        execute!(
+            self.output,
+            SetBackgroundColor(White),
+            SetForegroundColor(Black)
+        )?;
+
+        let footer_bar = &self.footer_text;
+
+        let message_len = min(footer_bar.len() as u16, cols);
+
+        let footer_text: String = footer_bar.chars().take(message_len as usize).collect();
+
+        let blank_space = cols - message_len;
+        write!(
+            self.output,
+            "{}{}",
+            footer_text,
+            " ".repeat(blank_space as usize)
+        )?;
+        execute!(self.output, ResetColor)?;
+        Ok(())
+    }
+
+    fn save_buffer(&mut self) {
+        if self.buffer().listing.is_some() {
+            self.footer_text = String::from("Directory listings can't be saved.");
+            return;
+        }
+
+        // If the buffer does not have a file path, use the default name or prompt the user for one.
+        if self.buffer().file_path.as_os_str().is_empty() {
+            // Don't let the default name overwrite an existing file.
+            let default_name = self
+                .config
+                .default_new_filename
+                .clone()
+                .filter(|name| !self.startup_dir.join(name).exists());
+            let new_filename = default_name.or_else(|| self.editor_prompt("Enter new filename> "));
+            match new_filename {
+                Some(name) => {
+                    self.buffer_mut().file_path = self.startup_dir.join(&name);
+                    let format_error = self.format_before_save();
+                    match self.buffer_mut().save_file() {
+                        Ok(_) => {
+                            self.footer_text = format!("New file saved as {}", &name);
+                            self.clear_selection_on_save();
+                        }
+                        Err(err) => self.footer_text = save_error_message(&err),
+                    }
+                    self.report_format_error(format_error);
+                }
+                None => self.footer_text = String::from("No file name given, cancelled save."),
+            }
+        } else {
+            self.buffer_mut().check_disk_changes();
+            if self.buffer().file_changed_externally {
+                let response =
+                    self.editor_prompt("The file has changed on disk. Overwrite it? (y/n): ");
+                if !matches!(response.as_deref(), Some("y" | "Y" | "yes")) {
+                    self.footer_text = String::from("Save cancelled.");
+                    return;
+                }
+            }
+            let format_error = self.format_before_save();
+            match self.buffer_mut().save_file() {
+                Ok(saved) => {
+                    self.footer_text = if saved {
+                        String::from("File saved.")
+                    } else {
+                        String::from("No changes to save.")
+                    };
+                    self.clear_selection_on_save();
+                }
+                Err(err) => self.footer_text = save_error_message(&err),
+            }
+            self.report_format_error(format_error);
+        }
+    }
+
+    /// Clears the active buffer's selection after it's been saved, if `clear_selection_on_save` is
+    /// set.
+    fn clear_selection_on_save(&mut self) {
+        if self.config.clear_selection_on_save {
+            self.buffer_mut().selection_anchor = None;
+        }
+    }
+
+    /// Runs the active buffer through its formatter if `format_on_save` is set. Returns the
+    /// formatter's error if it failed, in which case the buffer is left as it was.
+    fn format_before_save(&mut self) -> Option<String> {
+        let settings = &self.buffer().settings;
+        if !settings.format_on_save {
+            return None;
+        }
+        let command = settings.formatter.clone()?;
+        self.buffer_mut().run_formatter(&command).err()
+    }
+
+    /// Adds the error from `format_before_save`, if there was one, to the save message in the
+    /// footer.
+    fn report_format_error(&mut self, format_error: Option<String>) {
+        if let Some(err) = format_error {
+            self.footer_text = format!("{} Formatting failed: {}", self.footer_text, err);
+        }
+    }
+
+    /// If any open buffer is dirty, we need to ask the user whether they really meant to exit
+    /// without saving, naming the unsaved buffers. Otherwise, just exit. With `force`, exit without
+    /// asking and discard any changes.
+    fn attempt_exit(&mut self, force: bool) -> bool {
+        let unsaved: Vec<String> = self
+            .buffers
+            .iter()
+            .filter(|buffer| buffer.dirty_buffer)
+            .map(Buffer::file_name)
+            .collect();
+        if unsaved.is_empty() || force {
+            return true;
+        }
+        let prompt = format!(
+            "Unsaved changes in {}. Do you really want to exit? (y/n): ",
+            unsaved.join(", ")
+        );
+        match self.editor_prompt(&prompt) {
+            Some(str) => str == "y" || str == "Y" || str == "yes",
+            None => false,
+        }
+    }
+
+    /// Returns true if the user wants to quit, false otherwise.
+    pub fn handle_key_event(&mut self, key_event: KeyEvent) -> bool {
+        // Some terminals report keys being held down as repeats, which should act like presses.
+        if key_event.kind == KeyEventKind::Release {
+            return false;
+        }
+
+        if self.paste_mode
+            && !key_event
+                .modifiers
+                .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
+        {
+            let text = match key_event.code {
+                KeyCode::Char(c) => Some(c.to_string()),
+                KeyCode::Enter => Some(String::from("\n")),
+                KeyCode::Tab => Some(String::from("\t")),
+                KeyCode::Esc => {
+                    self.paste_mode = false;
+                    self.footer_text = String::from("Paste mode off.");
+                    return false;
+                }
+                _ => None,
+            };
+            if let Some(text) = text {
+                self.edit(|editor| editor.insert_raw(&text));
+                return false;
+            }
+        }
+
+        // Record everything but the macro keys themselves.
+        let is_macro_key = key_event.modifiers.contains(KeyModifiers::ALT)
+            && matches!(key_event.code, KeyCode::Char('q') | KeyCode::Char('m'));
+        if let Some(events) = &mut self.recording
+            && !is_macro_key
+        {
+            events.push(key_event);
+        }
+
+        if self.buffer().listing.is_some() {
+            return self.handle_listing_key(key_event);
+        }
+        if self.config.modal && self.handle_modal_key(key_event) {
+            self.autosave_after_edits();
+            return false;
+        }
+
+        // Edits made by commands get an undo step of their own, apart from any typing around them.
+        let is_command = key_event
+            .modifiers
+            .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
+            || matches!(key_event.code, KeyCode::F(_));
+        if is_command {
+            self.buffer_mut().break_undo_group();
+        }
+        let quit = self.run_key_binding(key_event);
+        if is_command {
+            self.buffer_mut().break_undo_group();
+        }
+        self.autosave_after_edits();
+        quit
+    }
+
+    /// Saves the active buffer if it has a name and `autosave_after_edits` edits have been made to
+    /// it since it was last saved.
+    fn autosave_after_edits(&mut self) {
+        let Some(limit) = self.config.autosave_after_edits else {
+            return;
+        };
+        let buffer = self.buffer();
+        if buffer.edits_since_save >= limit && !buffer.file_path.as_os_str().is_empty() {
+            self.autosave(self.active_buffer..self.active_buffer + 1);
+        }
+    }
+
+    /// Handles `key_event` as a modal editing key: in Normal mode, letters move the cursor and run
+    /// commands rather than typing, and `i` switches to Insert mode; Esc switches back. Returns true
+    /// if the key was used up, or false to handle it as usual. Enter and Backspace move down and
+    /// left as `j` and `h` do, and the other keys that would edit the text do nothing. Otherwise,
+    /// keys with Ctrl or Alt, and keys that aren't characters, are handled as usual.
+    fn handle_modal_key(&mut self, key_event: KeyEvent) -> bool {
+        if self.mode == Mode::Insert {
+            if key_event.code == KeyCode::Esc {
+                self.mode = Mode::Normal;
+                self.buffer_mut().break_undo_group();
+            }
+            // Let Esc do its usual job of clearing the selection too.
+            return false;
+        }
+
+        if key_event.code == KeyCode::Esc {
+            self.pending_count = None;
+            self.pending_operator = None;
+        }
+        let plain = !key_event
+            .modifiers
+            .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT);
+        let c = match key_event.code {
+            KeyCode::Char(c) => c,
+            KeyCode::Enter if plain => 'j',
+            KeyCode::Backspace if plain => 'h',
+            KeyCode::Enter
+            | KeyCode::Backspace
+            | KeyCode::Delete
+            | KeyCode::Tab
+            | KeyCode::BackTab => {
+                self.pending_count = None;
+                self.pending_operator = None;
+                return true;
+            }
+            _ => return false,
+        };
+
+        // Ctrl-A and Ctrl-X add the count to, or take it from, the number under the cursor.
+        if key_event.modifiers.contains(KeyModifiers::CONTROL) && matches!(c, 'a' | 'x') {
+            let count = i64::try_from(self.pending_count.take().unwrap_or(1)).unwrap_or(i64::MAX);
+            self.pending_operator = None;
+            self.add_to_number(if c == 'a' { count } else { -count });
+            return true;
+        }
+        if !plain {
+            return false;
+        }
+
+        // Digits build up a count for the next command. A leading 0 isn't a count, and nor is the
+        // character a search is waiting for.
+        let searching = matches!(self.pending_operator, Some('f' | 'F' | 't' | 'T'));
+        if let Some(digit) = c.to_digit(10)
+            && (digit != 0 || self.pending_count.is_some())
+            && !searching
+        {
+            let count = self.pending_count.unwrap_or(0);
+            self.pending_count = Some(count.saturating_mul(10).saturating_add(digit as usize));
+            return true;
+        }
+
+        let pending = self.pending_operator.take();
+        // No command can usefully be repeated more times than there are chars in the buffer.
+        let count = min(
+            self.pending_count.unwrap_or(1),
+            self.buffer().len_chars().max(1),
+        );
+
+        // Yanking and pasting go through the registers, so they're done here rather than in the
+        // loop below, which borrows the buffer.
+        match (pending, c) {
+            (Some('y'), 'y') => {
+                let line_idx = self.buffer().get_logical_cursor_line();
+                let end = min(line_idx + count, self.buffer().len_lines());
+                self.yank_lines(line_idx..end);
+                self.pending_count = None;
+                return true;
+            }
+            (_, 'y') => {
+                self.pending_operator = Some('y');
+                return true;
+            }
+            (_, 'p' | 'P') => {
+                self.buffer_mut().selection_anchor = None;
+                self.put(c == 'p');
+                self.pending_count = None;
+                return true;
+            }
+            _ => {}
+        }
+
+        // Borrow the buffer through the field so the mode and operator can still be set below.
+        let buffer = &mut self.buffers[self.active_buffer];
+        buffer.selection_anchor = None;
+        buffer.break_undo_group();
+        for i in 0..count {
+            let before = (buffer.cursor_idx, buffer.len_chars());
+            let at_line_end = buffer.cursor_idx >= buffer.len_chars()
+                || buffer.get_range(buffer.cursor_idx..buffer.cursor_idx + 1) == "\n";
+            // `f` and `t` look forward for the next key pressed, `F` and `T` look back, and `;`
+            // and `,` repeat the last search in the same or the opposite direction.
+            let search = match (pending, c) {
+                (Some(kind @ ('f' | 'F' | 't' | 'T')), target) => Some(CharSearch {
+                    target,
+                    forward: kind.is_lowercase(),
+                    till: kind.eq_ignore_ascii_case(&'t'),
+                }),
+                (_, ';') => self.last_char_search,
+                (_, ',') => self.last_char_search.map(|search| CharSearch {
+                    forward: !search.forward,
+                    ..search
+                }),
+                _ => None,
+            };
+            if let Some(search) = search {
+                let repeat = i > 0 || pending.is_none();
+                if pending.is_some() {
+                    self.last_char_search = Some(search);
+                }
+                if !buffer.find_char_on_line(search, repeat) {
+                    self.footer_text = format!("Character not found: {}", search.target);
+                    break;
+                }
+                continue;
+            }
+
+            match (pending, c) {
+                (Some('d'), 'd') => {
+                    let line_idx = buffer.get_logical_cursor_line();
+                    buffer.delete_line(line_idx);
+                }
+                (_, 'd' | 'f' | 'F' | 't' | 'T') => self.pending_operator = Some(c),
+                (_, 'h') if buffer.get_logical_cursor_col() > 0 => buffer.move_left(),
+                (_, 'l') if !at_line_end => buffer.move_right(),
+                (_, 'j') => buffer.move_down(),
+                (_, 'k') => buffer.move_up(),
+                (_, '}') => buffer.next_paragraph(),
+                (_, '{') => buffer.prev_paragraph(),
+                (_, 'x') if !at_line_end => {
+                    let cursor_idx = buffer.cursor_idx;
+                    buffer.delete_range(cursor_idx..cursor_idx + 1);
+                }
+                // Switching to Insert mode happens once, whatever the count.
+                (_, 'i') => {
+                    self.mode = Mode::Insert;
+                    break;
+                }
+                (_, 'a') => {
+                    if !at_line_end {
+                        buffer.move_right();
+                    }
+                    self.mode = Mode::Insert;
+                    break;
+                }
+                (_, 'o') => {
+                    buffer.open_line(true);
+                    self.mode = Mode::Insert;
+                    break;
+                }
+                (_, 'O') => {
+                    buffer.open_line(false);
+                    self.mode = Mode::Insert;
+                    break;
+                }
+                _ => {}
+            }
+
+            // Stop once repeating has stopped doing anything, such as at the end of the buffer.
+            if (buffer.cursor_idx, buffer.len_chars()) == before {
+                break;
+            }
+        }
+
+        // Keep the count for an operator still waiting for its second key, as in `3dd`.
+        if self.pending_operator.is_none() {
+            self.pending_count = None;
+        }
+        true
+    }
+
+    /// Handles a key pressed in a directory listing. Enter opens the entry on the cursor's line and
+    /// the movement keys work as usual, but keys that would edit the listing do nothing. Returns
+    /// true if the user wants to quit, false otherwise.
+    fn handle_listing_key(&mut self, key_event: KeyEvent) -> bool {
+        let ctrl = key_event.modifiers.contains(KeyModifiers::CONTROL);
+        match key_event.code {
+            KeyCode::Char(c) if ctrl && self.config.quit_keys.contains(&c) => {
+                return self.attempt_exit(false);
+            }
+            KeyCode::Char('w') if ctrl => return self.close_active_buffer(),
+            KeyCode::F(10) => return self.attempt_exit(false),
+            KeyCode::F(1) => {
+                if let Some(command) = self.editor_prompt("> ") {
+                    return self.run_command(&command);
+                }
+            }
+            KeyCode::Enter => self.open_listing_entry(),
+            KeyCode::PageDown => self.page(true, false),
+            KeyCode::PageUp => self.page(false, false),
+            KeyCode::Up
+            | KeyCode::Down
+            | KeyCode::Left
+            | KeyCode::Right
+            | KeyCode::Home
+            | KeyCode::End => self.buffer_mut().handle_key_event(key_event),
+            _ => {}
+        }
+        false
+    }
+
+    /// Returns true, and says why in the footer, if the active buffer is a directory listing and so
+    /// can't be edited.
+    fn refuse_listing_edit(&mut self) -> bool {
+        if self.buffer().listing.is_none() {
+            return false;
+        }
+        self.footer_text = String::from("Directory listings can't be edited.");
+        true
+    }
+
+    /// Runs `edit`, which changes the active buffer's text, unless the active buffer is a directory
+    /// listing. Every palette command that edits goes through this, so listings refuse them all.
+    fn edit(&mut self, edit: impl FnOnce(&mut Editor)) {
+        if !self.refuse_listing_edit() {
+            edit(self);
+        }
+    }
+
+    /// Opens the entry on the cursor's line of the active directory listing. Directories are listed
+    /// in place of the listing, and files are opened in place of it.
+    fn open_listing_entry(&mut self) {
+        let Some(dir) = self.buffer().listing.clone() else {
+            return;
+        };
+        let line = self
+            .buffer()
+            .get_line(self.buffer().get_logical_cursor_line());
+        let entry = line.trim_end_matches('\n');
+        if entry.is_empty() {
+            return;
+        }
+
+        let path = dir.join(entry);
+        if entry.ends_with('/') {
+            self.browse(path);
+            return;
+        }
+        let listing_idx = self.active_buffer;
+        self.open_buffer(path);
+        if self.active_buffer != listing_idx {
+            self.remove_buffer(listing_idx);
+        }
+    }
+
+    /// Replaces the active buffer's text with its file's contents on disk, asking first if that
+    /// would throw away unsaved changes.
+    fn reload(&mut self) {
+        if self.buffer().file_path.as_os_str().is_empty() || self.buffer().listing.is_some() {
+            self.footer_text = String::from("Only files can be reloaded.");
+            return;
+        }
+        if self.buffer().dirty_buffer {
+            let response = self.editor_prompt("Discard unsaved changes and reload? (y/n): ");
+            if !matches!(response.as_deref(), Some("y" | "Y" | "yes")) {
+                self.footer_text = String::from("Reload cancelled.");
+                return;
+            }
+        }
+        self.footer_text = match self.buffer_mut().reload() {
+            Ok(()) => String::from("Reloaded from disk."),
+            Err(_) => String::from("Could not read file."),
+        };
+    }
+
+    /// Shows a listing of the directory `dir`, in place of the active buffer if that's a listing
+    /// too, or else in a new buffer.
+    fn browse(&mut self, dir: PathBuf) {
+        let dir = std::fs::canonicalize(&dir).unwrap_or(dir);
+        match Buffer::directory_listing(&dir, self.config.buffer_settings.clone()) {
+            Ok(mut listing) => {
+                listing.visual_width = self.buffer().visual_width;
+                listing.visual_height = self.buffer().visual_height;
+                if self.buffer().listing.is_some() {
+                    self.buffers[self.active_buffer] = listing;
+                } else {
+                    self.buffers.push(listing);
+                    self.set_active_buffer(self.buffers.len() - 1);
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                self.footer_text = format!("Permission denied: {}", dir.display());
+            }
+            Err(_) => self.footer_text = format!("Could not read directory {}.", dir.display()),
+        }
+    }
+
+    /// Does whatever `key_event` is bound to, passing it on to the active buffer if it isn't an
+    /// editor-wide binding. Returns true if the user wants to quit, false otherwise.
+    fn run_key_binding(&mut self, key_event: KeyEvent) -> bool {
+        // Handle Ctrl-<X>
+        if key_event.modifiers.contains(KeyModifiers::CONTROL) {
+            match key_event.code {
+                KeyCode::Char(c) if self.config.quit_keys.contains(&c) => {
+                    return self.attempt_exit(false);
+                }
+                KeyCode::Char('s') => {
+                    self.save_buffer();
+                }
+                KeyCode::Char('l') => {
+                    if self.redraw().is_err() {
+                        self.footer_text = String::from("Could not redraw the screen.");
+                    }
+                }
+                KeyCode::Char('w') => {
+                    return self.close_active_buffer();
+                }
+                KeyCode::Char('z') => {
+                    if !self.buffer_mut().undo() {
+                        self.footer_text = String::from("Nothing to undo.");
+                    }
+                }
+                KeyCode::Char('y') => {
+                    if !self.buffer_mut().redo() {
+                        self.footer_text = String::from("Nothing to redo.");
+                    }
+                }
+                KeyCode::Char('t') => self.buffer_mut().transpose_chars(),
+                KeyCode::Char('c') => self.yank(false),
+                KeyCode::Char('x') => self.yank(true),
+                KeyCode::Char('v') => self.paste(),
+                KeyCode::Char('j') => {
+                    // Join the selected lines, or the current line with the next one.
+                    let lines = if self.buffer().selection_range().is_some() {
+                        self.buffer().selected_lines()
+                    } else {
+                        let line = self.buffer().get_logical_cursor_line();
+                        line..line + 2
+                    };
+                    self.buffer_mut().join_lines(lines);
+                }
+                KeyCode::Char('f') => self.search(),
+                // Terminals send Ctrl-^ as Ctrl-6.
+                KeyCode::Char('6' | '^') => self.alternate_buffer(),
+                _ => self.buffer_mut().handle_key_event(key_event),
+            }
+        } else if key_event.modifiers.contains(KeyModifiers::ALT) {
+            // Handle Alt-<X>
+            match key_event.code {
+                KeyCode::Char('c') => self.scroll_cursor_to(ScreenPosition::Center),
+                KeyCode::Char('t') => self.scroll_cursor_to(ScreenPosition::Top),
+                KeyCode::Char('b') => self.scroll_cursor_to(ScreenPosition::Bottom),
+                KeyCode::Char('u') => self.transform_case(CaseOp::Toggle),
+                KeyCode::Char('n') => self.select_next_occurrence(),
+                KeyCode::Char('j') => self.buffer_mut().open_line(true),
+                KeyCode::Char('k') => self.buffer_mut().open_line(false),
+                KeyCode::Char('h') => self.move_cursor_to(ScreenPosition::Top),
+                KeyCode::Char('l') => self.move_cursor_to(ScreenPosition::Bottom),
+                KeyCode::Char('r') => self.select_register(),
+                KeyCode::Char('o') => self.open_path_under_cursor(),
+                KeyCode::Char('s') => self.buffer_mut().insert_spaces_to_tab_stop(),
+                KeyCode::Char('w') => {
+                    let range = self.buffer().word_bounds();
+                    self.buffer_mut().select(range);
+                }
+                KeyCode::Char('x') => {
+                    let range = self.buffer().line_bounds();
+                    self.buffer_mut().select(range);
+                }
+                KeyCode::Char('p') => {
+                    let range = self.buffer().paragraph_bounds();
+                    self.buffer_mut().select(range);
+                }
+                KeyCode::Char('/') => self.toggle_block_comment(),
+                KeyCode::Char('f') => self.config.focus_mode = !self.config.focus_mode,
+                KeyCode::Char('.') => self.go_to_change(true),
+                KeyCode::Char(',') => self.go_to_change(false),
+                KeyCode::Char('y') => {
+                    let lines = if self.buffer().selection_range().is_some() {
+                        self.buffer().selected_lines()
+                    } else {
+                        let line = self.buffer().get_logical_cursor_line();
+                        line..line + 1
+                    };
+                    self.yank_lines(lines);
+                }
+                KeyCode::Char('=') => self.add_to_number(1),
+                KeyCode::Char('-') => self.add_to_number(-1),
+                KeyCode::Char('q') => self.toggle_recording(),
+                KeyCode::Char('m') => return self.replay_macro(1),
+                KeyCode::Down | KeyCode::Up => {
+                    let forward = key_event.code == KeyCode::Down;
+                    if !self.buffer_mut().go_to_matching_indent(forward) {
+                        self.footer_text = String::from("No line with matching indentation.");
+                    }
+                }
+                _ => self.buffer_mut().handle_key_event(key_event),
+            }
+        } else {
+            match key_event.code {
+                KeyCode::F(10) => return self.attempt_exit(false),
+                KeyCode::PageDown | KeyCode::PageUp => self.page(
+                    key_event.code == KeyCode::PageDown,
+                    key_event.modifiers.contains(KeyModifiers::SHIFT),
+                ),
+                KeyCode::F(3) => self.find_next(),
+                KeyCode::Tab if self.config.tab_completion && self.complete_word() => {}
+                KeyCode::Esc => {
+                    // Esc is a general "cancel" key: drop the selection and any footer message.
+                    // Prompts read their own key events, so this never sees their Esc.
+                    self.buffer_mut().selection_anchor = None;
+                    self.buffer_mut().occurrences.clear();
+                    self.footer_text.clear();
+                }
+                KeyCode::F(1) => {
+                    if let Some(command) = self.editor_prompt("> ") {
+                        return self.run_command(&command);
+                    }
+                }
+                _ => {
+                    self.buffer_mut().handle_key_event(key_event);
+                }
+            }
+        }
+        false
+    }
+
+    /// Completes the word before the cursor from the words in the buffer, inserting as much as all
+    /// the candidates have in common and listing them in the footer if there's more than one.
+    /// Returns false without doing anything if the cursor isn't at the end of a word or there's
+    /// nothing to complete it with.
+    fn complete_word(&mut self) -> bool {
+        let buffer = self.buffer();
+        if buffer.selection_range().is_some() {
+            return false;
+        }
+        let Some(range) = buffer
+            .word_range_at_cursor()
+            .filter(|range| range.end == buffer.cursor_idx)
+        else {
+            return false;
+        };
+
+        let prefix = buffer.get_range(range);
+        let completer = BufferWords::new(&buffer.get_range(0..buffer.len_chars()));
+        let candidates = completer.candidates(&prefix);
+        if candidates.is_empty() {
+            return false;
+        }
+
+        let rest = common_prefix(&candidates)[prefix.len()..].to_string();
+        let cursor_idx = self.buffer().cursor_idx;
+        self.buffer_mut().insert_text(cursor_idx, &rest);
+        if candidates.len() > 1 {
+            self.footer_text = candidates.join(" ");
+        }
+        true
+    }
+
+    /// Prompts for text to search for and moves the cursor to its next instance, offering to search
+    /// again from the top if there isn't one. The text is remembered for `find_next`.
+    fn search(&mut self) {
+        let Some(target) = self.editor_prompt("Enter target text> ") else {
+            self.footer_text = String::from("Search cancelled.");
+            return;
+        };
+        self.last_search = Some(target.clone());
+
+        let from = self.buffer().cursor_idx + 1;
+        if self.buffer_mut().go_to_next_instance(&target, from) {
+            self.footer_text = String::from("Match found.");
+            return;
+        }
+
+        match self.editor_prompt("No match found. Search from top? y/n> ") {
+            Some(answer) if answer == "y" => {
+                if self.buffer_mut().go_to_next_instance(&target, 0) {
+                    self.footer_text = String::from("Match found.");
+                } else {
+                    self.footer_text = String::from("No match found.");
+                }
+            }
+            Some(_) => {}
+            None => self.footer_text = String::from("Search cancelled."),
+        }
+    }
+
+    /// Moves the cursor to the next instance of the last text searched for, wrapping around to the
+    /// top of the buffer if needed.
+    pub fn find_next(&mut self) {
+        let Some(target) = self.last_search.clone() else {
+            self.footer_text = String::from("No previous search.");
+            return;
+        };
+
+        let from = self.buffer().cursor_idx + 1;
+        if self.buffer_mut().go_to_next_instance(&target, from) {
+            self.footer_text = String::from("Match found.");
+        } else if self.buffer_mut().go_to_next_instance(&target, 0) {
+            self.footer_text = String::from("Search wrapped to top.");
+        } else {
+            self.footer_text = String::from("No match found.");
+        }
+    }
+
+    /// Moves the cursor to the start of the next run of changed lines after the cursor's line if
+    /// `forward` is set, or the last one starting before it if not. Past the last change, the
+    /// cursor wraps around to the first if `wrap_change_navigation` is set.
+    fn go_to_change(&mut self, forward: bool) {
+        let starts = self.buffer_mut().hunk_starts();
+        let (Some(&first), Some(&last)) = (starts.first(), starts.last()) else {
+            self.footer_text = String::from("No changes.");
+            return;
+        };
+
+        let cursor_line = self.buffer().get_logical_cursor_line();
+        let next = if forward {
+            starts.iter().find(|&&start| start > cursor_line)
+        } else {
+            starts.iter().rev().find(|&&start| start < cursor_line)
+        };
+        let line = match next {
+            Some(&line) => line,
+            None if self.config.wrap_change_navigation => {
+                self.footer_text = if forward {
+                    String::from("Wrapped to first change.")
+                } else {
+                    String::from("Wrapped to last change.")
+                };
+                if forward { first } else { last }
+            }
+            None => {
+                self.footer_text = String::from("No more changes.");
+                return;
+            }
+        };
+        let buffer = self.buffer_mut();
+        buffer.selection_anchor = None;
+        buffer.go_to_position(line, 0);
+    }
+
+    /// Runs a command entered in the command palette. Returns true if the user wants to quit, false
+    /// otherwise.
+    pub fn run_command(&mut self, command: &str) -> bool {
+        let mut words = command.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let args: Vec<&str> = words.collect();
+
+        match (name, args.as_slice()) {
+            ("q", []) => return self.attempt_exit(false),
+            ("q!", []) => return self.attempt_exit(true),
+            ("replay", [times]) => match times.parse() {
+                Ok(times) => return self.replay_macro(times),
+                Err(_) => self.footer_text = format!("Invalid count: {}", times),
+            },
+            (name, []) if let Some(percent) = name.strip_suffix('%') => match percent.parse() {
+                Ok(percent) => {
+                    self.buffer_mut().go_to_percentage(percent);
+                    self.scroll_cursor_to(ScreenPosition::Center);
+                }
+                Err(_) => self.footer_text = format!("Invalid percentage: {}", percent),
+            },
+            ("sort", []) => self.edit(|editor| editor.sort_lines(SortOrder::Ascending)),
+            ("sort", ["reverse"]) => self.edit(|editor| editor.sort_lines(SortOrder::Descending)),
+            ("sort", ["numeric"]) => self.edit(|editor| editor.sort_lines(SortOrder::Numeric)),
+            ("trim", []) => self.edit(|editor| {
+                let lines = editor.buffer().selected_lines();
+                if !editor.confirm_bulk(lines.len()) {
+                    return;
+                }
+                editor.buffer_mut().trim_lines(lines);
+                editor.footer_text = String::from("Trimmed trailing whitespace.");
+            }),
+            ("clean", []) => self.edit(|editor| {
+                if !editor.confirm_bulk(editor.buffer().content_lines()) {
+                    return;
+                }
+                editor.footer_text = if editor.buffer_mut().clean(CleanOptions::default()) {
+                    String::from("Cleaned up the buffer.")
+                } else {
+                    String::from("Nothing to clean up.")
+                };
+            }),
+            ("reflow", []) => self.edit(|editor| {
+                let lines = editor.buffer().paragraph_at_cursor();
+                let width = editor.config.text_width;
+                editor.footer_text = if lines.is_empty() {
+                    String::from("No paragraph at the cursor.")
+                } else {
+                    editor.buffer_mut().reflow(lines, width);
+                    format!("Reflowed paragraph to {} columns.", width)
+                };
+            }),
+            ("upper", []) => self.edit(|editor| editor.transform_case(CaseOp::Upper)),
+            ("lower", []) => self.edit(|editor| editor.transform_case(CaseOp::Lower)),
+            ("toggle-case", []) => self.edit(|editor| editor.transform_case(CaseOp::Toggle)),
+            ("dos2unix", []) => self.edit(|editor| editor.set_line_ending(LineEnding::Lf)),
+            ("unix2dos", []) => self.edit(|editor| editor.set_line_ending(LineEnding::Crlf)),
+            ("retab", []) => self.edit(|editor| editor.retab(false)),
+            ("retab", ["all"]) => self.edit(|editor| editor.retab(true)),
+            ("open", [path]) => self.open_buffer(PathBuf::from(path)),
+            ("write", [path]) => {
+                // Like saves, relative paths are relative to where the editor was started.
+                let full_path = self.startup_dir.join(path);
+                self.footer_text = match self.buffer().write_selection_to(&full_path) {
+                    Ok(()) => format!("Wrote {}.", path),
+                    Err(_) => format!("Could not write {}.", path),
+                };
+            }
+            ("reload", []) => self.reload(),
+            ("paste", []) => {
+                self.paste_mode = true;
+                self.footer_text = String::from("Paste mode on. Press Esc to end it.");
+            }
+            ("browse", []) => {
+                let buffer = self.buffer();
+                let dir = match (&buffer.listing, buffer.file_path.parent()) {
+                    (Some(dir), _) => dir.clone(),
+                    (None, Some(parent)) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+                    _ => self.startup_dir.clone(),
+                };
+                self.browse(dir);
+            }
+            ("read", [path]) => self.edit(|editor| {
+                if editor.buffer_mut().insert_file(Path::new(path)).is_err() {
+                    editor.footer_text = format!("Could not read {}.", path);
+                }
+            }),
+            ("next", []) => self.cycle_buffer(1),
+            ("prev", []) => self.cycle_buffer(-1),
+            ("insert-char", [hex]) => match parse_code_point(hex) {
+                Some(c) => self.edit(|editor| {
+                    let cursor_idx = editor.buffer().cursor_idx;
+                    editor.buffer_mut().insert_text(cursor_idx, &c.to_string());
+                }),
+                None => self.footer_text = format!("Invalid code point: {}", hex),
+            },
+            ("char-info", []) => {
+                let offset = self.buffer().cursor_byte_offset();
+                self.footer_text = match self.buffer().char_at_cursor() {
+                    Some(c) => format!(
+                        "Byte {}: U+{:04X}, {} byte(s) in UTF-8",
+                        offset,
+                        c as u32,
+                        c.len_utf8()
+                    ),
+                    None => format!("Byte {}: end of file", offset),
+                };
+            }
+            ("move", [count, direction]) => {
+                let step = match *direction {
+                    "up" => Buffer::move_up,
+                    "down" => Buffer::move_down,
+                    "left" => Buffer::move_left,
+                    "right" => Buffer::move_right,
+                    _ => {
+                        self.footer_text = format!("Unknown direction: {}", direction);
+                        return false;
+                    }
+                };
+                match count.parse::<usize>() {
+                    Ok(count) => {
+                        let buffer = self.buffer_mut();
+                        // Stop as soon as the cursor can't move any further.
+                        for _ in 0..min(count, buffer.len_chars()) {
+                            let cursor_idx = buffer.cursor_idx;
+                            step(buffer);
+                            if buffer.cursor_idx == cursor_idx {
+                                break;
+                            }
+                        }
+                    }
+                    Err(_) => self.footer_text = format!("Invalid count: {}", count),
+                }
+            }
+            ("line-limit", ["off"]) => self.config.line_length_limit = None,
+            ("line-limit", [limit]) => match limit.parse() {
+                Ok(limit) => self.config.line_length_limit = Some(limit),
+                Err(_) => self.footer_text = format!("Invalid line length: {}", limit),
+            },
+            ("line-numbers", [mode]) => match *mode {
+                "off" => self.config.line_numbers = LineNumbers::Off,
+                "absolute" => self.config.line_numbers = LineNumbers::Absolute,
+                "relative" => self.config.line_numbers = LineNumbers::Relative,
+                "hybrid" => self.config.line_numbers = LineNumbers::Hybrid,
+                _ => self.footer_text = format!("Unknown line number mode: {}", mode),
+            },
+            ("minimap", []) => self.config.minimap = !self.config.minimap,
+            ("focus", []) => self.config.focus_mode = !self.config.focus_mode,
+            ("diff-gutter", []) => self.config.diff_gutter = !self.config.diff_gutter,
+            #[cfg(feature = "spell")]
+            ("spell", []) => self.config.spell_check = !self.config.spell_check,
+            ("", _) => {}
+            _ => self.footer_text = format!("Unknown command: {}", command),
+        }
+        false
+    }
+
+    /// Sorts the selected lines, or the whole buffer if there is no selection.
+    fn sort_lines(&mut self, order: SortOrder) {
+        let lines = self.buffer().selected_lines();
+        let count = lines.len();
+        if !self.confirm_bulk(count) {
+            return;
+        }
+        self.buffer_mut().sort_lines(lines, order);
+        self.footer_text = format!("Sorted {} lines.", count);
+    }
+
+    /// Asks whether to go ahead with a command that will change `affected` lines, if
+    /// `confirm_bulk_edits` is set and that's at least `bulk_edit_threshold` lines. Returns true if
+    /// the command should go ahead.
+    fn confirm_bulk(&mut self, affected: usize) -> bool {
+        if !self.config.confirm_bulk_edits || affected < self.config.bulk_edit_threshold {
+            return true;
+        }
+        let prompt = format!("This will modify {} lines. Continue? (y/n): ", affected);
+        let confirmed = matches!(
+            self.editor_prompt(&prompt).as_deref(),
+            Some("y" | "Y" | "yes")
+        );
+        if !confirmed {
+            self.footer_text = String::from("Cancelled.");
+        }
+        confirmed
+    }
+
+    /// Starts recording key events into a macro, or stops recording and keeps the macro for
+    /// `replay_macro`.
+    fn toggle_recording(&mut self) {
+        match self.recording.take() {
+            Some(events) => {
+                self.footer_text = format!("Recorded {} keys.", events.len());
+                self.last_macro = events;
+            }
+            None => {
+                self.recording = Some(Vec::new());
+                self.footer_text = String::from("Recording macro.");
+            }
+        }
+    }
+
+    /// Feeds the last recorded macro through `handle_key_event` `times` times. Prompts and register
+    /// selection are skipped while it runs. Returns true if the macro asked to quit.
+    pub fn replay_macro(&mut self, times: usize) -> bool {
+        if self.recording.is_some() {
+            self.footer_text = String::from("Can't replay a macro while recording one.");
+            return false;
+        }
+        if self.last_macro.is_empty() {
+            self.footer_text = String::from("No macro recorded.");
+            return false;
+        }
+
+        self.replaying = true;
+        let events = self.last_macro.clone();
+        let quit = (0..times)
+            .flat_map(|_| events.iter())
+            .any(|&key_event| self.handle_key_event(key_event));
+        self.replaying = false;
+        quit
+    }
+
+    /// Adds `delta` to the number under or after the cursor on its line.
+    fn add_to_number(&mut self, delta: i64) {
+        if let Err(message) = self.buffer_mut().add_to_number(delta) {
+            self.footer_text = message;
+        }
+    }
+
+    /// Wraps the selection in a block comment, or unwraps it if it's already one. Without a
+    /// selection, the cursor's line is used. Languages without block comments get line comments
+    /// instead.
+    fn toggle_block_comment(&mut self) {
+        let syntax = self.buffer().comment_syntax();
+        let buffer = self.buffer_mut();
+        match (syntax.block, syntax.line) {
+            (Some((open, close)), _) => {
+                let range = buffer.selection_range().unwrap_or_else(|| {
+                    let line_idx = buffer.get_logical_cursor_line();
+                    let start = buffer.line_to_char(line_idx);
+                    start..start + buffer.line_len(line_idx)
+                });
+                buffer.toggle_block_comment(range, open, close);
+            }
+            (None, Some(prefix)) => {
+                let lines = if buffer.selection_range().is_some() {
+                    buffer.selected_lines()
+                } else {
+                    let line_idx = buffer.get_logical_cursor_line();
+                    line_idx..line_idx + 1
+                };
+                buffer.toggle_line_comments(lines, prefix);
+            }
+            (None, None) => {
+                self.footer_text = String::from("No comment syntax known for this file type.");
+            }
+        }
+    }
+
+    /// Reads the next key press as the name of the register to use for the next yank or paste.
+    /// Registers are named `a` to `z`; naming one in uppercase appends to it when yanking.
+    fn select_register(&mut self) {
+        if self.replaying {
+            return;
+        }
+        self.footer_text = String::from("Register: ");
+        self.render().ok();
+        let _ = self.output.flush();
+
+        loop {
+            match self.read_event() {
+                Ok(Event::Key(key_event)) if key_event.kind == KeyEventKind::Press => {
+                    match key_event.code {
+                        KeyCode::Char(c) if c.is_ascii_alphabetic() || c == '"' => {
+                            self.pending_register = Some(c);
+                            self.footer_text = format!("Using register {}.", c);
+                        }
+                        _ => self.footer_text = String::from("Register selection cancelled."),
+                    }
+                    return;
+                }
+                Err(_) => return,
+                _ => {}
+            }
+        }
+    }
+
+    /// Copies the selection into the chosen register, removing it from the buffer if `cut` is set.
+    /// An uppercase register name appends to the register rather than replacing its contents.
+    pub fn yank(&mut self, cut: bool) {
+        let name = self
+            .pending_register
+            .take()
+            .unwrap_or(Editor::UNNAMED_REGISTER);
+        let Some(range) = self.buffer().selection_range() else {
+            self.footer_text = String::from("Nothing selected.");
+            return;
+        };
+
+        let text = self.buffer().get_range(range.clone());
+        self.store_in_register(name, text, false);
+
+        if cut {
+            self.buffer_mut().delete_range(range);
+        }
+        if cut || !self.config.keep_selection_after_copy {
+            self.buffer_mut().selection_anchor = None;
+        }
+    }
+
+    /// Copies the lines with indices in `lines` into the chosen register as whole lines. An
+    /// uppercase register name appends to the register rather than replacing its contents.
+    pub fn yank_lines(&mut self, lines: Range<usize>) {
+        let name = self
+            .pending_register
+            .take()
+            .unwrap_or(Editor::UNNAMED_REGISTER);
+        let buffer = self.buffer();
+        let mut text =
+            buffer.get_range(buffer.line_to_char(lines.start)..buffer.line_to_char(lines.end));
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        self.store_in_register(name, text, true);
+        self.footer_text = format!("Yanked {} lines.", lines.len());
+    }
+
+    /// Puts `text` in the register `name`, or adds it to the end of the lowercase register if
+    /// `name` is uppercase. A register holds whole lines if `linewise` is set, or if it already
+    /// held whole lines and is being added to.
+    fn store_in_register(&mut self, name: char, text: String, linewise: bool) {
+        let register = name.to_ascii_lowercase();
+        let appending = name.is_ascii_uppercase();
+        if appending && self.linewise_registers.contains(&register) {
+            let contents = self.registers.entry(register).or_default();
+            if !contents.ends_with('\n') {
+                contents.push('\n');
+            }
+            contents.push_str(&text);
+        } else if appending {
+            self.registers.entry(register).or_default().push_str(&text);
+        } else {
+            self.registers.insert(register, text);
+        }
+
+        if linewise {
+            self.linewise_registers.insert(register);
+        } else if !appending {
+            self.linewise_registers.remove(&register);
+        }
+    }
+
+    /// Inserts the contents of the chosen register at the cursor, replacing the selection if there
+    /// is one. Whole lines are inserted above the cursor's line instead.
+    pub fn paste(&mut self) {
+        self.put(false);
+    }
+
+    /// Inserts the contents of the chosen register, as lines below the cursor's line (or above it,
+    /// if `below` is false) if it holds whole lines, or otherwise at the cursor, replacing the
+    /// selection if there is one. After pasting lines, the cursor is at the start of the first.
+    fn put(&mut self, below: bool) {
+        if self.refuse_listing_edit() {
+            return;
+        }
+        let name = self
+            .pending_register
+            .take()
+            .unwrap_or(Editor::UNNAMED_REGISTER)
+            .to_ascii_lowercase();
+        let Some(text) = self.registers.get(&name).cloned() else {
+            self.footer_text = format!("Register {} is empty.", name);
+            return;
+        };
+
+        if self.linewise_registers.contains(&name) {
+            let buffer = self.buffer_mut();
+            buffer.selection_anchor = None;
+            let mut line_idx = buffer.get_logical_cursor_line();
+            if below {
+                line_idx += 1;
+            }
+            if line_idx < buffer.len_lines() {
+                let line_start = buffer.line_to_char(line_idx);
+                buffer.insert_text(line_start, &text);
+                buffer.cursor_idx = line_start;
+            } else {
+                // Below a last line without a line feed, so the lines have to be started with one.
+                let end = buffer.len_chars();
+                buffer.insert_text(
+                    end,
+                    &format!("\n{}", text.strip_suffix('\n').unwrap_or(&text)),
+                );
+                buffer.cursor_idx = end + 1;
+            }
+            buffer.break_undo_group();
+            return;
+        }
+
+        self.insert_raw(&text);
+    }
+
+    /// Inserts `text` at the cursor exactly as it is, replacing the selection if there is one.
+    /// Unlike typing, nothing is auto-indented and no brackets are closed.
+    fn insert_raw(&mut self, text: &str) {
+        let buffer = self.buffer_mut();
+        if let Some(range) = buffer.selection_range() {
+            buffer.delete_range(range);
+        }
+        buffer.selection_anchor = None;
+        let cursor_idx = buffer.cursor_idx;
+        buffer.insert_text(cursor_idx, text);
+    }
+
+    /// Inserts text pasted into the terminal, which it has marked as pasted rather than typed, as a
+    /// single undo step.
+    fn paste_from_terminal(&mut self, text: &str) {
+        if self.refuse_listing_edit() {
+            return;
+        }
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        self.buffer_mut().break_undo_group();
+        self.insert_raw(&text);
+        self.buffer_mut().break_undo_group();
+        self.footer_text = format!("Pasted {} chars verbatim.", text.chars().count());
+    }
+
+    /// Scrolls the viewport so that the cursor's line is at `position` in the text area, without
+    /// scrolling past the end of the file. The cursor stays on screen, so `align_cursor` leaves the
+    /// new viewport alone.
+    pub fn scroll_cursor_to(&mut self, position: ScreenPosition) {
+        let text_rows = self.text_area_height();
+        let cursor_line = self.buffer().get_logical_cursor_line();
+        let origin = match position {
+            ScreenPosition::Top => cursor_line,
+            ScreenPosition::Center => cursor_line.saturating_sub(text_rows / 2),
+            ScreenPosition::Bottom => (cursor_line + 1).saturating_sub(text_rows),
+        };
+        let max_origin = self.max_origin_row();
+        self.buffer_mut().visual_origin_row = min(origin, max_origin);
+    }
+
+    /// Returns the furthest down the viewport can start, which puts the last line of the buffer on
+    /// the bottom row of the text area. A buffer shorter than the text area can't be scrolled.
+    fn max_origin_row(&self) -> usize {
+        self.buffer()
+            .len_lines()
+            .saturating_sub(self.text_area_height())
+    }
+
+    /// Scrolls the viewport and the cursor down (or up, if `forward` is false) by a screenful, less
+    /// `page_overlap` lines so some context stays in view. With `extend_selection`, the selection is extended
+    /// to the new cursor position; otherwise it's cleared.
+    fn page(&mut self, forward: bool, extend_selection: bool) {
+        let text_rows = self.text_area_height();
+        let step = text_rows.saturating_sub(self.config.page_overlap).max(1);
+        let lines = if forward {
+            step as isize
+        } else {
+            -(step as isize)
+        };
+        let line_idx = self.buffer().get_logical_cursor_line();
+        let col = self.buffer().get_logical_cursor_col();
+        self.buffer_mut().prepare_selection(extend_selection);
+        self.scroll_view(lines);
+
+        let buffer = self.buffer_mut();
+        let last_line = buffer.content_lines().saturating_sub(1);
+        let new_line = min(line_idx.saturating_add_signed(lines), last_line);
+        let col = min(col, buffer.line_len(new_line));
+        buffer.cursor_idx = buffer.line_to_char(new_line) + col;
+        buffer.break_undo_group();
+    }
+
+    /// Scrolls the viewport by `lines`, down if positive and up if negative, without scrolling past
+    /// the end of the file. The cursor is pulled along if it would otherwise leave the screen.
+    fn scroll_view(&mut self, lines: isize) {
+        let text_rows = self.text_area_height();
+        if text_rows == 0 {
+            return;
+        }
+
+        let max_origin = self.max_origin_row();
+        let buffer = self.buffer_mut();
+        let origin = buffer.visual_origin_row.saturating_add_signed(lines);
+        buffer.visual_origin_row = min(origin, max_origin);
+
+        let first = buffer.visual_origin_row;
+        let last = first + text_rows - 1;
+        let line_idx = buffer.get_logical_cursor_line();
+        let new_line = line_idx.clamp(first, min(last, buffer.content_lines().saturating_sub(1)));
+        if new_line != line_idx {
+            let col = min(buffer.get_logical_cursor_col(), buffer.line_len(new_line));
+            buffer.cursor_idx = buffer.line_to_char(new_line) + col;
+        }
+    }
+
+    /// Moves the cursor to the line shown at `position` in the text area without scrolling, keeping
+    /// its column where the line is long enough. Past the end of the file, the cursor goes to the
+    /// last line instead.
+    pub fn move_cursor_to(&mut self, position: ScreenPosition) {
+        let text_rows = self.text_area_height();
+        if text_rows == 0 {
+            return;
+        }
+
+        let first = self.buffer().visual_origin_row;
+        let last_line = self.buffer().content_lines().saturating_sub(1);
+        let last = min(first + text_rows - 1, last_line);
+        let line_idx = match position {
+            ScreenPosition::Top => first,
+            ScreenPosition::Center => first + last.saturating_sub(first) / 2,
+            ScreenPosition::Bottom => last,
+        };
+        let line_idx = min(line_idx, last_line);
+
+        let col = min(
+            self.buffer().get_logical_cursor_col(),
+            self.buffer().line_len(line_idx),
+        );
+        self.buffer_mut().cursor_idx = self.buffer().line_to_char(line_idx) + col;
+    }
+
+    /// Selects the word under the cursor if nothing is selected. Otherwise, selects the next
+    /// occurrence of the selected text too, so the next edit typed changes both.
+    fn select_next_occurrence(&mut self) {
+        let buffer = self.buffer_mut();
+        if buffer.selection_range().is_none() {
+            match buffer.word_range_at_cursor() {
+                Some(range) => {
+                    buffer.selection_anchor = Some(range.start);
+                    buffer.cursor_idx = range.end;
+                }
+                None => self.footer_text = String::from("No word under the cursor."),
+            }
+        } else if buffer.add_next_occurrence() {
+            let count = buffer.occurrences.len() + 1;
+            self.footer_text = format!("{} occurrences selected.", count);
+        } else {
+            self.footer_text = String::from("No more occurrences.");
+        }
+    }
+
+    /// Changes the case of the selected text, or of the word under the cursor if nothing is
+    /// selected.
+    fn transform_case(&mut self, op: CaseOp) {
+        let range = self
+            .buffer()
+            .selection_range()
+            .or_else(|| self.buffer().word_range_at_cursor());
+        match range {
+            Some(range) => self.buffer_mut().transform_case(range, op),
+            None => self.footer_text = String::from("No word under the cursor."),
+        }
+    }
+
+    /// Replaces tabs with spaces on the selected lines, or in the whole buffer if there is no
+    /// selection.
+    fn retab(&mut self, all_tabs: bool) {
+        let lines = self.buffer().selected_lines();
+        if !self.confirm_bulk(lines.len()) {
+            return;
+        }
+        self.buffer_mut().retab(lines, all_tabs);
+        self.footer_text = String::from("Replaced tabs with spaces.");
+    }
+
+    /// Switches the line endings the active buffer is saved with.
+    fn set_line_ending(&mut self, line_ending: LineEnding) {
+        let name = match line_ending {
+            LineEnding::Lf => "LF",
+            LineEnding::Crlf => "CRLF",
+        };
+        self.footer_text = if self.buffer_mut().set_line_ending(line_ending) {
+            format!("Line endings will be saved as {}.", name)
+        } else {
+            format!("Line endings are already {}.", name)
+        };
+    }
+
+    /// Ensures the cursor remains on screen at all times by moving the viewport if the cursor has
+    /// gone out-of-bounds since the last input event.
+    pub fn align_cursor(&mut self) {
+        self.buffer_mut().clamp_cursor();
+        let text_rows = self.text_area_height();
+
+        // It doesn't matter where the cursor is in this case because no part of the buffer will be
+        // shown on-screen.
+        if text_rows == 0 {
+            return;
+        }
+
+        let line_idx = self.buffer().char_to_line(self.buffer().cursor_idx);
+        let col_idx = self.buffer().cursor_idx - self.buffer().line_to_char(line_idx);
+
+        // Don't leave the viewport scrolled past the end of the buffer, as it can be once lines near
+        // the end are deleted, so the text area isn't left mostly empty.
+        let max_origin = self.max_origin_row();
+        if self.buffer().visual_origin_row > max_origin {
+            self.buffer_mut().visual_origin_row = max_origin;
+        }
+
+        // If the cursor is above the first visual line, then set the line the cursor is on to be
+        // the first visual line.
+        if line_idx < self.buffer().visual_origin_row {
+            self.buffer_mut().visual_origin_row = line_idx;
+        }
+
+        // Similarly, if the cursor is below the last line, then the last line needs to be the line
+        // the cursor is on.
+        if line_idx >= self.buffer().visual_origin_row + text_rows {
+            self.buffer_mut().visual_origin_row = line_idx + 1 - text_rows;
+        }
+
+        // If the cursor is left of the first column being displayed, then the first column needs to
+        // be the column that the cursor is on.
+        if col_idx < self.buffer().visual_origin_col {
+            self.buffer_mut().visual_origin_col = col_idx;
+        }
+
+        // And finally, if the cursor is right of the last column being displayed, then the last
+        // line needs to be the line that the cursor is on.
+        let text_area_width = self.text_area_width();
+        if col_idx >= self.buffer().visual_origin_col + text_area_width {
+            self.buffer_mut().visual_origin_col = col_idx + 1 - text_area_width;
+        }
+    }
+
+    pub fn clear_terminal(&mut self) -> std::io::Result<()> {
+        execute!(self.output, Clear(ClearType::All))?;
+        execute!(self.output, MoveTo(0, 0))?;
+        self.output.flush()?;
+        Ok(())
+    }
+
+    /// Prompt the user for some input, and return that input as a string. The prompt will appear in
+    /// the footer bar, a la Vim.
+    pub fn editor_prompt(&mut self, prompt_text: &str) -> Option<String> {
+        // Nothing can answer a prompt during a macro, so act as if it was cancelled.
+        if self.replaying {
+            return None;
+        }
+
+        self.footer_text = prompt_text.to_owned();
+        let mut user_input = String::new();
+
+        let (cols, rows) = self.terminal_size().unwrap();
+
+        loop {
+            // Keep the end of the input in view on narrow terminals, cutting off the start of the
+            // prompt first, and leave a column for the caret.
+            let full_text = format!("{}{}", prompt_text, user_input);
+            self.footer_text = tail_to_width(&full_text, (cols as usize).saturating_sub(1));
+            self.render().ok();
+            let caret_col = min(self.footer_text.width(), (cols as usize).saturating_sub(1));
+            let _ = execute!(
+                self.output,
+                MoveTo(caret_col as u16, rows.saturating_sub(1))
+            );
+            let _ = self.output.flush();
+
+            match self.read_event() {
+                Ok(Event::Key(key_event)) if key_event.kind != KeyEventKind::Release => {
+                    match key_event.code {
+                        KeyCode::Char(x) => {
+                            user_input.push(x);
+                        }
+                        KeyCode::Backspace => {
+                            user_input.pop();
+                        }
+                        KeyCode::Enter => {
+                            self.footer_text.clear();
+                            return Some(user_input);
+                        }
+                        KeyCode::Esc => {
+                            self.footer_text.clear();
+                            return None;
+                        }
+                        _ => {}
+                    }
+                }
+                Err(_) => return None,
+                _ => {}
+            }
+        }
+    }
+
+    /// Handles one input event from the main loop, then brings the cursor back into view and logs
+    /// the key if logging is on. Returns true if the editor should quit.
+    fn handle_event(&mut self, event: Event) -> bool {
+        let mut handled_key = None;
+        match event {
+            Event::Key(key_event) => {
+                if self.handle_key_event(key_event) {
+                    return true;
+                }
+                handled_key = Some(key_event);
+            }
+            Event::Paste(text) => self.paste_from_terminal(&text),
+            Event::FocusLost if self.config.save_on_focus_lost => {
+                self.autosave(0..self.buffers.len());
+            }
+            Event::Mouse(mouse_event) => {
+                let lines = self.config.scroll_lines as isize;
+                match mouse_event.kind {
+                    MouseEventKind::ScrollDown => self.scroll_view(lines),
+                    MouseEventKind::ScrollUp => self.scroll_view(-lines),
+                    _ => {}
+                }
+            }
+            Event::Resize(w, h) if self.terminal_size.is_none() => self.resize(w, h),
+            _ => {}
+        }
+        // Start the delay once the event has been handled, which may have taken a while if it
+        // opened a prompt.
+        self.line_changes.touch();
+
+        // After every input event, we need to ensure that the cursor remains on screen.
+        self.align_cursor();
+        #[cfg(feature = "lsp")]
+        self.show_diagnostic_at_cursor();
+
+        if let Some(logger) = &mut self.logger
+            && let Some(key_event) = handled_key
+        {
+            let buffer = &self.buffers[self.active_buffer];
+            let origin = (buffer.visual_origin_row, buffer.visual_origin_col);
+            logger.log_key(key_event, buffer.cursor_idx, origin);
+        }
+        false
+    }
+
+    pub fn mainloop(&mut self) -> std::io::Result<()> {
+        #[cfg(feature = "lsp")]
+        self.sync_language_server();
+        loop {
+            self.update_window_title()?;
+            self.render()?;
+            self.output.flush()?;
+
+            // Wake up once input pauses so any debounced work gets done and drawn, and every so
+            // often to check for files changed on disk.
+            let decorations_due = self.line_changes.time_until_due();
+            let timeout = decorations_due.map_or(Self::DISK_CHECK_INTERVAL, |due| {
+                due.min(Self::DISK_CHECK_INTERVAL)
+            });
+            // Language servers send diagnostics whenever they're ready, so check for them often.
+            #[cfg(feature = "lsp")]
+            let timeout = if self.language_servers.values().any(Option::is_some) {
+                timeout.min(Self::LANGUAGE_SERVER_POLL_INTERVAL)
+            } else {
+                timeout
+            };
+            if !poll(timeout)? {
+                if self
+                    .line_changes
+                    .time_until_due()
+                    .is_some_and(|due| due.is_zero())
+                {
+                    self.refresh_decorations();
+                }
+                self.check_disk_changes();
+                #[cfg(feature = "lsp")]
+                self.receive_from_language_servers();
+                continue;
+            }
+
+            let event = self.read_event()?;
+            if self.handle_event(event) {
+                break;
+            }
+        }
+
+        disable_raw_mode()?;
+        if self.config.mouse_scroll {
+            execute!(self.output, DisableMouseCapture)?;
+        }
+        execute!(
+            self.output,
+            DisableFocusChange,
+            DisableBracketedPaste,
+            ResetColor,
+            Show
+        )?;
+        self.leave_screen()?;
+        if self.window_title.is_some() {
+            execute!(self.output, SetTitle(""))?;
+        }
+        if self.cursor_style.is_some() {
+            execute!(self.output, SetCursorStyle::DefaultUserShape)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the cursor shape for the active buffer's typing mode if it has changed since it was last
+    /// set. Does nothing unless `set_cursor_shape` is enabled.
+    fn update_cursor_style(&mut self) -> std::io::Result<()> {
+        if !self.config.set_cursor_shape {
+            return Ok(());
+        }
+
+        let style = if self.buffer().overwrite || self.mode == Mode::Normal {
+            self.config.overwrite_cursor_shape
+        } else {
+            self.config.insert_cursor_shape
+        };
+        if self.cursor_style != Some(style) {
+            execute!(self.output, style)?;
+            self.cursor_style = Some(style);
+        }
+        Ok(())
+    }
+
+    /// Sets the terminal window title to the name of the active buffer's file if it has changed
+    /// since it was last set. Does nothing unless `set_window_title` is enabled.
+    fn update_window_title(&mut self) -> std::io::Result<()> {
+        if !self.config.set_window_title {
+            return Ok(());
+        }
+
+        let title = format!("editor \u{2014} {}", self.buffer().file_name());
+        if self.window_title.as_ref() != Some(&title) {
+            execute!(self.output, SetTitle(&title))?;
+            self.window_title = Some(title);
+        }
+        Ok(())
+    }
+}
+
+/// Parses a hexadecimal Unicode code point such as `e9`, `0xE9` or `U+00E9`. Returns None for
+/// anything that isn't a valid `char`, including surrogates.
+fn parse_code_point(text: &str) -> Option<char> {
+    let digits = text
+        .strip_prefix("U+")
+        .or_else(|| text.strip_prefix("u+"))
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text);
+    u32::from_str_radix(digits, 16)
+        .ok()
+        .and_then(char::from_u32)
+}
+
+/// Returns the footer message for an error from `Buffer::save_file`.
+fn save_error_message(err: &std::io::Error) -> String {
+    if err.get_ref().is_some_and(|inner| inner.is::<SyncError>()) {
+        String::from("File written, but syncing it to disk failed.")
+    } else {
+        String::from("File save failed. Please try again.")
+    }
+}
+
+/// Returns the line number shown for line `line_idx` in `mode` when the cursor is on line
+/// `cursor_line`. Both are counted from 0, but absolute line numbers count from 1.
+fn line_number_label(mode: LineNumbers, line_idx: usize, cursor_line: usize) -> String {
+    match mode {
+        LineNumbers::Off => String::new(),
+        LineNumbers::Hybrid if line_idx == cursor_line => (line_idx + 1).to_string(),
+        LineNumbers::Relative | LineNumbers::Hybrid => line_idx.abs_diff(cursor_line).to_string(),
+        LineNumbers::Absolute => (line_idx + 1).to_string(),
+    }
+}
+
+/// Returns the longest suffix of `text` that fits in `width` columns.
+fn tail_to_width(text: &str, width: usize) -> String {
+    let mut used = 0;
+    let mut tail: Vec<char> = text
+        .chars()
+        .rev()
+        .take_while(|c| {
+            used += c.width().unwrap_or(0);
+            used <= width
+        })
+        .collect();
+    tail.reverse();
+    tail.into_iter().collect()
+}
+
+/// Returns the longest prefix of `text` that fits in `width` columns.
+fn truncate_to_width(text: &str, width: usize) -> String {
+    let mut used = 0;
+    text.chars()
+        .take_while(|c| {
+            used += c.width().unwrap_or(0);
+            used <= width
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::BufferSettings;
+
+    /// Creates a headless editor around an unnamed buffer holding `text`.
+    fn editor_with(text: &str) -> Editor {
+        Editor::new_headless(Buffer::from_str(text, BufferSettings::default()), (80, 24))
+    }
+
+    fn press(editor: &mut Editor, code: KeyCode) -> bool {
+        editor.handle_key_event(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    /// Creates an empty directory for a test's files, named after the test.
+    fn fixture_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("editor-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn key_events(text: &str) -> Vec<Event> {
+        text.chars()
+            .map(|c| Event::Key(KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE)))
+            .chain(std::iter::once(Event::Key(KeyEvent::new(
+                KeyCode::Enter,
+                KeyModifiers::NONE,
+            ))))
+            .collect()
+    }
+
+    #[test]
+    fn headless_editor_types_and_draws_to_memory() {
+        let mut editor = editor_with("world\n");
+        for c in "hello ".chars() {
+            press(&mut editor, KeyCode::Char(c));
+        }
+        assert_eq!(editor.buffer().text(), "hello world\n");
+        assert_eq!(editor.buffer().cursor_idx(), 6);
+        assert_eq!(editor.buffer().viewport(), (0, 0, 80, 24));
+
+        editor.render().unwrap();
+        assert!(editor.take_output().contains("hello world"));
+        assert!(editor.take_output().is_empty());
+    }
+
+    #[test]
+    fn prompts_read_scripted_input() {
+        let mut editor = editor_with("");
+        editor.script_input(key_events("yes"));
+        assert_eq!(editor.editor_prompt("? ").as_deref(), Some("yes"));
+        // Running out of input cancels the prompt rather than blocking.
+        assert_eq!(editor.editor_prompt("? "), None);
+    }
+
+    #[test]
+    fn quitting_asks_about_unsaved_changes_in_any_buffer() {
+        let mut editor = editor_with("");
+        let mut other = Buffer::from_str("", BufferSettings::default());
+        other.file_path = PathBuf::from("notes.txt");
+        other.dirty_buffer = true;
+        editor.buffers.push(other);
+
+        editor.script_input(key_events("n"));
+        assert!(!editor.attempt_exit(false));
+        assert!(
+            editor
+                .take_output()
+                .contains("Unsaved changes in notes.txt.")
+        );
+
+        editor.script_input(key_events("y"));
+        assert!(editor.attempt_exit(false));
+        assert!(editor.attempt_exit(true));
+    }
+
+    #[test]
+    fn f10_asks_before_discarding_changes() {
+        let mut editor = editor_with("");
+        press(&mut editor, KeyCode::Char('x'));
+        editor.script_input(key_events("n"));
+        assert!(!press(&mut editor, KeyCode::F(10)));
+
+        editor.buffer_mut().dirty_buffer = false;
+        assert!(press(&mut editor, KeyCode::F(10)));
+    }
+
+    /// Creates a headless editor in Normal mode around `text`.
+    fn modal_editor_with(text: &str) -> Editor {
+        let mut editor = editor_with(text);
+        editor.config.modal = true;
+        editor.mode = Mode::Normal;
+        editor
+    }
+
+    #[test]
+    fn a_count_repeats_the_next_movement_once() {
+        let mut editor = modal_editor_with("0\n1\n2\n3\n4\n5\n6\n");
+        press(&mut editor, KeyCode::Char('3'));
+        assert_eq!(editor.pending_count, Some(3));
+        press(&mut editor, KeyCode::Char('j'));
+        assert_eq!(editor.buffer().get_logical_cursor_line(), 3);
+        assert_eq!(editor.pending_count, None);
+
+        press(&mut editor, KeyCode::Char('j'));
+        assert_eq!(editor.buffer().get_logical_cursor_line(), 4);
+
+        // Esc drops a count that hasn't been used.
+        press(&mut editor, KeyCode::Char('2'));
+        press(&mut editor, KeyCode::Esc);
+        press(&mut editor, KeyCode::Char('k'));
+        assert_eq!(editor.buffer().get_logical_cursor_line(), 3);
+    }
+
+    #[test]
+    fn move_command_moves_the_given_number_of_lines() {
+        let mut editor = editor_with("0\n1\n2\n3\n4\n");
+        editor.run_command("move 3 down");
+        assert_eq!(editor.buffer().get_logical_cursor_line(), 3);
+        editor.run_command("move 2 up");
+        assert_eq!(editor.buffer().get_logical_cursor_line(), 1);
+    }
+
+    #[test]
+    fn huge_counts_stop_when_nothing_more_happens() {
+        let mut editor = modal_editor_with("one\ntwo\nthree\n");
+        for c in "99999999999j".chars() {
+            press(&mut editor, KeyCode::Char(c));
+        }
+        assert_eq!(editor.buffer().get_logical_cursor_line(), 2);
+        for c in "99999999999z".chars() {
+            press(&mut editor, KeyCode::Char(c));
+        }
+        assert_eq!(editor.buffer().text(), "one\ntwo\nthree\n");
+    }
+
+    #[test]
+    fn move_command_stops_at_the_end_and_checks_its_direction() {
+        let mut editor = editor_with("one\ntwo\nthree\n");
+        editor.run_command("move 99999999999 down");
+        assert_eq!(editor.buffer().get_logical_cursor_line(), 2);
+        editor.run_command("move 3 sideways");
+        assert_eq!(editor.footer_text, "Unknown direction: sideways");
+    }
+
+    #[test]
+    fn without_the_alternate_screen_the_shell_output_is_kept() {
+        let mut editor = editor_with("");
+        editor.config.use_alternate_screen = false;
+
+        editor.enter_screen().unwrap();
+        let output = editor.take_output();
+        assert_eq!(output, format!("{}\x1b[1;1H", "\r\n".repeat(24)));
+
+        editor.leave_screen().unwrap();
+        let output = editor.take_output();
+        assert_eq!(output, "\x1b[1;1H\x1b[J");
+        assert!(!output.contains("\x1b[2J"));
+    }
+
+    #[test]
+    fn the_alternate_screen_is_entered_and_left() {
+        let mut editor = editor_with("");
+        editor.enter_screen().unwrap();
+        assert_eq!(editor.take_output(), "\x1b[?1049h");
+        editor.leave_screen().unwrap();
+        assert_eq!(editor.take_output(), "\x1b[?1049l");
+    }
+
+    #[test]
+    fn reflow_on_a_blank_line_reports_no_paragraph() {
+        let mut editor = editor_with("\nsome text\n");
+        editor.run_command("reflow");
+        assert_eq!(editor.footer_text, "No paragraph at the cursor.");
+        assert_eq!(editor.buffer().text(), "\nsome text\n");
+    }
+
+    #[test]
+    fn editing_keys_do_not_edit_in_normal_mode() {
+        let mut editor = modal_editor_with("one\ntwo\n");
+        for code in [KeyCode::Delete, KeyCode::Tab, KeyCode::Enter] {
+            press(&mut editor, code);
+        }
+        assert_eq!(editor.buffer().text(), "one\ntwo\n");
+        assert_eq!(editor.buffer().get_logical_cursor_pos(), (1, 0));
+
+        press(&mut editor, KeyCode::Char('l'));
+        press(&mut editor, KeyCode::Backspace);
+        assert_eq!(editor.buffer().text(), "one\ntwo\n");
+        assert_eq!(editor.buffer().get_logical_cursor_pos(), (1, 0));
+    }
+
+    #[test]
+    fn huge_counts_saturate_when_adding_to_numbers() {
+        let mut editor = modal_editor_with("5\n");
+        for _ in 0..25 {
+            press(&mut editor, KeyCode::Char('9'));
+        }
+        editor.handle_key_event(KeyEvent::new(KeyCode::Char('a'), KeyModifiers::CONTROL));
+        assert_eq!(editor.buffer().text(), format!("{}\n", i64::MAX));
+    }
+
+    #[test]
+    fn directory_listings_refuse_edits() {
+        let mut editor = editor_with("");
+        editor.browse(std::env::temp_dir());
+        assert!(editor.buffer().listing.is_some());
+        let listing = editor.buffer().text();
+
+        for command in [
+            "sort reverse",
+            "upper",
+            "reflow",
+            "insert-char 41",
+            "unix2dos",
+        ] {
+            editor.footer_text.clear();
+            editor.run_command(command);
+            assert_eq!(editor.footer_text, "Directory listings can't be edited.");
+        }
+        editor.run_command("paste");
+        press(&mut editor, KeyCode::Char('x'));
+        press(&mut editor, KeyCode::Esc);
+        editor.paste_from_terminal("pasted");
+        editor
+            .registers
+            .insert(Editor::UNNAMED_REGISTER, String::from("put"));
+        editor.paste();
+        assert_eq!(editor.buffer().text(), listing);
+    }
+
+    #[test]
+    fn focus_mode_dims_lines_outside_the_cursors_paragraph() {
+        let mut editor = editor_with("one\ntwo\n\nthree\nfour\n\nfive\n");
+        editor.color_support = ColorSupport::Ansi256;
+        editor.buffer_mut().go_to_position(4, 0);
+        assert_eq!(editor.focused_lines(), None);
+
+        editor.config.focus_mode = true;
+        assert_eq!(editor.focused_lines(), Some(3..5));
+
+        editor.render().unwrap();
+        let output = editor.take_output();
+        let dim = "\x1b[38;5;242m";
+        let row = |text: &str| {
+            let end = output.find(text).unwrap();
+            let start = output[..end].rfind(";1H").unwrap();
+            &output[start..end]
+        };
+        for text in ["one", "two", "five"] {
+            assert!(row(text).contains(dim), "{} isn't dimmed", text);
+        }
+        for text in ["three", "four"] {
+            assert!(!row(text).contains(dim), "{} is dimmed", text);
+        }
+    }
+
+    #[test]
+    fn change_navigation_jumps_between_hunks() {
+        let mut editor = editor_with("a\nb\nc\nd\ne\nf\n");
+        editor.go_to_change(true);
+        assert_eq!(editor.footer_text, "No changes.");
+
+        editor.buffer_mut().insert_text(2, "changed ");
+        editor.buffer_mut().insert_text(16, "new line\n");
+        editor.buffer_mut().go_to_position(0, 0);
+        assert_eq!(editor.buffer_mut().hunk_starts(), vec![1, 4]);
+
+        let line = |editor: &Editor| editor.buffer().get_logical_cursor_line();
+        editor.config.wrap_change_navigation = true;
+        editor.go_to_change(true);
+        assert_eq!(line(&editor), 1);
+        editor.go_to_change(true);
+        assert_eq!(line(&editor), 4);
+        editor.go_to_change(true);
+        assert_eq!(line(&editor), 1);
+        assert_eq!(editor.footer_text, "Wrapped to first change.");
+        editor.go_to_change(false);
+        assert_eq!(line(&editor), 4);
+        assert_eq!(editor.footer_text, "Wrapped to last change.");
+
+        editor.config.wrap_change_navigation = false;
+        editor.go_to_change(true);
+        assert_eq!(line(&editor), 4);
+        assert_eq!(editor.footer_text, "No more changes.");
+        editor.go_to_change(false);
+        assert_eq!(line(&editor), 1);
+    }
+
+    #[test]
+    fn write_saves_the_selection_relative_to_the_startup_directory() {
+        let dir = fixture_dir("write-selection");
+        let mut editor = editor_with("one\r\ntwo\r\nthree\r\n");
+        editor.startup_dir = dir.clone();
+        editor.buffer_mut().select(4..14);
+
+        editor.run_command("write part.txt");
+        let written = std::fs::read(dir.join("part.txt")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(editor.footer_text, "Wrote part.txt.");
+        assert_eq!(written, b"two\r\nthree\r\n");
+        assert!(editor.buffer().file_path.as_os_str().is_empty());
+        assert!(!editor.buffer().dirty_buffer);
+    }
+
+    #[test]
+    fn line_ending_commands_set_the_endings_written_on_save() {
+        let dir = fixture_dir("line-endings");
+        let path = dir.join("file.txt");
+        let mut editor = editor_with("one\ntwo\n");
+        editor.buffer_mut().file_path = path.clone();
+
+        editor.run_command("unix2dos");
+        assert_eq!(editor.footer_text, "Line endings will be saved as CRLF.");
+        assert_eq!(editor.buffer().text(), "one\ntwo\n");
+        editor.buffer_mut().save_file().unwrap();
+        let crlf = std::fs::read(&path).unwrap();
+
+        editor.run_command("unix2dos");
+        assert_eq!(editor.footer_text, "Line endings are already CRLF.");
+        editor.run_command("dos2unix");
+        assert_eq!(editor.footer_text, "Line endings will be saved as LF.");
+        editor.buffer_mut().save_file().unwrap();
+        let lf = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(crlf, b"one\r\ntwo\r\n");
+        assert_eq!(lf, b"one\ntwo\n");
+    }
+
+    #[test]
+    fn dos2unix_evens_out_mixed_line_endings() {
+        let mut editor = editor_with("one\r\ntwo\nthree\r\n");
+        assert!(editor.buffer().mixed_line_endings);
+        editor.run_command("dos2unix");
+        assert_eq!(editor.footer_text, "Line endings will be saved as LF.");
+        assert!(!editor.buffer().mixed_line_endings);
+        assert_eq!(editor.buffer().line_ending, LineEnding::Lf);
+        assert!(editor.buffer().dirty_buffer);
+    }
+
+    #[test]
+    fn logging_writes_a_line_for_each_handled_key() {
+        let dir = fixture_dir("key-log");
+        let path = dir.join("keys.log");
+        let mut editor = editor_with("abc\n");
+        editor.enable_logging(&path).unwrap();
+        for code in [KeyCode::Right, KeyCode::Char('x'), KeyCode::End] {
+            editor.handle_event(Event::Key(KeyEvent::new(code, KeyModifiers::NONE)));
+        }
+        editor.handle_event(Event::Paste(String::from("ignored")));
+        let log = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            log,
+            "Right KeyModifiers(0x0) cursor_idx=1 visual_origin=0,0\n\
+             Char('x') KeyModifiers(0x0) cursor_idx=2 visual_origin=0,0\n\
+             End KeyModifiers(0x0) cursor_idx=4 visual_origin=0,0\n"
+        );
+    }
+
+    #[test]
+    fn huge_lines_are_drawn_from_the_visible_part_only() {
+        let mut editor = editor_with(&"0123456789".repeat(100_000));
+        editor.buffer_mut().cursor_idx = 500_005;
+        editor.align_cursor();
+        let width = editor.text_area_width();
+        assert_eq!(editor.buffer().visual_origin_col, 500_006 - width);
+        assert_eq!(editor.buffer().get_visual_cursor_col(), width - 1);
+
+        editor.render().unwrap();
+        let output = editor.take_output();
+        assert!(output.contains(&"0123456789".repeat(5)));
+        assert!(output.len() < 4096, "drew {} bytes", output.len());
+    }
+
+    /// Creates a headless editor with unnamed buffers holding each of `texts`, the first active.
+    fn editor_with_buffers(texts: &[&str]) -> Editor {
+        let mut editor = editor_with(texts[0]);
+        for text in &texts[1..] {
+            let buffer = Buffer::from_str(text, BufferSettings::default());
+            editor.buffers.push(buffer);
+        }
+        editor
+    }
+
+    #[test]
+    fn alternate_buffer_switches_back_and_forth() {
+        let mut editor = editor_with_buffers(&["a", "b", "c"]);
+        editor.alternate_buffer();
+        assert_eq!(editor.footer_text, "No other buffer to switch to.");
+
+        editor.set_active_buffer(1);
+        editor.alternate_buffer();
+        assert_eq!(editor.active_buffer, 0);
+        editor.alternate_buffer();
+        assert_eq!(editor.active_buffer, 1);
+    }
+
+    #[test]
+    fn alternate_buffer_falls_back_when_the_previous_one_closes() {
+        // b -> c, then closing b moves c into its slot.
+        let mut editor = editor_with_buffers(&["a", "b", "c"]);
+        editor.set_active_buffer(1);
+        editor.set_active_buffer(2);
+        editor.remove_buffer(1);
+        assert_eq!(editor.buffer().text(), "c");
+        editor.alternate_buffer();
+        assert_eq!(editor.buffer().text(), "a");
+
+        // The only other buffer closing leaves nothing to switch to.
+        let mut editor = editor_with_buffers(&["a", "b"]);
+        editor.set_active_buffer(1);
+        editor.remove_buffer(0);
+        assert_eq!(editor.previous_buffer, None);
+    }
+
+    #[test]
+    fn opening_from_a_listing_keeps_the_alternate_buffer() {
+        let dir = fixture_dir("listing-alternate");
+        std::fs::write(dir.join("file.txt"), "file\n").unwrap();
+        let mut editor = editor_with("first\n");
+        editor.browse(dir.clone());
+        editor.buffer_mut().go_to_position(1, 0);
+        editor.open_listing_entry();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(editor.buffer().text(), "file\n");
+        editor.alternate_buffer();
+        assert_eq!(editor.buffer().text(), "first\n");
+    }
+
+    #[test]
+    fn a_forced_size_sets_the_number_of_text_rows() {
+        let text: String = (0..20).map(|idx| format!("line {}\n", idx)).collect();
+        let mut editor =
+            Editor::new_headless(Buffer::from_str(&text, BufferSettings::default()), (40, 10));
+        assert_eq!(editor.buffer().viewport(), (0, 0, 40, 10));
+        assert_eq!(editor.text_area_height(), 8);
+
+        editor.render().unwrap();
+        let output = editor.take_output();
+        assert!(output.contains("line 7 "));
+        assert!(!output.contains("line 8 "));
+    }
+
+    #[test]
+    fn line_numbers_count_from_the_cursor_in_relative_and_hybrid_modes() {
+        let labels = |mode| {
+            (0..10)
+                .map(|line_idx| line_number_label(mode, line_idx, 4))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!(labels(LineNumbers::Absolute), "1 2 3 4 5 6 7 8 9 10");
+        assert_eq!(labels(LineNumbers::Relative), "4 3 2 1 0 1 2 3 4 5");
+        assert_eq!(labels(LineNumbers::Hybrid), "4 3 2 1 5 1 2 3 4 5");
+        assert_eq!(labels(LineNumbers::Off).trim(), "");
+    }
+
+    #[test]
+    fn declining_a_bulk_edit_leaves_the_buffer_alone() {
+        let mut editor = editor_with("c\nb\na\n");
+        editor.config.confirm_bulk_edits = true;
+        editor.config.bulk_edit_threshold = 3;
+
+        editor.script_input(key_events("n"));
+        editor.run_command("sort");
+        assert_eq!(editor.footer_text, "Cancelled.");
+        assert_eq!(editor.buffer().text(), "c\nb\na\n");
+        assert!(!editor.buffer().dirty_buffer);
+
+        editor.script_input(key_events("y"));
+        editor.run_command("sort");
+        assert_eq!(editor.buffer().text(), "a\nb\nc\n");
+
+        // Fewer lines than the threshold go ahead without asking.
+        editor.buffer_mut().select(0..4);
+        editor.run_command("sort reverse");
+        assert_eq!(editor.buffer().text(), "b\na\nc\n");
+    }
+
+    #[test]
+    fn find_char_keys_repeat_and_report_missing_chars() {
+        let mut editor = modal_editor_with("a,b,c,d\n");
+        for c in "f,;".chars() {
+            press(&mut editor, KeyCode::Char(c));
+        }
+        assert_eq!(editor.buffer().cursor_idx(), 3);
+        press(&mut editor, KeyCode::Char(','));
+        assert_eq!(editor.buffer().cursor_idx(), 1);
+
+        press(&mut editor, KeyCode::Char('f'));
+        press(&mut editor, KeyCode::Char('z'));
+        assert_eq!(editor.footer_text, "Character not found: z");
+        assert_eq!(editor.buffer().cursor_idx(), 1);
+    }
+
+    #[test]
+    fn short_files_never_scroll() {
+        let buffer = Buffer::from_str("1\n2\n3\n4\n5\n", BufferSettings::default());
+        let mut editor = Editor::new_headless(buffer, (80, 20));
+        editor.scroll_view(10);
+        assert_eq!(editor.buffer().visual_origin_row, 0);
+        press(&mut editor, KeyCode::PageDown);
+        editor.handle_key_event(KeyEvent::new(KeyCode::End, KeyModifiers::CONTROL));
+        editor.align_cursor();
+        assert_eq!(editor.buffer().visual_origin_row, 0);
+    }
+
+    #[test]
+    fn scrolling_stops_with_the_last_line_at_the_bottom() {
+        let text: String = (1..=100).map(|n| format!("line {}\n", n)).collect();
+        let buffer = Buffer::from_str(&text, BufferSettings::default());
+        let mut editor = Editor::new_headless(buffer, (80, 20));
+        let text_rows = editor.text_area_height();
+        // 100 lines and the empty one after the last line feed.
+        let bottom_origin = 101 - text_rows;
+
+        editor.scroll_view(1000);
+        assert_eq!(editor.buffer().visual_origin_row, bottom_origin);
+
+        // Deleting lines near the end pulls the view back so it isn't left mostly empty.
+        editor.buffer_mut().set_text(&text[..text.len() / 2]);
+        editor.align_cursor();
+        assert_eq!(
+            editor.buffer().visual_origin_row,
+            editor.buffer().len_lines() - text_rows
+        );
+    }
+
+    #[test]
+    fn block_comments_wrap_and_unwrap_the_selection() {
+        for (name, text, commented) in [
+            (
+                "style.css",
+                "a { color: red; }\n",
+                "/* a { color: red; } */\n",
+            ),
+            ("page.html", "<p>hi</p>\n", "<!-- <p>hi</p> -->\n"),
+            ("main.rs", "let x = 1;\n", "/* let x = 1; */\n"),
+        ] {
+            let mut editor = editor_with(text);
+            editor.buffer_mut().file_path = PathBuf::from(name);
+            editor.buffer_mut().select(0..text.len() - 1);
+            editor.toggle_block_comment();
+            assert_eq!(editor.buffer().text(), commented, "{}", name);
+
+            // The comment is left selected, so toggling again unwraps it.
+            editor.toggle_block_comment();
+            assert_eq!(editor.buffer().text(), text, "{}", name);
+        }
+    }
+
+    #[test]
+    fn block_comments_fall_back_to_line_comments() {
+        let mut editor = editor_with("x = 1\n");
+        editor.buffer_mut().file_path = PathBuf::from("script.py");
+        editor.toggle_block_comment();
+        assert_eq!(editor.buffer().text(), "# x = 1\n");
+
+        editor.buffer_mut().file_path = PathBuf::from("notes.txt");
+        editor.toggle_block_comment();
+        assert_eq!(
+            editor.footer_text,
+            "No comment syntax known for this file type."
+        );
+    }
+
+    #[test]
+    fn go_tabs_are_drawn_wider_than_rust_tabs() {
+        let dir = fixture_dir("tab-width");
+        std::fs::write(dir.join("main.go"), "\tgo()\n").unwrap();
+        std::fs::write(dir.join("main.rs"), "\trs()\n").unwrap();
+        let mut editor = editor_with("");
+        let mut drawn = |name: &str| {
+            editor.open_buffer(dir.join(name));
+            editor.buffer_mut().cursor_idx = 1;
+            editor.align_cursor();
+            editor.render().unwrap();
+            (
+                editor.take_output(),
+                editor.buffer().get_visual_cursor_col(),
+            )
+        };
+        let (go, go_col) = drawn("main.go");
+        let (rust, rust_col) = drawn("main.rs");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!((go_col, rust_col), (8, 4));
+        assert!(go.contains("        go()"));
+        assert!(rust.contains("    rs()"));
+        assert!(!rust.contains("     rs()"));
+    }
+
+    #[test]
+    fn yanked_lines_are_put_as_whole_lines_above_or_below() {
+        let mut editor = modal_editor_with("one\ntwo\nthree\n");
+        editor.buffer_mut().cursor_idx = 5;
+        for c in "yyp".chars() {
+            press(&mut editor, KeyCode::Char(c));
+        }
+        assert_eq!(editor.buffer().text(), "one\ntwo\ntwo\nthree\n");
+        assert_eq!(editor.buffer().cursor_idx(), 8);
+
+        editor.buffer_mut().cursor_idx = 1;
+        press(&mut editor, KeyCode::Char('P'));
+        assert_eq!(editor.buffer().text(), "two\none\ntwo\ntwo\nthree\n");
+        assert_eq!(editor.buffer().cursor_idx(), 0);
+    }
+
+    #[test]
+    fn lines_put_below_a_last_line_without_a_line_feed_start_a_new_line() {
+        let mut editor = modal_editor_with("a\nb");
+        editor.buffer_mut().cursor_idx = 0;
+        for c in "yyjp".chars() {
+            press(&mut editor, KeyCode::Char(c));
+        }
+        assert_eq!(editor.buffer().text(), "a\nb\na");
+        assert_eq!(editor.buffer().get_logical_cursor_line(), 2);
+        assert_eq!(editor.buffer().get_logical_cursor_col(), 0);
+    }
+
+    #[test]
+    fn held_keys_repeat_but_releases_are_ignored() {
+        let mut editor = editor_with("abc\n");
+        let event = |kind| KeyEvent::new_with_kind(KeyCode::Right, KeyModifiers::NONE, kind);
+        editor.handle_key_event(event(KeyEventKind::Press));
+        editor.handle_key_event(event(KeyEventKind::Repeat));
+        editor.handle_key_event(event(KeyEventKind::Repeat));
+        assert_eq!(editor.buffer().cursor_idx(), 3);
+        editor.handle_key_event(event(KeyEventKind::Release));
+        assert_eq!(editor.buffer().cursor_idx(), 3);
+
+        let typed =
+            KeyEvent::new_with_kind(KeyCode::Char('x'), KeyModifiers::NONE, KeyEventKind::Repeat);
+        editor.handle_key_event(typed);
+        assert_eq!(editor.buffer().text(), "abcx\n");
+    }
+
+    #[test]
+    fn raw_inserts_keep_newlines_without_indenting() {
+        let mut editor = editor_with("    if x {\n");
+        editor.buffer_mut().settings.auto_indent = true;
+        editor.buffer_mut().cursor_idx = 10;
+
+        editor.run_command("paste");
+        assert!(editor.paste_mode);
+        for code in [
+            KeyCode::Enter,
+            KeyCode::Char('a'),
+            KeyCode::Enter,
+            KeyCode::Char('}'),
+        ] {
+            press(&mut editor, code);
+        }
+        assert_eq!(editor.buffer().text(), "    if x {\na\n}\n");
+        press(&mut editor, KeyCode::Esc);
+        assert!(!editor.paste_mode);
+
+        editor.paste_from_terminal("\r\nb\r\nc");
+        assert_eq!(editor.buffer().text(), "    if x {\na\n}\nb\nc\n");
+        assert_eq!(editor.footer_text, "Pasted 4 chars verbatim.");
+    }
+
+    #[test]
+    fn esc_clears_the_selection_and_the_footer() {
+        let mut editor = editor_with("ab ab ab\n");
+        editor.buffer_mut().select(0..2);
+        assert!(editor.buffer_mut().add_next_occurrence());
+        editor.footer_text = String::from("Saved.");
+
+        press(&mut editor, KeyCode::Esc);
+        assert_eq!(editor.buffer().selection_range(), None);
+        assert!(editor.buffer().occurrences.is_empty());
+        assert_eq!(editor.footer_text, "");
+        assert_eq!(editor.buffer().text(), "ab ab ab\n");
+    }
+
+    #[test]
+    fn esc_in_a_prompt_only_cancels_the_prompt() {
+        let mut editor = editor_with("abc\n");
+        editor.buffer_mut().select(0..2);
+        editor.script_input([Event::Key(KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE))]);
+        press(&mut editor, KeyCode::F(1));
+        assert_eq!(editor.buffer().selection_range(), Some(0..2));
+    }
+
+    #[test]
+    fn a_cursor_past_the_end_is_pulled_back_before_drawing() {
+        let mut editor = editor_with("abc\n");
+        editor.buffer_mut().cursor_idx = 100;
+        editor.buffer_mut().selection_anchor = Some(50);
+        editor.render().unwrap();
+        assert_eq!(editor.buffer().cursor_idx(), 4);
+        assert_eq!(editor.buffer().selection_anchor, Some(4));
+
+        editor.buffer_mut().cursor_idx = 100;
+        editor.align_cursor();
+        assert_eq!(editor.buffer().cursor_idx(), 4);
+        press(&mut editor, KeyCode::Left);
+        assert_eq!(editor.buffer().cursor_idx(), 3);
+    }
+
+    #[test]
+    fn insert_char_inserts_a_code_point_after_the_cursor() {
+        let mut editor = editor_with("cafe\n");
+        editor.buffer_mut().cursor_idx = 3;
+        editor.run_command("insert-char U+00E9");
+        assert_eq!(editor.buffer().text(), "cafée\n");
+        assert_eq!(editor.buffer().cursor_idx(), 4);
+    }
+
+    #[test]
+    fn insert_char_rejects_invalid_code_points() {
+        let mut editor = editor_with("x\n");
+        for hex in ["", "d800", "110000", "zz"] {
+            editor.run_command(&format!("insert-char {}", hex));
+            assert_eq!(editor.buffer().text(), "x\n", "{}", hex);
+        }
+        assert_eq!(editor.footer_text, "Invalid code point: zz");
+        assert_eq!(parse_code_point("0x1F600"), Some('😀'));
+        assert_eq!(parse_code_point("41"), Some('A'));
+    }
+
+    #[test]
+    fn closing_the_middle_buffer_activates_the_one_after_it() {
+        let mut editor = editor_with_buffers(&["a", "b", "c"]);
+        editor.set_active_buffer(1);
+        assert!(!editor.close_active_buffer());
+        assert_eq!(editor.buffers.len(), 2);
+        assert_eq!(editor.active_buffer, 1);
+        assert_eq!(editor.buffer().text(), "c");
+
+        // Closing the last buffer in the list falls back to the one before it.
+        assert!(!editor.close_active_buffer());
+        assert_eq!(editor.active_buffer, 0);
+        assert_eq!(editor.buffer().text(), "a");
+
+        // Closing the only buffer leaves an empty one.
+        assert!(!editor.close_active_buffer());
+        assert_eq!(editor.buffers.len(), 1);
+        assert_eq!(editor.buffer().text(), "");
+        editor.config.quit_on_last_buffer_close = true;
+        assert!(editor.close_active_buffer());
+    }
+
+    #[test]
+    fn closing_a_dirty_buffer_asks_first() {
+        let mut editor = editor_with_buffers(&["a", "b"]);
+        editor.buffer_mut().insert_text(0, "x");
+
+        editor.script_input([Event::Key(KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE))]);
+        assert!(!editor.close_active_buffer());
+        assert_eq!(editor.footer_text, "Close cancelled.");
+        assert_eq!(editor.buffers.len(), 2);
+
+        editor.script_input(key_events("n"));
+        assert!(!editor.close_active_buffer());
+        assert_eq!(editor.buffers.len(), 1);
+        assert_eq!(editor.buffer().text(), "b");
+    }
+
+    #[test]
+    fn uppercase_registers_append_to_the_lowercase_ones() {
+        let mut editor = editor_with("alpha beta gamma\n");
+        let key = |code, modifiers| KeyEvent::new(code, modifiers);
+
+        // Alt-r reads the register name, then Ctrl-c copies into it.
+        editor.script_input([Event::Key(key(KeyCode::Char('a'), KeyModifiers::NONE))]);
+        editor.handle_key_event(key(KeyCode::Char('r'), KeyModifiers::ALT));
+        editor.buffer_mut().select(0..6);
+        editor.handle_key_event(key(KeyCode::Char('c'), KeyModifiers::CONTROL));
+        assert_eq!(editor.registers[&'a'], "alpha ");
+
+        editor.pending_register = Some('A');
+        editor.buffer_mut().select(11..16);
+        editor.yank(false);
+        assert_eq!(editor.registers[&'a'], "alpha gamma");
+        // The unnamed register wasn't touched.
+        assert!(!editor.registers.contains_key(&Editor::UNNAMED_REGISTER));
+
+        editor.buffer_mut().select(0..0);
+        editor.buffer_mut().cursor_idx = 17;
+        editor.pending_register = Some('a');
+        editor.put(true);
+        assert_eq!(editor.buffer().text(), "alpha beta gamma\nalpha gamma");
+    }
+
+    #[test]
+    fn the_path_under_the_cursor_opens_relative_to_the_file() {
+        let dir = fixture_dir("path-under-cursor");
+        std::fs::write(dir.join("other.txt"), "other\n").unwrap();
+        let mut editor = editor_with("see (other.txt) or missing.txt\n");
+        editor.buffer_mut().file_path = dir.join("main.txt");
+
+        editor.buffer_mut().cursor_idx = 20;
+        editor.open_path_under_cursor();
+        assert_eq!(
+            editor.footer_text,
+            format!("File not found: {}", dir.join("missing.txt").display())
+        );
+
+        editor.buffer_mut().cursor_idx = 7;
+        editor.open_path_under_cursor();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(editor.buffers.len(), 2);
+        assert_eq!(editor.buffer().text(), "other\n");
+    }
+
+    #[test]
+    fn rows_past_the_end_show_the_empty_line_marker() {
+        let drawn_row = |marker: &str| {
+            let mut editor = editor_with("a\n");
+            editor.config.empty_line_marker = String::from(marker);
+            editor.render().unwrap();
+            let width = editor.text_area_width();
+            let cells = editor.drawn.rows[2].clone().unwrap();
+            (cells.iter().map(|cell| cell.ch).collect::<String>(), width)
+        };
+
+        let (row, width) = drawn_row("-->");
+        assert_eq!(row, format!("{:<1$}", "-->", width));
+        // An empty marker still fills the row.
+        let (row, width) = drawn_row("");
+        assert_eq!(row, " ".repeat(width));
+    }
+
+    #[test]
+    fn find_next_repeats_the_last_search_from_the_cursor() {
+        let mut editor = editor_with("one x two x three x\n");
+        press(&mut editor, KeyCode::F(3));
+        assert_eq!(editor.footer_text, "No previous search.");
+
+        editor.script_input(key_events("x"));
+        editor.handle_key_event(KeyEvent::new(KeyCode::Char('f'), KeyModifiers::CONTROL));
+        assert_eq!(editor.buffer().cursor_idx(), 4);
+
+        // Edits and moves in between don't lose the search.
+        editor.buffer_mut().cursor_idx = 9;
+        press(&mut editor, KeyCode::Char('!'));
+        press(&mut editor, KeyCode::F(3));
+        assert_eq!(editor.buffer().cursor_idx(), 11);
+        assert_eq!(editor.footer_text, "Match found.");
+        press(&mut editor, KeyCode::F(3));
+        assert_eq!(editor.buffer().cursor_idx(), 19);
+        press(&mut editor, KeyCode::F(3));
+        assert_eq!(editor.buffer().cursor_idx(), 4);
+        assert_eq!(editor.footer_text, "Search wrapped to top.");
+    }
+
+    #[test]
+    fn a_recorded_macro_replays_its_keys() {
+        let mut editor = editor_with("");
+        let alt = |c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::ALT);
+        editor.handle_key_event(alt('m'));
+        assert_eq!(editor.footer_text, "No macro recorded.");
+
+        editor.handle_key_event(alt('q'));
+        editor.render().unwrap();
+        assert!(editor.take_output().contains("REC "));
+        for c in "abc".chars() {
+            press(&mut editor, KeyCode::Char(c));
+        }
+        editor.handle_key_event(alt('q'));
+        assert_eq!(editor.footer_text, "Recorded 3 keys.");
+        assert_eq!(editor.buffer().text(), "abc");
+
+        editor.run_command("replay 3");
+        assert_eq!(editor.buffer().text(), "abcabcabcabc");
+        editor.handle_key_event(alt('m'));
+        assert_eq!(editor.buffer().text(), "abcabcabcabcabc");
+    }
+
+    #[test]
+    fn new_files_are_saved_relative_to_the_startup_directory() {
+        // Tests share the working directory, so a different startup directory stands in for the
+        // working directory having changed since the editor started.
+        let dir = fixture_dir("startup-dir");
+        let mut editor = editor_with("new\n");
+        editor.startup_dir = dir.clone();
+        editor.script_input(key_events("new.txt"));
+        editor.save_buffer();
+        let saved = std::fs::read_to_string(dir.join("new.txt"));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(editor.footer_text, "New file saved as new.txt");
+        assert_eq!(editor.buffer().file_path, dir.join("new.txt"));
+        assert_eq!(saved.unwrap(), "new\n");
+    }
+
+    #[test]
+    fn a_default_file_name_is_used_unless_it_exists() {
+        let dir = fixture_dir("default-name");
+        let mut first = editor_with("first\n");
+        first.startup_dir = dir.clone();
+        first.config.default_new_filename = Some(String::from("untitled.txt"));
+        first.save_buffer();
+
+        // The default name is taken now, so the name is asked for instead.
+        let mut second = editor_with("second\n");
+        second.startup_dir = dir.clone();
+        second.config.default_new_filename = Some(String::from("untitled.txt"));
+        second.script_input(key_events("other.txt"));
+        second.save_buffer();
+
+        // Without a default name, cancelling the prompt saves nothing.
+        let mut unset = editor_with("unset\n");
+        unset.startup_dir = dir.clone();
+        unset.script_input([Event::Key(KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE))]);
+        unset.save_buffer();
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        let (untitled, other) = (read("untitled.txt"), read("other.txt"));
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(first.footer_text, "New file saved as untitled.txt");
+        assert_eq!(untitled, "first\n");
+        assert_eq!(second.footer_text, "New file saved as other.txt");
+        assert_eq!(other, "second\n");
+        assert_eq!(unset.footer_text, "No file name given, cancelled save.");
+        assert_eq!(files, 2);
+    }
+
+    #[test]
+    fn the_cursor_moves_to_the_top_and_bottom_of_the_view() {
+        let text: String = (0..100).map(|n| format!("line {:02}\n", n)).collect();
+        let mut editor = editor_with(&text);
+        let text_rows = editor.text_area_height();
+        editor.buffer_mut().visual_origin_row = 40;
+        editor.buffer_mut().go_to_position(50, 6);
+        let alt = |c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::ALT);
+
+        editor.handle_key_event(alt('h'));
+        assert_eq!(editor.buffer().get_logical_cursor_pos(), (40, 6));
+        editor.handle_key_event(alt('l'));
+        assert_eq!(
+            editor.buffer().get_logical_cursor_pos(),
+            (40 + text_rows - 1, 6)
+        );
+        // Neither one scrolls.
+        assert_eq!(editor.buffer().visual_origin_row, 40);
+
+        // With the end of the file in view, the bottom is the last line.
+        editor.buffer_mut().visual_origin_row = 95;
+        editor.move_cursor_to(ScreenPosition::Bottom);
+        assert_eq!(editor.buffer().get_logical_cursor_line(), 99);
+    }
+
+    #[test]
+    fn page_down_keeps_the_overlap_in_view() {
+        let text: String = (0..200).map(|n| format!("{}\n", n)).collect();
+        let mut editor = editor_with(&text);
+        let text_rows = editor.text_area_height();
+        assert_eq!(editor.config.page_overlap, 1);
+
+        press(&mut editor, KeyCode::PageDown);
+        // The last row of the old page is now the first row.
+        assert_eq!(editor.buffer().visual_origin_row, text_rows - 1);
+        assert_eq!(editor.buffer().get_logical_cursor_line(), text_rows - 1);
+
+        editor.config.page_overlap = 3;
+        press(&mut editor, KeyCode::PageDown);
+        assert_eq!(editor.buffer().get_logical_cursor_line(), 2 * text_rows - 4);
+        press(&mut editor, KeyCode::PageUp);
+        assert_eq!(editor.buffer().get_logical_cursor_line(), text_rows - 1);
+    }
+
+    #[test]
+    fn char_info_reports_the_byte_offset_in_the_file() {
+        let mut editor = editor_with("hé€\r\nx");
+        editor.buffer_mut().cursor_idx = 1;
+        editor.run_command("char-info");
+        assert_eq!(editor.footer_text, "Byte 1: U+00E9, 2 byte(s) in UTF-8");
+
+        editor.buffer_mut().cursor_idx = 2;
+        editor.run_command("char-info");
+        assert_eq!(editor.footer_text, "Byte 3: U+20AC, 3 byte(s) in UTF-8");
+
+        // The carriage return saved before the line feed counts too.
+        editor.buffer_mut().cursor_idx = 4;
+        editor.run_command("char-info");
+        assert_eq!(editor.footer_text, "Byte 8: U+0078, 1 byte(s) in UTF-8");
+        editor.buffer_mut().cursor_idx = 5;
+        editor.run_command("char-info");
+        assert_eq!(editor.footer_text, "Byte 9: end of file");
+    }
+
+    #[test]
+    fn prompts_on_narrow_terminals_show_the_end_of_the_input() {
+        let buffer = Buffer::from_str("", BufferSettings::default());
+        let mut editor = Editor::new_headless(buffer, (10, 5));
+        editor.script_input(key_events("abcdefghijklmnop"));
+        let answer = editor.editor_prompt("Enter a long file name> ");
+        assert_eq!(answer.as_deref(), Some("abcdefghijklmnop"));
+        // The last draw before Enter showed the input's tail, leaving a column for the caret.
+        assert!(editor.take_output().contains("ijklmnop"));
+
+        assert_eq!(tail_to_width("name> abcdef", 9), "e> abcdef");
+        assert_eq!(tail_to_width("名前> 日本", 5), " 日本");
+        assert_eq!(tail_to_width("abc", 0), "");
+    }
+
+    #[test]
+    fn percentages_go_to_lines_through_the_file() {
+        let text: String = (1..=200).map(|n| format!("line {n}\n")).collect();
+        let mut editor = editor_with(&text);
+        for (command, line) in [("50%", 100), ("0%", 0), ("100%", 199), ("150%", 199)] {
+            editor.run_command(command);
+            assert_eq!(editor.buffer().get_logical_cursor_line(), line, "{command}");
+        }
+
+        editor.run_command("half%");
+        assert_eq!(editor.footer_text, "Invalid percentage: half");
+        assert_eq!(editor.buffer().get_logical_cursor_line(), 199);
+    }
+
+    #[test]
+    fn trailing_whitespace_is_not_flagged_on_the_cursor_line() {
+        let mut editor = editor_with("a  \nb  \n");
+        editor.config.highlight_trailing_whitespace = true;
+        let flagged_cols = |editor: &mut Editor, row: usize| {
+            editor.render().unwrap();
+            let cells = editor.drawn.rows[row].clone().unwrap();
+            (0..cells.len())
+                .filter(|&col| cells[col].bg == Some(editor.theme.trailing_whitespace_bg))
+                .collect::<Vec<_>>()
+        };
+
+        editor.buffer_mut().cursor_idx = 3;
+        assert_eq!(flagged_cols(&mut editor, 0), Vec::<usize>::new());
+        assert_eq!(flagged_cols(&mut editor, 1), vec![1, 2]);
+
+        editor.buffer_mut().cursor_idx = 7;
+        assert_eq!(flagged_cols(&mut editor, 0), vec![1, 2]);
+        assert_eq!(flagged_cols(&mut editor, 1), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn named_buffers_are_saved_after_enough_edits() {
+        let dir = fixture_dir("autosave-edits");
+        let path = dir.join("file.txt");
+        let mut editor = editor_with("");
+        editor.config.autosave_after_edits = Some(3);
+        editor.buffer_mut().file_path = path.clone();
+
+        press(&mut editor, KeyCode::Char('a'));
+        press(&mut editor, KeyCode::Char('b'));
+        assert!(!path.exists());
+        assert_eq!(editor.buffer().edits_since_save, 2);
+
+        press(&mut editor, KeyCode::Char('c'));
+        let saved = std::fs::read_to_string(&path);
+        assert_eq!(editor.footer_text, "Auto-saved 1 buffer(s).");
+        assert_eq!(editor.buffer().edits_since_save, 0);
+        assert!(!editor.buffer().dirty_buffer);
+
+        press(&mut editor, KeyCode::Char('d'));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(saved.unwrap(), "abc");
+        assert_eq!(editor.buffer().edits_since_save, 1);
+
+        // Buffers without a file name are never saved.
+        let mut editor = editor_with("");
+        editor.config.autosave_after_edits = Some(1);
+        press(&mut editor, KeyCode::Char('a'));
+        assert!(editor.buffer().dirty_buffer);
+        assert_eq!(editor.buffer().edits_since_save, 1);
+    }
+
+    #[test]
+    fn ctrl_l_redraws_the_whole_screen() {
+        let mut editor = editor_with("hello world\n");
+        editor.render().unwrap();
+        editor.take_output();
+        editor.render().unwrap();
+        assert!(!editor.take_output().contains("hello world"));
+
+        // The terminal shrank without a resize event arriving.
+        editor.terminal_size = Some((40, 10));
+        editor.handle_key_event(KeyEvent::new(KeyCode::Char('l'), KeyModifiers::CONTROL));
+        assert!(editor.take_output().contains("\x1b[2J"));
+        assert!(editor.drawn.rows.is_empty());
+        assert_eq!(editor.buffer().viewport(), (0, 0, 40, 10));
+
+        editor.render().unwrap();
+        assert!(editor.take_output().contains("hello world"));
+    }
+
+    #[test]
+    fn files_changed_on_disk_are_flagged_until_saved_or_reloaded() {
+        let dir = fixture_dir("changed-on-disk");
+        let path = dir.join("file.txt");
+        std::fs::write(&path, "one\n").unwrap();
+        let mut editor = editor_with("");
+        editor.open_buffer(path.clone());
+        let touch = |path: &Path, secs: u64| {
+            let mtime = std::time::UNIX_EPOCH + Duration::from_secs(secs);
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        };
+
+        editor.check_disk_changes();
+        assert!(!editor.buffer().file_changed_externally);
+
+        touch(&path, 1_000_000);
+        editor.check_disk_changes();
+        assert!(editor.buffer().file_changed_externally);
+        assert_eq!(editor.footer_text, "The file has changed on disk.");
+        assert!(
+            editor
+                .buffer()
+                .get_status_bar_text()
+                .0
+                .ends_with(" [changed on disk]")
+        );
+
+        press(&mut editor, KeyCode::Char('x'));
+        editor.script_input(key_events("n"));
+        editor.handle_key_event(KeyEvent::new(KeyCode::Char('s'), KeyModifiers::CONTROL));
+        assert_eq!(editor.footer_text, "Save cancelled.");
+        assert!(editor.buffer().file_changed_externally);
+
+        editor.script_input(key_events("y"));
+        editor.handle_key_event(KeyEvent::new(KeyCode::Char('s'), KeyModifiers::CONTROL));
+        let saved = std::fs::read_to_string(&path);
+        assert!(!editor.buffer().file_changed_externally);
 
-        return Ok(());
+        touch(&path, 2_000_000);
+        editor.check_disk_changes();
+        assert!(editor.buffer().file_changed_externally);
+        editor.run_command("reload");
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(saved.unwrap(), "xone\n");
+        assert!(!editor.buffer().file_changed_externally);
     }
 }
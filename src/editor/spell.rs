@@ -0,0 +1,63 @@
+use std::{collections::HashSet, fs, io, ops::Range, path::Path};
+
+/// A list of known words to check text against. Words are ASCII and compared case-insensitively.
+pub struct Dictionary {
+    words: HashSet<String>,
+}
+
+impl Dictionary {
+    /// Loads a dictionary from a file with one word per line, such as `/usr/share/dict/words`.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Ok(Dictionary {
+            words: contents
+                .lines()
+                .map(|word| word.trim().to_ascii_lowercase())
+                .filter(|word| !word.is_empty())
+                .collect(),
+        })
+    }
+
+    /// Returns true if `word` is in the dictionary, ignoring case.
+    pub fn contains(&self, word: &str) -> bool {
+        self.words.contains(&word.to_ascii_lowercase())
+    }
+
+    /// Returns the char ranges of the words in `text` that aren't in the dictionary. A word is a
+    /// run of ASCII letters; anything else, including digits and non-ASCII letters, separates
+    /// words.
+    pub fn misspelled_ranges(&self, text: &str) -> Vec<Range<usize>> {
+        let mut ranges = Vec::new();
+        let mut word = String::new();
+        for (idx, ch) in text.chars().chain(std::iter::once(' ')).enumerate() {
+            if ch.is_ascii_alphabetic() {
+                word.push(ch);
+            } else if !word.is_empty() {
+                if !self.contains(&word) {
+                    ranges.push(idx - word.len()..idx);
+                }
+                word.clear();
+            }
+        }
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn misspelled_words_are_found_ignoring_case() {
+        let path = std::env::temp_dir().join(format!("editor-words-{}", std::process::id()));
+        fs::write(&path, "the\nQuick\n  fox\n\n").unwrap();
+        let dictionary = Dictionary::load(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert!(dictionary.contains("QUICK"));
+        assert_eq!(
+            dictionary.misspelled_ranges("The quikc fox, café 42brwn"),
+            vec![4..9, 15..18, 22..26]
+        );
+    }
+}
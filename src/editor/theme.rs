@@ -0,0 +1,261 @@
+use crossterm::style::Color;
+
+/// Colours used when drawing the editor.
+pub struct Theme {
+    /// Background of the line the cursor is on, when `cursorline` is enabled.
+    pub cursorline_bg: Color,
+
+    /// Background of selected text. Takes priority over the cursorline.
+    pub selection_bg: Color,
+
+    /// Background of the other occurrences of the selected text collected for editing together.
+    pub occurrence_bg: Color,
+
+    /// Background of characters drawn past the line length limit.
+    pub line_too_long_bg: Color,
+
+    /// Background of whitespace at the end of a line, when highlighted.
+    pub trailing_whitespace_bg: Color,
+
+    /// Colour of the marker shown on lines past the end of the buffer, or None for the terminal's
+    /// default.
+    pub empty_line_marker_fg: Option<Color>,
+
+    /// Drawn in the first column of a line that is partly scrolled off to the left, or None to
+    /// leave the column alone.
+    pub truncation_marker_left: Option<char>,
+
+    /// Drawn in the last column of a line too long to fit on screen, or None to leave the column
+    /// alone.
+    pub truncation_marker_right: Option<char>,
+
+    /// Colour of the truncation markers.
+    pub truncation_marker_fg: Color,
+
+    /// Colour of words flagged by the spell checker.
+    pub misspelled_fg: Color,
+
+    /// Colour of the text outside the focused paragraph, when `focus_mode` is enabled.
+    pub unfocused_fg: Color,
+
+    /// Colours of the underlines beneath problems found by a language server, by severity.
+    #[cfg(feature = "lsp")]
+    pub diagnostic_error: Color,
+    #[cfg(feature = "lsp")]
+    pub diagnostic_warning: Color,
+    #[cfg(feature = "lsp")]
+    pub diagnostic_info: Color,
+
+    /// Background of the minimap rows covering the lines currently on screen.
+    pub minimap_viewport_bg: Color,
+
+    /// Colour of line numbers.
+    pub line_number_fg: Color,
+
+    /// Colours of the diff gutter markers for added, modified and deleted lines.
+    pub diff_added_fg: Color,
+    pub diff_modified_fg: Color,
+    pub diff_deleted_fg: Color,
+
+    /// Colours of the tab bar shown when several buffers are open.
+    pub tab_bar_bg: Color,
+    pub tab_bar_fg: Color,
+
+    /// Colours of the active buffer's tab.
+    pub tab_active_bg: Color,
+    pub tab_active_fg: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            cursorline_bg: Color::AnsiValue(236),
+            selection_bg: Color::AnsiValue(24),
+            occurrence_bg: Color::AnsiValue(60),
+            line_too_long_bg: Color::DarkRed,
+            trailing_whitespace_bg: Color::AnsiValue(52),
+            empty_line_marker_fg: None,
+            truncation_marker_left: Some('<'),
+            truncation_marker_right: Some('>'),
+            truncation_marker_fg: Color::DarkYellow,
+            misspelled_fg: Color::Red,
+            unfocused_fg: Color::AnsiValue(242),
+            #[cfg(feature = "lsp")]
+            diagnostic_error: Color::Red,
+            #[cfg(feature = "lsp")]
+            diagnostic_warning: Color::Yellow,
+            #[cfg(feature = "lsp")]
+            diagnostic_info: Color::Blue,
+            minimap_viewport_bg: Color::AnsiValue(238),
+            line_number_fg: Color::DarkGrey,
+            diff_added_fg: Color::Green,
+            diff_modified_fg: Color::Yellow,
+            diff_deleted_fg: Color::Red,
+            tab_bar_bg: Color::AnsiValue(236),
+            tab_bar_fg: Color::Grey,
+            tab_active_bg: Color::White,
+            tab_active_fg: Color::Black,
+        }
+    }
+}
+
+/// The colours a terminal can draw, from least to most.
+#[derive(Clone, Copy)]
+pub enum ColorSupport {
+    /// The 16 named colours.
+    Basic,
+    /// The 256-colour palette.
+    Ansi256,
+    /// Any RGB colour.
+    TrueColor,
+}
+
+/// The 16 named colours, in palette order, with the RGB values xterm gives them.
+const BASIC_COLORS: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::DarkRed, (205, 0, 0)),
+    (Color::DarkGreen, (0, 205, 0)),
+    (Color::DarkYellow, (205, 205, 0)),
+    (Color::DarkBlue, (0, 0, 238)),
+    (Color::DarkMagenta, (205, 0, 205)),
+    (Color::DarkCyan, (0, 205, 205)),
+    (Color::Grey, (229, 229, 229)),
+    (Color::DarkGrey, (127, 127, 127)),
+    (Color::Red, (255, 0, 0)),
+    (Color::Green, (0, 255, 0)),
+    (Color::Yellow, (255, 255, 0)),
+    (Color::Blue, (92, 92, 255)),
+    (Color::Magenta, (255, 0, 255)),
+    (Color::Cyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+/// The levels of each component in the 6x6x6 colour cube of the 256-colour palette.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+impl ColorSupport {
+    /// Works out what the terminal can draw from the `COLORTERM` and `TERM` environment variables.
+    pub fn detect() -> Self {
+        let var = |name| std::env::var(name).unwrap_or_default().to_lowercase();
+        let colorterm = var("COLORTERM");
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            ColorSupport::TrueColor
+        } else if var("TERM").contains("256color") {
+            ColorSupport::Ansi256
+        } else {
+            ColorSupport::Basic
+        }
+    }
+
+    /// Returns the nearest colour to `color` that the terminal can draw.
+    pub fn downgrade(self, color: Color) -> Color {
+        match (self, color) {
+            (ColorSupport::TrueColor, _) => color,
+            (ColorSupport::Ansi256, Color::Rgb { r, g, b }) => {
+                Color::AnsiValue(nearest_ansi256(r, g, b))
+            }
+            (ColorSupport::Basic, Color::Rgb { r, g, b }) => nearest_basic(r, g, b),
+            (ColorSupport::Basic, Color::AnsiValue(value)) => {
+                let (r, g, b) = ansi256_to_rgb(value);
+                nearest_basic(r, g, b)
+            }
+            _ => color,
+        }
+    }
+}
+
+/// Returns the RGB value of colour `value` of the 256-colour palette.
+fn ansi256_to_rgb(value: u8) -> (u8, u8, u8) {
+    match value {
+        0..16 => BASIC_COLORS[value as usize].1,
+        16..232 => {
+            let idx = value - 16;
+            (
+                CUBE_LEVELS[(idx / 36) as usize],
+                CUBE_LEVELS[(idx / 6 % 6) as usize],
+                CUBE_LEVELS[(idx % 6) as usize],
+            )
+        }
+        232.. => {
+            let level = 8 + (value - 232) * 10;
+            (level, level, level)
+        }
+    }
+}
+
+/// Returns the colour of the 256-colour palette nearest to the given RGB value, from either the
+/// colour cube or the greyscale ramp. The 16 named colours at the start of the palette are left
+/// out, since terminals often change them.
+fn nearest_ansi256(r: u8, g: u8, b: u8) -> u8 {
+    let cube_idx = |component: u8| {
+        (0..CUBE_LEVELS.len())
+            .min_by_key(|&idx| CUBE_LEVELS[idx].abs_diff(component))
+            .unwrap() as u8
+    };
+    let cube = 16 + 36 * cube_idx(r) + 6 * cube_idx(g) + cube_idx(b);
+
+    let average = ((r as u16 + g as u16 + b as u16) / 3) as u8;
+    let grey = 232 + (average.saturating_sub(3) / 10).min(23);
+
+    [cube, grey]
+        .into_iter()
+        .min_by_key(|&value| distance(ansi256_to_rgb(value), (r, g, b)))
+        .unwrap()
+}
+
+/// Returns the named colour nearest to the given RGB value.
+fn nearest_basic(r: u8, g: u8, b: u8) -> Color {
+    BASIC_COLORS
+        .iter()
+        .min_by_key(|(_, rgb)| distance(*rgb, (r, g, b)))
+        .unwrap()
+        .0
+}
+
+/// Returns the squared distance between two RGB values.
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let diff = |x: u8, y: u8| (x as i32 - y as i32).pow(2) as u32;
+    diff(a.0, b.0) + diff(a.1, b.1) + diff(a.2, b.2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rgb_colours_downgrade_to_the_nearest_palette_colour() {
+        let red = Color::Rgb { r: 255, g: 0, b: 0 };
+        let grey = Color::Rgb {
+            r: 128,
+            g: 128,
+            b: 128,
+        };
+        let teal = Color::Rgb {
+            r: 0,
+            g: 130,
+            b: 140,
+        };
+        assert_eq!(ColorSupport::TrueColor.downgrade(teal), teal);
+
+        assert_eq!(ColorSupport::Ansi256.downgrade(red), Color::AnsiValue(196));
+        assert_eq!(ColorSupport::Ansi256.downgrade(grey), Color::AnsiValue(244));
+        assert_eq!(ColorSupport::Ansi256.downgrade(teal), Color::AnsiValue(30));
+
+        assert_eq!(ColorSupport::Basic.downgrade(red), Color::Red);
+        assert_eq!(ColorSupport::Basic.downgrade(grey), Color::DarkGrey);
+        assert_eq!(ColorSupport::Basic.downgrade(teal), Color::DarkCyan);
+    }
+
+    #[test]
+    fn palette_colours_downgrade_to_named_colours() {
+        let basic = ColorSupport::Basic;
+        assert_eq!(basic.downgrade(Color::AnsiValue(196)), Color::Red);
+        assert_eq!(basic.downgrade(Color::AnsiValue(244)), Color::DarkGrey);
+        assert_eq!(basic.downgrade(Color::AnsiValue(4)), Color::DarkBlue);
+        assert_eq!(basic.downgrade(Color::Yellow), Color::Yellow);
+        assert_eq!(
+            ColorSupport::Ansi256.downgrade(Color::AnsiValue(30)),
+            Color::AnsiValue(30)
+        );
+    }
+}
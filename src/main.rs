@@ -1,16 +1,62 @@
 mod editor;
 
-use editor::Editor;
+use editor::{BinaryFileError, Editor, LargeFileError};
 
 use std::env;
 use std::path::PathBuf;
-use std::{io::Result, process::exit};
+use std::{
+    io::{Result, Write, stdin, stdout},
+    process::exit,
+};
+
+const USAGE: &str = "Usage: editor [--log <path>] [--size <cols>x<rows>] [+line[:col]] [filename]";
 
 fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    // `--log <path>` records every key handled, for reproducing bugs.
+    let log_path = match args.iter().position(|arg| arg == "--log") {
+        Some(idx) if idx + 1 < args.len() => {
+            let path = args.remove(idx + 1);
+            args.remove(idx);
+            Some(PathBuf::from(path))
+        }
+        Some(_) => {
+            println!("{}", USAGE);
+            exit(1);
+        }
+        None => None,
+    };
+
+    // `--size <cols>x<rows>` draws at a fixed size instead of the terminal's, so scripted runs and
+    // layout bug reports can be reproduced exactly.
+    let forced_size = match args.iter().position(|arg| arg == "--size") {
+        Some(idx) if idx + 1 < args.len() => match parse_size(&args[idx + 1]) {
+            Some(size) => {
+                args.drain(idx..idx + 2);
+                Some(size)
+            }
+            None => {
+                println!("Invalid size: {}", args[idx + 1]);
+                exit(1);
+            }
+        },
+        Some(_) => {
+            println!("{}", USAGE);
+            exit(1);
+        }
+        None => None,
+    };
+
+    // `+<line>` or `+<line>:<col>` before the file name starts the cursor there, as tools that
+    // run `$EDITOR` expect.
+    let position_arg = match args.get(1) {
+        Some(arg) if arg.starts_with('+') => Some(args.remove(1)),
+        _ => None,
+    };
 
     if args.len() > 2 {
-        println!("Usage: editor [filename]");
+        println!("{}", USAGE);
         exit(1);
     }
 
@@ -19,19 +65,91 @@ fn main() -> Result<()> {
         None => String::from(""),
     };
 
-    let path = PathBuf::from(filename);
+    let path = PathBuf::from(&filename);
 
-    let mut editor = match Editor::from_path(path) {
+    let mut editor = match Editor::from_path(path.clone(), false, forced_size) {
         Ok(editor) => editor,
+        // The terminal hasn't been set up yet, so ask on the command line before loading a large
+        // file.
+        Err(ref err) if let Some(large) = LargeFileError::from_io_error(err) => {
+            print!(
+                "This file is large ({}). Open anyway? y/n ",
+                large.readable_size()
+            );
+            stdout().flush()?;
+            let mut answer = String::new();
+            stdin().read_line(&mut answer)?;
+            if !matches!(answer.trim(), "y" | "Y" | "yes") {
+                exit(0);
+            }
+            match Editor::from_path(path, true, forced_size) {
+                Ok(editor) => editor,
+                Err(_) => {
+                    println!("Could not read file.");
+                    exit(1);
+                }
+            }
+        }
+        Err(ref err) if BinaryFileError::from_io_error(err).is_some() => {
+            println!("editor: {} appears to be a binary file", filename);
+            exit(1);
+        }
         Err(_) => {
             println!("Could not read file.");
             exit(1);
         }
     };
 
+    if let Some(log_path) = log_path
+        && editor.enable_logging(&log_path).is_err()
+    {
+        editor.footer_text = format!("Could not open log file {}.", log_path.display());
+    }
+
+    if let Some(arg) = position_arg {
+        match parse_position(&arg) {
+            Some((line, col)) => {
+                editor.go_to_position(line.saturating_sub(1), col.saturating_sub(1));
+            }
+            None => editor.footer_text = format!("Invalid position: {}", arg),
+        }
+    }
+
     editor.mainloop()?;
 
     editor.clear_terminal()?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Parses a `+<line>` or `+<line>:<col>` argument into a line and column, both counted from 1.
+/// The column is 1 if it isn't given.
+fn parse_position(arg: &str) -> Option<(usize, usize)> {
+    let position = arg.strip_prefix('+')?;
+    match position.split_once(':') {
+        Some((line, col)) => Some((line.parse().ok()?, col.parse().ok()?)),
+        None => Some((position.parse().ok()?, 1)),
+    }
+}
+
+/// Parses a `<cols>x<rows>` argument into a size, neither of which may be 0.
+fn parse_size(arg: &str) -> Option<(u16, u16)> {
+    let (cols, rows) = arg.split_once('x')?;
+    let (cols, rows) = (cols.parse().ok()?, rows.parse().ok()?);
+    (cols > 0 && rows > 0).then_some((cols, rows))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_are_parsed_from_plus_arguments() {
+        assert_eq!(parse_position("+10"), Some((10, 1)));
+        assert_eq!(parse_position("+10:5"), Some((10, 5)));
+        assert_eq!(parse_position("+"), None);
+        assert_eq!(parse_position("+ten"), None);
+        assert_eq!(parse_position("+10:"), None);
+        assert_eq!(parse_position("10"), None);
+    }
+}